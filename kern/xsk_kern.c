@@ -19,7 +19,11 @@
 
 #include <linux/bpf.h>
 #include <linux/if_ether.h>
+#include <linux/if_arp.h>
 #include <linux/ip.h>
+#include <linux/icmp.h>
+#include <linux/ipv6.h>
+#include <linux/icmpv6.h>
 #include <linux/udp.h>
 #include <linux/in.h>
 #include <linux/bpf.h>
@@ -29,10 +33,98 @@
 
 #include "utils.h"
 
+#define VLAN_MAX_TAGS 2
+#define VLAN_VID_MASK 0x0fff
+
+#define IP_MF     0x2000
+#define IP_OFFSET 0x1fff
+
+struct vlan_hdr {
+	__be16 h_vlan_TCI;
+	__be16 h_vlan_encapsulated_proto;
+};
+
 SINGLE_VAL_MAP(socks_per_queue_map, u32);
-SINGLE_VAL_MAP(bind_addr_map, u32);
-SINGLE_VAL_MAP(bind_port_map, u16);
+/* Whether IPv4 packets of every protocol, rather than only UDP and the ICMP
+ * messages handled by the stack, are redirected for the bind addresses.
+ */
+SINGLE_VAL_MAP(all_protocols_map, u32);
+/* The addresses bound on the untagged network, in network byte order. UDP
+ * datagrams are further matched against the listeners.
+ */
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, u32);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} bind_addr_map SEC(".maps");
+
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, struct in6_addr);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} bind_addr6_map SEC(".maps");
+
+/* The addresses bound on each VLAN, keyed by the VLAN key (see the `Vlan`
+ * type): the VID of a single tag, or 1 << 24 | outer VID << 12 | inner VID for
+ * QinQ frames.
+ */
+struct vlan_addr {
+	u32 vlan;
+	u32 addr;
+};
+
+struct vlan_addr6 {
+	u32 vlan;
+	struct in6_addr addr;
+};
+
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, struct vlan_addr);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} vlan_addr_map SEC(".maps");
+
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, struct vlan_addr6);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} vlan_addr6_map SEC(".maps");
+
+/* The listeners, i.e. the addresses and ports (in network byte order) of the
+ * UDP datagrams redirected to XSK, whatever their VLAN. The datagrams sent to
+ * a bound address on other ports are left to the kernel.
+ */
+struct listener {
+	u32 addr;
+	u16 port;
+	u16 pad;
+};
+
+struct listener6 {
+	struct in6_addr addr;
+	u16 port;
+	u16 pad;
+};
+
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, struct listener);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} listener_map SEC(".maps");
 
+struct {
+        __uint(type, BPF_MAP_TYPE_HASH);
+        __type(key, struct listener6);
+        __type(value, u8);
+        __uint(max_entries, 1024);
+} listener6_map SEC(".maps");
+
+/* Keep max_entries in sync with XSKS_MAP_SIZE (src/xsk/xdp_prog.rs). */
 struct {
         __uint(type, BPF_MAP_TYPE_XSKMAP);
         __type(key, __u32);
@@ -48,6 +140,145 @@ i32 redirect_to_xsk(struct xdp_md *xdp, u16 sport) {
 	return bpf_redirect_map(&xsks_map, index, XDP_PASS);
 }
 
+/* Returns true if addr (in network byte order) is bound on vlan, 0 meaning
+ * untagged frames.
+ */
+static inline
+int ip4_is_local(u32 vlan, u32 addr) {
+	if (!vlan)
+		return bpf_map_lookup_elem(&bind_addr_map, &addr) != NULL;
+
+	struct vlan_addr key = { .vlan = vlan, .addr = addr };
+	return bpf_map_lookup_elem(&vlan_addr_map, &key) != NULL;
+}
+
+struct arp_ip4 {
+	struct arphdr hdr;
+	u8 sha[ETH_ALEN];
+	u8 sip[4];
+	u8 tha[ETH_ALEN];
+	u8 tip[4];
+} __attribute__((packed));
+
+/* Only Ethernet/IPv4 ARP packets targeting the bind address, or sent from it
+ * (so that other hosts using the same address can be detected), are redirected
+ * to XSK, everything else (including requests for other hosts) is left to the
+ * kernel.
+ */
+static inline
+i32 handle_arp(struct xdp_md *xdp, u32 vlan, struct arp_ip4 *arp, void *data_end) {
+	if (arp + 1 > (struct arp_ip4 *)data_end)
+		return XDP_PASS;
+
+	if (arp->hdr.ar_hrd != bpf_htons(ARPHRD_ETHER) ||
+	    arp->hdr.ar_pro != bpf_htons(ETH_P_IP) ||
+	    arp->hdr.ar_hln != ETH_ALEN ||
+	    arp->hdr.ar_pln != 4)
+		return XDP_PASS;
+
+	u32 sip, tip;
+	__builtin_memcpy(&sip, arp->sip, sizeof(sip));
+	__builtin_memcpy(&tip, arp->tip, sizeof(tip));
+
+	if (!ip4_is_local(vlan, tip) && !ip4_is_local(vlan, sip))
+		return XDP_PASS;
+
+	return redirect_to_xsk(xdp, 0);
+}
+
+/* The stack answers echo requests and uses fragmentation needed messages for
+ * path MTU discovery: any other ICMP message is left to the kernel.
+ */
+static inline
+i32 handle_icmp(struct xdp_md *xdp, struct icmphdr *icmp, void *data_end) {
+	if (icmp + 1 > (struct icmphdr *)data_end)
+		return XDP_PASS;
+
+	if (icmp->type != ICMP_ECHO &&
+	    (icmp->type != ICMP_DEST_UNREACH || icmp->code != ICMP_FRAG_NEEDED))
+		return XDP_PASS;
+
+	return redirect_to_xsk(xdp, 0);
+}
+
+#define ND_NEIGHBOR_SOLICIT 135
+#define ND_NEIGHBOR_ADVERT  136
+
+struct nd_msg {
+	struct icmp6hdr hdr;
+	struct in6_addr target;
+};
+
+static inline
+int ip6_is_local(u32 vlan, const struct in6_addr *addr) {
+	if (!vlan)
+		return bpf_map_lookup_elem(&bind_addr6_map, addr) != NULL;
+
+	struct vlan_addr6 key = { .vlan = vlan };
+	__builtin_memcpy(&key.addr, addr, sizeof(key.addr));
+
+	return bpf_map_lookup_elem(&vlan_addr6_map, &key) != NULL;
+}
+
+/* IPv6 UDP datagrams for the listeners and the ICMPv6 messages handled by the
+ * stack (echo requests, packet too big and Neighbor Discovery) for the bind
+ * address are redirected to XSK, together with the Neighbor Solicitations for
+ * it, which are sent to its solicited-node multicast address. Everything else
+ * is left to the kernel.
+ */
+static inline
+i32 handle_ip6(struct xdp_md *xdp, u32 vlan, struct ipv6hdr *ip6, void *data_end) {
+	if (ip6 + 1 > (struct ipv6hdr *)data_end)
+		return XDP_ABORTED;
+
+	if (ip6_is_local(vlan, &ip6->daddr)) {
+		if (ip6->nexthdr == IPPROTO_ICMPV6) {
+			struct icmp6hdr *icmp6 = (struct icmp6hdr *)(ip6 + 1);
+			if (icmp6 + 1 > (struct icmp6hdr *)data_end)
+				return XDP_PASS;
+
+			if (icmp6->icmp6_type != ICMPV6_ECHO_REQUEST &&
+			    icmp6->icmp6_type != ICMPV6_PKT_TOOBIG &&
+			    icmp6->icmp6_type != ND_NEIGHBOR_SOLICIT &&
+			    icmp6->icmp6_type != ND_NEIGHBOR_ADVERT)
+				return XDP_PASS;
+
+			return redirect_to_xsk(xdp, 0);
+		}
+
+		if (ip6->nexthdr != IPPROTO_UDP)
+			return XDP_PASS;
+
+		struct udphdr *udp = (struct udphdr *)(ip6 + 1);
+		if (udp + 1 > (struct udphdr *)data_end)
+			return XDP_ABORTED;
+
+		struct listener6 key = { .port = udp->dest };
+		__builtin_memcpy(&key.addr, &ip6->daddr, sizeof(key.addr));
+
+		if (!bpf_map_lookup_elem(&listener6_map, &key))
+			return XDP_PASS;
+
+		return redirect_to_xsk(xdp, udp->source);
+	}
+
+	if (ip6->daddr.s6_addr32[0] != bpf_htonl(0xff020000) ||
+	    ip6->daddr.s6_addr32[1] != 0 ||
+	    ip6->daddr.s6_addr32[2] != bpf_htonl(0x00000001) ||
+	    ip6->nexthdr != IPPROTO_ICMPV6)
+		return XDP_PASS;
+
+	struct nd_msg *nd = (struct nd_msg *)(ip6 + 1);
+	if (nd + 1 > (struct nd_msg *)data_end)
+		return XDP_PASS;
+
+	if (nd->hdr.icmp6_type != ND_NEIGHBOR_SOLICIT ||
+	    !ip6_is_local(vlan, &nd->target))
+		return XDP_PASS;
+
+	return redirect_to_xsk(xdp, 0);
+}
+
 SEC("xdp/prog")
 i32 xdp_sock_prog(struct xdp_md *xdp) {
 	void *data = (void *)(u64)xdp->data;
@@ -57,26 +288,73 @@ i32 xdp_sock_prog(struct xdp_md *xdp) {
 	if (eth + 1 > (struct ethhdr *)data_end)
 		return XDP_ABORTED;
 
-	if (eth->h_proto == bpf_htons(ETH_P_IP)) {
-		struct iphdr *ip = (struct iphdr *)(eth + 1);
+	u16 h_proto = eth->h_proto;
+	void *l3 = eth + 1;
+	u32 vlan = 0;
+
+	/* Up to two 802.1Q/802.1ad tags. The VLAN key is built as the tags are
+	 * parsed, a single priority tag (VID 0) meaning untagged.
+	 */
+#pragma unroll
+	for (int i = 0; i < VLAN_MAX_TAGS; i++) {
+		if (h_proto != bpf_htons(ETH_P_8021Q) && h_proto != bpf_htons(ETH_P_8021AD))
+			break;
+
+		struct vlan_hdr *vlan_hdr = l3;
+		if (vlan_hdr + 1 > (struct vlan_hdr *)data_end)
+			return XDP_ABORTED;
+
+		u32 vid = bpf_ntohs(vlan_hdr->h_vlan_TCI) & VLAN_VID_MASK;
+		vlan = i ? (1 << 24 | vlan << 12 | vid) : vid;
+
+		h_proto = vlan_hdr->h_vlan_encapsulated_proto;
+		l3 = vlan_hdr + 1;
+	}
+
+	if (h_proto == bpf_htons(ETH_P_IP)) {
+		struct iphdr *ip = l3;
 		if (ip + 1 > (struct iphdr *)data_end)
 			return XDP_ABORTED;
 
-		if (ip->daddr != bpf_htonl(get_val(u32, bind_addr_map)))
+		if (!ip4_is_local(vlan, ip->daddr))
 			return XDP_PASS;
-		if (ip->protocol != IPPROTO_UDP)
+
+		/* Packets of other protocols are only redirected if the stack
+		 * is to answer them with ICMP protocol unreachable messages.
+		 */
+		if (ip->protocol != IPPROTO_UDP && get_val(u32, all_protocols_map))
+			return redirect_to_xsk(xdp, 0);
+
+		if (ip->protocol != IPPROTO_UDP && ip->protocol != IPPROTO_ICMP)
 			return XDP_PASS;
 
-		struct udphdr *udp = (struct udphdr *)(ip + 1);
+		/* Only the first fragment of a datagram carries the UDP or
+		 * ICMP header: all the fragments are steered by source address
+		 * and ID instead, so that they are reassembled by the same
+		 * socket, and their port or type is matched by the stack.
+		 */
+		if (ip->frag_off & bpf_htons(IP_MF | IP_OFFSET))
+			return redirect_to_xsk(xdp, ip->saddr ^ (ip->saddr >> 16) ^ ip->id);
+
+		if (ip->ihl < 5)
+			return XDP_ABORTED;
+
+		if (ip->protocol == IPPROTO_ICMP)
+			return handle_icmp(xdp, (void *)ip + ip->ihl * 4, data_end);
+
+		struct udphdr *udp = (void *)ip + ip->ihl * 4;
 		if (udp + 1 > (struct udphdr *)data_end)
 			return XDP_ABORTED;
 
-		if (udp->dest != bpf_htons(get_val(u16, bind_port_map)))
+		struct listener key = { .addr = ip->daddr, .port = udp->dest };
+		if (!bpf_map_lookup_elem(&listener_map, &key))
 			return XDP_PASS;
 
 		return redirect_to_xsk(xdp, udp->source);
-	} else if (eth->h_proto == bpf_htons(ETH_P_ARP)) {
-		return redirect_to_xsk(xdp, 0);
+	} else if (h_proto == bpf_htons(ETH_P_ARP)) {
+		return handle_arp(xdp, vlan, l3, data_end);
+	} else if (h_proto == bpf_htons(ETH_P_IPV6)) {
+		return handle_ip6(xdp, vlan, l3, data_end);
 	}
 
 	return XDP_PASS;
@@ -37,7 +37,7 @@ impl EchoApp {
         socket: &net::app::Socket,
         rx_payload: &[u8],
     ) -> anyhow::Result<()> {
-        let mut tx_payload = netstack_handle.new_tx_payload_buf()?;
+        let mut tx_payload = netstack_handle.new_tx_payload_buf(socket, rx_payload.len())?;
 
         tx_payload
             .packet_buf()
@@ -73,13 +73,22 @@ impl net::app::App for EchoApp {
         &mut self,
         netstack_handle: &mut dyn net::app::Handle,
         socket: &net::app::Socket,
+        _metadata: &net::app::RxMetadata,
         rx_payload: &mut [u8],
     ) -> anyhow::Result<()> {
-        EchoApp::send_echo_response(netstack_handle, socket, rx_payload)?;
+        // The payload must be copied before the frame it is in is handed over to the TX ring
         if self.repeat {
             EchoApp::schedule_echo_response(self.netstack_handle.clone(), socket, rx_payload)?;
         }
 
+        // Reply in place of the request, unless it was reassembled from several fragments
+        match netstack_handle.send_rx_payload(socket, rx_payload.len()) {
+            Err(e) if matches!(e.downcast_ref(), Some(net::Error::NoRxFrame)) => {
+                EchoApp::send_echo_response(netstack_handle, socket, rx_payload)?
+            }
+            ret => ret?,
+        }
+
         Ok(())
     }
 }
@@ -22,7 +22,7 @@ use clap::{ArgAction, Parser};
 use simple_signal::Signal;
 
 use std::{
-    net::Ipv4Addr,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::ParseIntError,
     sync::{Arc, RwLock},
 };
@@ -40,10 +40,33 @@ pub struct Args {
     #[arg(short = 'a', long = "address")]
     pub bind_address: Ipv4Addr,
 
+    /// Sets the IPv6 bind address
+    #[arg(long = "address6")]
+    pub bind_address6: Option<Ipv6Addr>,
+
+    /// Binds an address on a VLAN, e.g. 100=198.18.100.2, or 10.100=198.18.100.2 for QinQ
+    #[arg(long = "vlan-address", value_parser = parse_vlan_address)]
+    pub vlan_addresses: Vec<(xsk::Vlan, IpAddr)>,
+
     /// Sets the bind port
     #[arg(short = 'p', long = "port")]
     pub bind_port: u16,
 
+    /// Listens on an additional address and port, e.g. 198.18.3.3:53 or [fc00::3]:53
+    #[arg(long = "listen")]
+    pub listeners: Vec<SocketAddr>,
+
+    /// Drops the datagrams for unserved ports instead of answering with ICMP port unreachable.
+    /// Only fragmented datagrams are concerned: unfragmented ones are left to the kernel
+    #[arg(long = "drop-unmatched")]
+    pub drop_unmatched: bool,
+
+    /// Redirects the IPv4 packets of every protocol sent to the bind addresses to the stack,
+    /// which answers the unserved ones with ICMP protocol unreachable messages, rather than
+    /// leaving them to the kernel
+    #[arg(long = "all-protocols")]
+    pub all_protocols: bool,
+
     /// Sets the XDP program path
     #[arg(long = "xdp-prog-path")]
     pub xdp_prog_path: Option<String>,
@@ -52,6 +75,14 @@ pub struct Args {
     #[arg(long = "queue")]
     pub queue: Option<Vec<usize>>,
 
+    /// Runs on all the RX queues of the interface
+    #[arg(long = "all-queues", conflicts_with = "queue")]
+    pub all_queues: bool,
+
+    /// Skips checking that the given queues exist on the interface
+    #[arg(long = "no-validate-queues")]
+    pub no_validate_queues: bool,
+
     /// Sets the number of XSK socks per queue
     #[arg(long = "socks-per-queue", value_parser = validate_socks_per_queue)]
     pub socks_per_queue: Option<usize>,
@@ -68,13 +99,58 @@ pub struct Args {
     #[arg(long = "frame-size")]
     pub frame_size: Option<usize>,
 
-    /// Sets the xsk mode of operation
+    /// Shares a single UMEM between all the queues
+    #[arg(long = "shared-umem")]
+    pub shared_umem: bool,
+
+    /// Pins the RX threads to CPUs: none, irq (follow the queues IRQ affinity) or a CPU list
+    #[arg(long = "cpu-affinity")]
+    pub cpu_affinity: Option<xsk::CpuAffinity>,
+
+    /// Sets the xsk mode of operation: skb, drv, drv-zc or auto
     #[arg(long = "xsk-mode")]
     pub xsk_mode: Option<xsk::XskMode>,
 
     /// Disable the XDP_NEEDS_WAKEUP flag (required for kernels < 4.4)
     #[arg(long = "no-needs-wakeup", action=ArgAction::SetTrue, value_parser = parse_no_needs_wakeup)]
     pub needs_wakeup: xsk::NeedsWakeup,
+
+    /// Sets the number of ARP probes sent to detect conflicts on the bind address, exiting if
+    /// another host is found using it
+    #[arg(long = "arp-probes")]
+    pub arp_probes: Option<usize>,
+
+    /// Sets the number of gratuitous ARPs sent to announce the bind address
+    #[arg(long = "arp-announcements")]
+    pub arp_announcements: Option<usize>,
+
+    /// Sets the MTU, which defaults to the one of the interface
+    #[arg(long = "mtu", value_parser = validate_mtu)]
+    pub mtu: Option<usize>,
+
+    /// Fragments the IPv4 payloads larger than the MTU instead of dropping them
+    #[arg(long = "fragment")]
+    pub fragment: bool,
+
+    /// Sets the default gateway, for IPv4 or IPv6
+    #[arg(long = "gateway")]
+    pub gateways: Vec<IpAddr>,
+
+    /// Imports the routes of the interface from the main routing table of the kernel
+    #[arg(long = "import-routes")]
+    pub import_routes: bool,
+}
+
+fn validate_mtu(mtu: &str) -> Result<usize, String> {
+    let val: usize = mtu.parse().map_err(|e: ParseIntError| e.to_string())?;
+    if val >= net::IP4_MIN_MTU {
+        Ok(val)
+    } else {
+        Err(format!(
+            "lower than the minimum MTU of {}",
+            net::IP4_MIN_MTU
+        ))
+    }
 }
 
 fn validate_socks_per_queue(socks: &str) -> Result<usize, String> {
@@ -92,6 +168,17 @@ fn parse_no_needs_wakeup(val: &str) -> Result<xsk::NeedsWakeup, String> {
         .map_err(|e| e.to_string())
 }
 
+fn parse_vlan_address(val: &str) -> Result<(xsk::Vlan, IpAddr), String> {
+    let (vlan, address) = val
+        .split_once('=')
+        .ok_or_else(|| "expected VLAN=ADDRESS".to_string())?;
+
+    Ok((
+        vlan.parse::<xsk::Vlan>().map_err(|e| e.to_string())?,
+        address.parse().map_err(|e: AddrParseError| e.to_string())?,
+    ))
+}
+
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
@@ -110,8 +197,9 @@ fn main() {
     });
 
     let runner = xsk.runner();
+    let signal_runner = runner.clone();
     simple_signal::set_handler(&[Signal::Int, Signal::Term], move |_signals| {
-        runner.clone().stop();
+        signal_runner.clone().stop();
     });
 
     info!(
@@ -119,32 +207,88 @@ fn main() {
         args.interface, args.bind_address, args.bind_port
     );
 
+    for listener in &args.listeners {
+        info!("Listening on {}, {}", args.interface, listener);
+    }
+
     xsk.wait_for_threads();
+
+    if runner.has_failed() {
+        drop(xsk);
+        std::process::exit(1);
+    }
 }
 
 fn build_xsk_config(args: &Args) -> xsk::Configuration {
     let mut cfg = xsk::Configuration::default();
 
-    let net_allocator: Box<xsk::net::NetAllocator> = Box::new(|xsk_handle: xsk::net::Handle| {
-        let app_allocator: Box<net::app::AppAllocator> =
-            Box::new(|net_handle: Arc<RwLock<dyn net::app::Handle>>| {
-                Box::new(echo::EchoApp::new(net_handle, false))
-            });
+    let arp_probes = args.arp_probes;
+    let arp_announcements = args.arp_announcements;
+    let mtu = args.mtu;
+    let fragment = args.fragment;
+    let gateways = args.gateways.clone();
+    let import_routes = args.import_routes;
+    let drop_unmatched = args.drop_unmatched;
 
-        let mut net_cfg = net::Configuration::default();
+    let net_allocator: Box<xsk::net::NetAllocator> =
+        Box::new(move |xsk_handle: xsk::net::Handle| {
+            let app_allocator: Box<net::app::AppAllocator> =
+                Box::new(|net_handle: Arc<RwLock<dyn net::app::Handle>>| {
+                    Box::new(echo::EchoApp::new(net_handle, false))
+                });
 
-        net_cfg
-            .set_app_allocator(app_allocator)
-            .set_xsk_handle(xsk_handle);
+            let mut net_cfg = net::Configuration::default();
 
-        Box::new(net::Net::new(net_cfg))
-    });
+            net_cfg
+                .set_app_allocator(app_allocator)
+                .set_xsk_handle(xsk_handle);
+
+            if let Some(v) = arp_probes {
+                net_cfg.set_arp_probes(v);
+            }
+
+            if let Some(v) = arp_announcements {
+                net_cfg.set_arp_announcements(v);
+            }
+
+            if let Some(v) = mtu {
+                net_cfg.set_mtu(v);
+            }
+
+            net_cfg.set_ip4_dont_fragment(!fragment);
+
+            for gateway in &gateways {
+                net_cfg.set_default_gateway(*gateway);
+            }
+
+            net_cfg.set_import_routes(import_routes);
+
+            if drop_unmatched {
+                net_cfg.set_unmatched_port(net::app::UnmatchedPort::Drop);
+            }
+
+            Box::new(net::Net::new(net_cfg))
+        });
 
     cfg.set_interface(&args.interface)
         .set_bind_address(args.bind_address)
         .set_bind_port(args.bind_port)
         .set_net_allocator(net_allocator);
 
+    if let Some(v) = args.bind_address6 {
+        cfg.set_bind_address6(v);
+    }
+
+    for (vlan, address) in &args.vlan_addresses {
+        cfg.add_vlan_bind_address(*vlan, *address);
+    }
+
+    for listener in &args.listeners {
+        cfg.add_listener(*listener);
+    }
+
+    cfg.set_all_protocols(args.all_protocols);
+
     if let Some(v) = args.xdp_prog_path.as_ref() {
         cfg.set_xdp_prog_path(v);
     }
@@ -153,6 +297,9 @@ fn build_xsk_config(args: &Args) -> xsk::Configuration {
         cfg.set_queues(v.clone());
     }
 
+    cfg.set_all_queues(args.all_queues);
+    cfg.set_validate_queues(!args.no_validate_queues);
+
     if let Some(v) = args.socks_per_queue {
         cfg.set_socks_per_queue(v);
     }
@@ -169,6 +316,12 @@ fn build_xsk_config(args: &Args) -> xsk::Configuration {
         cfg.set_frame_size(v);
     }
 
+    cfg.set_shared_umem(args.shared_umem);
+
+    if let Some(v) = args.cpu_affinity.as_ref() {
+        cfg.set_cpu_affinity(v.clone());
+    }
+
     if let Some(v) = args.xsk_mode {
         cfg.set_mode(v);
     }
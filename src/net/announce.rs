@@ -0,0 +1,232 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Announcement of the bind address on startup.
+//!
+//! When the stack is brought up it optionally probes the network for other hosts using the bind
+//! address (Address Conflict Detection, RFC 5227) and then announces the address with a number
+//! of gratuitous ARP packets, so that neighbors replace any stale entry they may have for it.
+//!
+//! As for the neighbor cache, the [`Announcer`] only tells the caller when a probe or an
+//! announcement has to be sent.
+
+use std::time::{Duration, Instant};
+
+use crate::net::Configuration;
+
+/// State of the [`Announcer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnouncerState {
+    /// Probing for other hosts using the address.
+    Probing,
+    /// Sending the gratuitous ARP announcements.
+    Announcing,
+    /// All the announcements have been sent.
+    Done,
+    /// Another host was found using the address while probing.
+    Conflict,
+}
+
+/// Packet that must be sent by the caller of [`Announcer::poll`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Announcement {
+    /// An ARP probe, i.e. a request with an unspecified sender address.
+    Probe,
+    /// A gratuitous ARP, i.e. a request with both sender and target set to the bind address.
+    Announce,
+}
+
+pub struct Announcer {
+    state: AnnouncerState,
+    sent:  usize,
+    next:  Instant,
+
+    probes:            usize,
+    probe_interval:    Duration,
+    announcements:     usize,
+    announce_interval: Duration,
+}
+
+impl Announcer {
+    pub fn new(configuration: &Configuration, now: Instant) -> Self {
+        let mut announcer = Announcer {
+            state: AnnouncerState::Done,
+            sent:  0,
+            next:  now,
+
+            probes:            configuration.arp_probes(),
+            probe_interval:    configuration.arp_probe_interval(),
+            announcements:     configuration.arp_announcements(),
+            announce_interval: configuration.arp_announce_interval(),
+        };
+
+        announcer.restart(now);
+        announcer
+    }
+
+    pub fn state(&self) -> AnnouncerState {
+        self.state
+    }
+
+    /// Returns true while the address is being probed, i.e. it must not be used yet.
+    pub fn is_probing(&self) -> bool {
+        self.state == AnnouncerState::Probing
+    }
+
+    /// Returns true if the address can be used: it is not being probed, and no other host was
+    /// found using it.
+    pub fn is_claimed(&self) -> bool {
+        !matches!(
+            self.state,
+            AnnouncerState::Probing | AnnouncerState::Conflict
+        )
+    }
+
+    /// Starts probing and announcing the address again, e.g. after it has been changed.
+    pub fn restart(&mut self, now: Instant) {
+        self.state = if self.probes > 0 {
+            AnnouncerState::Probing
+        } else {
+            AnnouncerState::Announcing
+        };
+        self.sent = 0;
+        self.next = now;
+    }
+
+    /// Records that another host is using the address.
+    ///
+    /// Returns true if the conflict was detected while probing, in which case no announcement
+    /// will be sent.
+    pub fn conflict(&mut self) -> bool {
+        if self.state != AnnouncerState::Probing {
+            return false;
+        }
+
+        self.state = AnnouncerState::Conflict;
+        true
+    }
+
+    /// Returns the next packet to send, if one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<Announcement> {
+        if now < self.next {
+            return None;
+        }
+
+        if self.state == AnnouncerState::Probing {
+            if self.sent < self.probes {
+                self.sent += 1;
+                self.next = now + self.probe_interval;
+
+                return Some(Announcement::Probe);
+            }
+
+            self.state = AnnouncerState::Announcing;
+            self.sent = 0;
+        }
+
+        if self.state != AnnouncerState::Announcing {
+            return None;
+        }
+
+        if self.sent >= self.announcements {
+            self.state = AnnouncerState::Done;
+            return None;
+        }
+
+        self.sent += 1;
+        self.next = now + self.announce_interval;
+        if self.sent == self.announcements {
+            self.state = AnnouncerState::Done;
+        }
+
+        Some(Announcement::Announce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_announcer(probes: usize, announcements: usize, now: Instant) -> Announcer {
+        let mut cfg = Configuration::default();
+        cfg.set_arp_probes(probes)
+            .set_arp_probe_interval(Duration::from_secs(1))
+            .set_arp_announcements(announcements)
+            .set_arp_announce_interval(Duration::from_secs(2));
+
+        Announcer::new(&cfg, now)
+    }
+
+    #[test]
+    fn test_announce() {
+        let now = Instant::now();
+        let mut announcer = new_announcer(0, 2, now);
+
+        assert_eq!(announcer.poll(now), Some(Announcement::Announce));
+        assert_eq!(announcer.poll(now + Duration::from_secs(1)), None);
+        assert_eq!(
+            announcer.poll(now + Duration::from_secs(2)),
+            Some(Announcement::Announce)
+        );
+        assert_eq!(announcer.state(), AnnouncerState::Done);
+        assert_eq!(announcer.poll(now + Duration::from_secs(4)), None);
+    }
+
+    #[test]
+    fn test_probe_then_announce() {
+        let now = Instant::now();
+        let mut announcer = new_announcer(2, 1, now);
+
+        assert_eq!(announcer.poll(now), Some(Announcement::Probe));
+        assert_eq!(
+            announcer.poll(now + Duration::from_secs(1)),
+            Some(Announcement::Probe)
+        );
+        assert!(announcer.is_probing());
+        assert_eq!(
+            announcer.poll(now + Duration::from_secs(2)),
+            Some(Announcement::Announce)
+        );
+        assert_eq!(announcer.state(), AnnouncerState::Done);
+    }
+
+    #[test]
+    fn test_conflict() {
+        let now = Instant::now();
+        let mut announcer = new_announcer(2, 1, now);
+
+        assert_eq!(announcer.poll(now), Some(Announcement::Probe));
+        assert!(announcer.conflict());
+        assert!(!announcer.is_claimed());
+
+        // Neither probes nor announcements are sent after a conflict
+        for secs in 1..8 {
+            assert_eq!(announcer.poll(now + Duration::from_secs(secs)), None);
+        }
+        assert_eq!(announcer.state(), AnnouncerState::Conflict);
+        assert!(!announcer.is_claimed());
+
+        // Conflicts are not reported once the address has been claimed
+        announcer.restart(now);
+        for secs in 0..4 {
+            announcer.poll(now + Duration::from_secs(secs));
+        }
+        assert_eq!(announcer.state(), AnnouncerState::Done);
+        assert!(announcer.is_claimed());
+        assert!(!announcer.conflict());
+    }
+}
@@ -18,11 +18,16 @@
 //! Interfaces for glueing together `net` and an app.
 
 use std::{
-    net::Ipv4Addr,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, RwLock},
+    time::Instant,
 };
 
-use crate::{net::PacketBufMut, xsk};
+use crate::{
+    net::{IpIdPolicy, PacketBufMut, VlanTags},
+    xsk,
+};
 
 /// Signature of the closure that `xsk` expects to call whenever it needs to allocate a new network
 /// stack object.
@@ -33,29 +38,180 @@ pub type AppAllocator = dyn Fn(Arc<RwLock<dyn Handle>>) -> Box<dyn App>;
 /// Trait that a generic app object must implement in order to receive payloads from the
 /// `net` module.
 pub trait App: Send {
+    /// Callback invoked for each datagram received by the app. `socket` can be used to reply to
+    /// the sender, while `metadata` describes how the datagram was received.
     fn rx_payload(
         &mut self,
         netstack_handle: &mut dyn Handle,
         socket: &Socket,
+        metadata: &RxMetadata,
         rx_payload: &mut [u8],
     ) -> anyhow::Result<()>;
 }
 
+/// Metadata of a received datagram.
+#[derive(Clone, Debug)]
+pub struct RxMetadata {
+    pub source_address:    IpAddr,
+    pub source_port:       u16,
+    pub dest_address:      IpAddr,
+    pub dest_port:         u16,
+    /// IPv4 TTL or IPv6 hop limit.
+    pub ttl:               u8,
+    /// IPv4 TOS or IPv6 traffic class: the DSCP followed by the ECN bits.
+    pub tos:               u8,
+    /// Link-layer address of the sender, or of the router that forwarded the datagram.
+    pub source_hw_address: [u8; 6],
+    /// VLAN the datagram was received on, if any.
+    pub vlan:              Option<xsk::Vlan>,
+    /// Queue of the interface the datagram was received on.
+    pub rx_queue:          usize,
+    /// Index of the XSK socket the datagram was received on.
+    pub socket_index:      usize,
+    /// Time at which the frame carrying the datagram (or its last fragment) was taken from the
+    /// RX ring.
+    pub timestamp:         Instant,
+}
+
+impl RxMetadata {
+    /// Returns the Differentiated Services Code Point of the datagram.
+    pub fn dscp(&self) -> u8 {
+        self.tos >> 2
+    }
+
+    /// Returns the Explicit Congestion Notification bits of the datagram.
+    pub fn ecn(&self) -> u8 {
+        self.tos & 0x3
+    }
+}
+
+/// How the datagrams addressed to a port with no app registered are handled.
+///
+/// The XDP program only redirects to the stack the datagrams sent to its listeners, leaving
+/// the other ones to the kernel, except for fragmented IPv4 datagrams, whose port it cannot
+/// match. This only applies to those once reassembled, and to the datagrams of the listeners
+/// with no app to receive them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnmatchedPort {
+    /// Drop the datagrams silently.
+    Drop,
+    /// Answer with an ICMP, or ICMPv6, port unreachable message.
+    Unreachable,
+}
+
+/// The apps of a network stack, each receiving the datagrams sent to a given port.
+///
+/// A datagram is delivered to the app registered for its destination address and port, then to
+/// the app registered for its destination port on any address and finally, if the address and
+/// port are one of the listeners of the stack, to the default app.
+#[derive(Default)]
+pub struct AppRegistry {
+    apps:        HashMap<(Option<IpAddr>, u16), Box<dyn App>>,
+    default_app: Option<Box<dyn App>>,
+}
+
+impl AppRegistry {
+    /// Register the app receiving the datagrams sent to `port`, on `address` or on any address.
+    pub fn add(&mut self, address: Option<IpAddr>, port: u16, app: Box<dyn App>) {
+        self.apps.insert((address, port), app);
+    }
+
+    /// Set the app receiving the datagrams sent to the listeners with no app registered.
+    pub fn set_default(&mut self, app: Box<dyn App>) {
+        self.default_app = Some(app);
+    }
+
+    /// Returns the app receiving the datagrams sent to `address` and `port`. `listening` tells
+    /// whether they are one of the listeners of the stack.
+    pub fn get_mut(
+        &mut self,
+        address: IpAddr,
+        port: u16,
+        listening: bool,
+    ) -> Option<&mut Box<dyn App>> {
+        if self.apps.contains_key(&(Some(address), port)) {
+            return self.apps.get_mut(&(Some(address), port));
+        }
+
+        if self.apps.contains_key(&(None, port)) {
+            return self.apps.get_mut(&(None, port));
+        }
+
+        match self.default_app.as_mut() {
+            Some(app) if listening => Some(app),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Socket {
-    pub source_address: Ipv4Addr,
+    pub source_address: IpAddr,
     pub source_port:    u16,
+    /// Local address the datagrams were received on, used as source of the replies.
+    pub local_address:  IpAddr,
+    /// Local port the datagrams were received on, used as source port of the replies.
+    pub local_port:     u16,
+    /// Whether IPv4 payloads are sent with the Don't Fragment flag set, failing if they do not
+    /// fit in the MTU, or fragmented.
+    pub dont_fragment:  bool,
+    /// VLAN tags of the payloads sent to the socket, the ones of the payload it was created from.
+    pub vlan:           VlanTags,
 }
 
+/// IP header fields of a payload sent, which default to the ones of
+/// [`crate::net::Configuration`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TxOptions {
+    /// Differentiated Services Code Point.
+    pub dscp:  u8,
+    /// Explicit Congestion Notification codepoint.
+    pub ecn:   u8,
+    /// IPv4 TTL or IPv6 hop limit.
+    pub ttl:   u8,
+    /// How the ID of IPv4 datagrams is chosen.
+    pub ip_id: IpIdPolicy,
+}
+
+impl TxOptions {
+    /// Returns the IPv4 TOS or IPv6 traffic class: the DSCP followed by the ECN bits.
+    pub fn tos(&self) -> u8 {
+        ((self.dscp & 0x3f) << 2) | (self.ecn & 0x3)
+    }
+}
+
+/// A buffer for the payload of an outgoing datagram.
+///
+/// Dropping a buffer that has not been sent gives back its TX descriptor and UMEM frame.
 pub struct PayloadBuf<'a> {
-    xdp_desc:   xsk::Desc,
+    xdp_desc:   Option<xsk::Desc>,
+    tx_options: TxOptions,
+    /// Memory backing `packet_buf` when there is no UMEM frame.
+    _buffer:    Vec<u8>,
     packet_buf: PacketBufMut<'a>,
 }
 
 impl<'a> PayloadBuf<'a> {
-    pub fn new(xdp_desc: xsk::Desc, packet_buf: PacketBufMut<'a>) -> Self {
+    pub fn new(xdp_desc: xsk::Desc, packet_buf: PacketBufMut<'a>, tx_options: TxOptions) -> Self {
+        PayloadBuf {
+            xdp_desc: Some(xdp_desc),
+            tx_options,
+            _buffer: Vec::new(),
+            packet_buf,
+        }
+    }
+
+    /// Creates a new [`PayloadBuf`] object backed by a `len` bytes buffer rather than an UMEM
+    /// frame, for packets that need to be fragmented.
+    pub fn with_len(len: usize, tx_options: TxOptions) -> Self {
+        let mut buffer = vec![0; len];
+        // The buffer is on the heap, so it does not move along with the `PayloadBuf` object
+        let packet_buf = PacketBufMut::from_raw_parts(buffer.as_mut_ptr(), len);
+
         PayloadBuf {
-            xdp_desc,
+            xdp_desc: None,
+            tx_options,
+            _buffer: buffer,
             packet_buf,
         }
     }
@@ -64,13 +220,156 @@ impl<'a> PayloadBuf<'a> {
         &mut self.packet_buf
     }
 
-    pub fn xdp_desc(&mut self) -> &mut xsk::Desc {
-        &mut self.xdp_desc
+    /// Get the IP header fields the payload is sent with.
+    pub fn tx_options(&self) -> &TxOptions {
+        &self.tx_options
+    }
+
+    /// Get the IP header fields the payload is sent with, to override them for this payload.
+    pub fn tx_options_mut(&mut self) -> &mut TxOptions {
+        &mut self.tx_options
+    }
+
+    /// Get the TX descriptor of the UMEM frame backing the buffer, if any.
+    pub fn xdp_desc(&mut self) -> Option<&mut xsk::Desc> {
+        self.xdp_desc.as_mut()
+    }
+
+    /// Take the TX descriptor of the UMEM frame backing the buffer, if any, to transmit it.
+    pub fn take_xdp_desc(&mut self) -> Option<xsk::Desc> {
+        self.xdp_desc.take()
     }
 }
 
 pub trait Handle: Sync + Send {
-    fn new_tx_payload_buf<'a>(&mut self) -> anyhow::Result<PayloadBuf<'a>>;
+    /// Returns a new socket sending payloads from `local_port` to `remote`, which can be any
+    /// destination reachable by the stack rather than the sender of a received datagram.
+    ///
+    /// Payloads are sourced from the untagged address of the same family as `remote` and the
+    /// replies, redirected to the stack from then on, are delivered to the app registered for
+    /// `local_port`, or to the default app. Fails with [`crate::net::Error::NoApp`] if there is
+    /// neither, and with [`crate::net::Error::NoRoute`] if there is no route toward `remote`.
+    fn new_socket(&self, remote: SocketAddr, local_port: u16) -> anyhow::Result<Socket>;
+    /// Returns a new buffer for a payload of up to `len` bytes, positioned at the offset of the
+    /// payload.
+    fn new_tx_payload_buf<'a>(
+        &mut self,
+        socket: &Socket,
+        len: usize,
+    ) -> anyhow::Result<PayloadBuf<'a>>;
+    /// Returns the maximum length of a payload that can be sent to `socket` without being
+    /// fragmented, according to the MTU of the path toward it.
+    fn max_payload_len(&self, socket: &Socket) -> usize;
     fn send_payload(&mut self, socket: &Socket, payload_buf: &mut PayloadBuf)
         -> anyhow::Result<()>;
+    /// Sends the first `len` bytes of the payload being received to `socket`, reusing the frame
+    /// the payload was received in rather than copying it to a new one. The payload can be
+    /// rewritten in place before.
+    ///
+    /// It can only be called once from [`App::rx_payload`], and fails with
+    /// [`crate::net::Error::NoRxFrame`] for datagrams reassembled from several fragments, or when
+    /// the link-layer address of the peer is not known yet: the reply must then be copied.
+    fn send_rx_payload(&mut self, socket: &Socket, len: usize) -> anyhow::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestApp(u8);
+
+    impl App for TestApp {
+        fn rx_payload(
+            &mut self,
+            _netstack_handle: &mut dyn Handle,
+            _socket: &Socket,
+            _metadata: &RxMetadata,
+            rx_payload: &mut [u8],
+        ) -> anyhow::Result<()> {
+            rx_payload[0] = self.0;
+            Ok(())
+        }
+    }
+
+    fn app_id(app: Option<&mut Box<dyn App>>) -> Option<u8> {
+        struct NoHandle;
+
+        impl Handle for NoHandle {
+            fn new_socket(&self, _remote: SocketAddr, _local_port: u16) -> anyhow::Result<Socket> {
+                unimplemented!()
+            }
+
+            fn new_tx_payload_buf<'a>(
+                &mut self,
+                _socket: &Socket,
+                _len: usize,
+            ) -> anyhow::Result<PayloadBuf<'a>> {
+                unimplemented!()
+            }
+
+            fn max_payload_len(&self, _socket: &Socket) -> usize {
+                unimplemented!()
+            }
+
+            fn send_payload(
+                &mut self,
+                _socket: &Socket,
+                _payload_buf: &mut PayloadBuf,
+            ) -> anyhow::Result<()> {
+                unimplemented!()
+            }
+
+            fn send_rx_payload(&mut self, _socket: &Socket, _len: usize) -> anyhow::Result<()> {
+                unimplemented!()
+            }
+        }
+
+        let address = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+        let socket = Socket {
+            source_address: address,
+            source_port:    0,
+            local_address:  address,
+            local_port:     0,
+            dont_fragment:  true,
+            vlan:           VlanTags::default(),
+        };
+
+        let metadata = RxMetadata {
+            source_address:    address,
+            source_port:       0,
+            dest_address:      address,
+            dest_port:         0,
+            ttl:               64,
+            tos:               0,
+            source_hw_address: [0; 6],
+            vlan:              None,
+            rx_queue:          0,
+            socket_index:      0,
+            timestamp:         Instant::now(),
+        };
+
+        let mut payload = [0];
+        app?.rx_payload(&mut NoHandle, &socket, &metadata, &mut payload)
+            .unwrap();
+
+        Some(payload[0])
+    }
+
+    #[test]
+    fn test_app_registry() {
+        let a1: IpAddr = "198.18.0.1".parse().unwrap();
+        let a2: IpAddr = "198.18.0.2".parse().unwrap();
+
+        let mut apps = AppRegistry::default();
+        assert_eq!(app_id(apps.get_mut(a1, 7, true)), None);
+
+        apps.set_default(Box::new(TestApp(1)));
+        apps.add(None, 53, Box::new(TestApp(2)));
+        apps.add(Some(a2), 53, Box::new(TestApp(3)));
+
+        assert_eq!(app_id(apps.get_mut(a1, 1234, true)), Some(1));
+        assert_eq!(app_id(apps.get_mut(a1, 1234, false)), None);
+        assert_eq!(app_id(apps.get_mut(a1, 53, false)), Some(2));
+        assert_eq!(app_id(apps.get_mut(a2, 53, false)), Some(3));
+    }
 }
@@ -18,18 +18,34 @@
 use crate::net;
 use crate::net::{EthType, PacketBufMut, Result};
 
+use std::convert::TryFrom;
 use std::net::Ipv4Addr;
-use std::{fmt, mem};
+use std::{fmt, mem, result};
 
 pub enum Htype {
     Ethernet = 0x1,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum ArpOpcode {
     REQUEST = 0x1,
     REPLY = 0x2,
 }
 
+impl TryFrom<u16> for ArpOpcode {
+    type Error = ();
+
+    fn try_from(x: u16) -> result::Result<Self, Self::Error> {
+        use ArpOpcode::*;
+
+        match x {
+            x if x == REQUEST as u16 => Ok(REQUEST),
+            x if x == REPLY as u16 => Ok(REPLY),
+            _ => Err(()),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct ArpHdr {
     pub hw_type:           u16,
@@ -52,6 +68,36 @@ impl ArpHdr {
             .map(|l3_slice| unsafe { &mut *(l3_slice.as_mut_ptr() as *mut ArpHdr) })
     }
 
+    /// Returns true if the header describes an Ethernet/IPv4 ARP packet.
+    pub fn is_ethernet_ip4(&self) -> bool {
+        net::utils::ntohs(self.hw_type) == Htype::Ethernet as u16
+            && net::utils::ntohs(self.proto_type) == EthType::IP4 as u16
+            && self.hw_addr_len == 6
+            && self.proto_addr_len == 4
+    }
+
+    pub fn opcode(&self) -> Option<ArpOpcode> {
+        ArpOpcode::try_from(net::utils::ntohs(self.opcode)).ok()
+    }
+
+    pub fn sender_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.sender_proto_addr)
+    }
+
+    pub fn target_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.target_proto_addr)
+    }
+
+    pub fn arp_request_ip(&mut self) -> &mut Self {
+        self.hw_type = net::utils::htons(Htype::Ethernet as u16);
+        self.proto_type = net::utils::htons(EthType::IP4 as u16);
+        self.hw_addr_len = 6;
+        self.proto_addr_len = 4;
+        self.opcode = net::utils::htons(ArpOpcode::REQUEST as u16);
+
+        self
+    }
+
     pub fn arp_reply_ip(&mut self) -> &mut Self {
         self.hw_type = net::utils::htons(Htype::Ethernet as u16);
         self.proto_type = net::utils::htons(EthType::IP4 as u16);
@@ -15,26 +15,149 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::{net::app::AppAllocator, xsk};
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    net::{
+        app::{AppAllocator, TxOptions, UnmatchedPort},
+        IpIdPolicy, Route,
+    },
+    xsk,
+};
 
 /// Configuration builder for a App object.
-#[derive(Default)]
 pub struct Configuration {
-    app_allocator: Option<Box<AppAllocator>>,
-    xsk_handle:    Option<xsk::net::Handle>,
+    app_allocator:  Option<Box<AppAllocator>>,
+    app_allocators: Vec<(Option<IpAddr>, u16, Box<AppAllocator>)>,
+    unmatched_port: UnmatchedPort,
+    xsk_handle:     Option<xsk::net::Handle>,
+
+    neigh_backlog_size:   usize,
+    neigh_retrans_time:   Duration,
+    neigh_max_solicits:   usize,
+    neigh_reachable_time: Duration,
+    neigh_stale_time:     Duration,
+    neigh_max_entries:    usize,
+
+    arp_probes:            usize,
+    arp_probe_interval:    Duration,
+    arp_announcements:     usize,
+    arp_announce_interval: Duration,
+
+    icmp_ratelimit:       u32,
+    icmp_ratelimit_burst: u32,
+
+    ip4_udp_checksum: bool,
+
+    ip4_frag_timeout:    Duration,
+    ip4_frag_max_memory: usize,
+
+    mtu:               Option<usize>,
+    ip4_dont_fragment: bool,
+
+    pmtu_timeout:     Duration,
+    pmtu_max_entries: usize,
+
+    routes:        Vec<Route>,
+    import_routes: bool,
+
+    dscp:          u8,
+    ecn:           u8,
+    ttl:           u8,
+    ip4_id_policy: IpIdPolicy,
 }
 
+impl Default for Configuration {
+    /// Creates a new [`Configuration`] object with the default values.
+    fn default() -> Self {
+        Configuration {
+            app_allocator:  None,
+            app_allocators: Vec::new(),
+            unmatched_port: UnmatchedPort::Unreachable,
+            xsk_handle:     None,
+
+            neigh_backlog_size:   16,
+            neigh_retrans_time:   Duration::from_secs(1),
+            neigh_max_solicits:   3,
+            neigh_reachable_time: Duration::from_secs(30),
+            neigh_stale_time:     Duration::from_secs(60),
+            neigh_max_entries:    1024,
+
+            arp_probes:            0,
+            arp_probe_interval:    Duration::from_secs(1),
+            arp_announcements:     2,
+            arp_announce_interval: Duration::from_secs(2),
+
+            icmp_ratelimit:       1000,
+            icmp_ratelimit_burst: 50,
+
+            ip4_udp_checksum: true,
+
+            ip4_frag_timeout:    Duration::from_secs(30),
+            ip4_frag_max_memory: 4 * 1024 * 1024,
+
+            mtu:               None,
+            ip4_dont_fragment: true,
+
+            pmtu_timeout:     Duration::from_secs(600),
+            pmtu_max_entries: 1024,
+
+            routes:        Vec::new(),
+            import_routes: false,
+
+            dscp:          0,
+            ecn:           0,
+            ttl:           64,
+            ip4_id_policy: IpIdPolicy::Zero,
+        }
+    }
+}
 
 impl Configuration {
-    /// Set the AppAllocator callback.
+    /// Set the AppAllocator callback of the default app, which receives the datagrams sent to
+    /// the listening addresses and ports with no app registered.
     pub fn set_app_allocator(&mut self, app_allocator: Box<AppAllocator>) -> &mut Self {
         self.app_allocator = Some(app_allocator);
         self
     }
 
-    /// Get the AppAllocator callback.
-    pub fn app_allocator(&self) -> &AppAllocator {
-        self.app_allocator.as_ref().unwrap()
+    /// Get the AppAllocator callback of the default app, if any.
+    pub fn app_allocator(&self) -> Option<&AppAllocator> {
+        self.app_allocator.as_deref()
+    }
+
+    /// Register the AppAllocator callback of the app receiving the datagrams sent to `port`,
+    /// either on a single local `address` or on all of them.
+    ///
+    /// The port does not need to be one of the listening ports of the XSK configuration: it is
+    /// added to the listeners of the XDP program.
+    pub fn add_app_allocator(
+        &mut self,
+        address: Option<IpAddr>,
+        port: u16,
+        app_allocator: Box<AppAllocator>,
+    ) -> &mut Self {
+        self.app_allocators.push((address, port, app_allocator));
+        self
+    }
+
+    /// Get the AppAllocator callbacks registered for each port.
+    pub fn app_allocators(&self) -> &[(Option<IpAddr>, u16, Box<AppAllocator>)] {
+        &self.app_allocators
+    }
+
+    /// Set how datagrams with no app to receive them are handled.
+    ///
+    /// Datagrams for ports which are not listened on are left to the kernel, so this only
+    /// applies to the ones reassembled from IPv4 fragments: see [`UnmatchedPort`].
+    pub fn set_unmatched_port(&mut self, value: UnmatchedPort) -> &mut Self {
+        self.unmatched_port = value;
+        self
+    }
+
+    /// Get how datagrams with no app to receive them are handled.
+    pub fn unmatched_port(&self) -> UnmatchedPort {
+        self.unmatched_port
     }
 
     /// Set the XSK handle.
@@ -47,4 +170,309 @@ impl Configuration {
     pub fn take_xsk_handle(&mut self) -> xsk::net::Handle {
         self.xsk_handle.take().unwrap()
     }
+
+    /// Set the maximum number of payloads queued while resolving a neighbor. Datagrams sent as
+    /// more fragments than can be queued are rejected.
+    pub fn set_neigh_backlog_size(&mut self, value: usize) -> &mut Self {
+        self.neigh_backlog_size = value;
+        self
+    }
+
+    /// Get the maximum number of payloads queued while resolving a neighbor.
+    pub fn neigh_backlog_size(&self) -> usize {
+        self.neigh_backlog_size
+    }
+
+    /// Set the interval between two solicitations (e.g. ARP requests) for the same neighbor.
+    pub fn set_neigh_retrans_time(&mut self, value: Duration) -> &mut Self {
+        self.neigh_retrans_time = value;
+        self
+    }
+
+    /// Get the interval between two solicitations (e.g. ARP requests) for the same neighbor.
+    pub fn neigh_retrans_time(&self) -> Duration {
+        self.neigh_retrans_time
+    }
+
+    /// Set the number of solicitations sent before declaring a neighbor unreachable.
+    pub fn set_neigh_max_solicits(&mut self, value: usize) -> &mut Self {
+        self.neigh_max_solicits = value;
+        self
+    }
+
+    /// Get the number of solicitations sent before declaring a neighbor unreachable.
+    pub fn neigh_max_solicits(&self) -> usize {
+        self.neigh_max_solicits
+    }
+
+    /// Set for how long a neighbor is considered reachable after it has been confirmed.
+    pub fn set_neigh_reachable_time(&mut self, value: Duration) -> &mut Self {
+        self.neigh_reachable_time = value;
+        self
+    }
+
+    /// Get for how long a neighbor is considered reachable after it has been confirmed.
+    pub fn neigh_reachable_time(&self) -> Duration {
+        self.neigh_reachable_time
+    }
+
+    /// Set for how long a stale or failed neighbor is kept in the cache if it is not used.
+    pub fn set_neigh_stale_time(&mut self, value: Duration) -> &mut Self {
+        self.neigh_stale_time = value;
+        self
+    }
+
+    /// Get for how long a stale or failed neighbor is kept in the cache if it is not used.
+    pub fn neigh_stale_time(&self) -> Duration {
+        self.neigh_stale_time
+    }
+
+    /// Set the maximum number of entries of the neighbor cache.
+    pub fn set_neigh_max_entries(&mut self, value: usize) -> &mut Self {
+        self.neigh_max_entries = value;
+        self
+    }
+
+    /// Get the maximum number of entries of the neighbor cache.
+    pub fn neigh_max_entries(&self) -> usize {
+        self.neigh_max_entries
+    }
+
+    /// Set the number of ARP probes sent on startup to detect whether the bind address is
+    /// already in use (RFC 5227). Zero disables the detection.
+    pub fn set_arp_probes(&mut self, value: usize) -> &mut Self {
+        self.arp_probes = value;
+        self
+    }
+
+    /// Get the number of ARP probes sent on startup.
+    pub fn arp_probes(&self) -> usize {
+        self.arp_probes
+    }
+
+    /// Set the interval between two ARP probes.
+    pub fn set_arp_probe_interval(&mut self, value: Duration) -> &mut Self {
+        self.arp_probe_interval = value;
+        self
+    }
+
+    /// Get the interval between two ARP probes.
+    pub fn arp_probe_interval(&self) -> Duration {
+        self.arp_probe_interval
+    }
+
+    /// Set the number of gratuitous ARP packets sent on startup to announce the bind address.
+    pub fn set_arp_announcements(&mut self, value: usize) -> &mut Self {
+        self.arp_announcements = value;
+        self
+    }
+
+    /// Get the number of gratuitous ARP packets sent on startup.
+    pub fn arp_announcements(&self) -> usize {
+        self.arp_announcements
+    }
+
+    /// Set the interval between two gratuitous ARP packets.
+    pub fn set_arp_announce_interval(&mut self, value: Duration) -> &mut Self {
+        self.arp_announce_interval = value;
+        self
+    }
+
+    /// Get the interval between two gratuitous ARP packets.
+    pub fn arp_announce_interval(&self) -> Duration {
+        self.arp_announce_interval
+    }
+
+    /// Set the maximum number of ICMP error messages sent per second. Zero disables the rate
+    /// limit.
+    pub fn set_icmp_ratelimit(&mut self, value: u32) -> &mut Self {
+        self.icmp_ratelimit = value;
+        self
+    }
+
+    /// Get the maximum number of ICMP error messages sent per second.
+    pub fn icmp_ratelimit(&self) -> u32 {
+        self.icmp_ratelimit
+    }
+
+    /// Set the maximum number of ICMP error messages that can be sent in a burst.
+    pub fn set_icmp_ratelimit_burst(&mut self, value: u32) -> &mut Self {
+        self.icmp_ratelimit_burst = value;
+        self
+    }
+
+    /// Get the maximum number of ICMP error messages that can be sent in a burst.
+    pub fn icmp_ratelimit_burst(&self) -> u32 {
+        self.icmp_ratelimit_burst
+    }
+
+    /// Set whether the checksum of the UDP datagrams sent over IPv4 is computed. It is optional
+    /// for IPv4 and always computed for IPv6.
+    pub fn set_ip4_udp_checksum(&mut self, value: bool) -> &mut Self {
+        self.ip4_udp_checksum = value;
+        self
+    }
+
+    /// Get whether the checksum of the UDP datagrams sent over IPv4 is computed.
+    pub fn ip4_udp_checksum(&self) -> bool {
+        self.ip4_udp_checksum
+    }
+
+    /// Set how long the fragments of an incomplete IPv4 datagram are kept before being dropped.
+    pub fn set_ip4_frag_timeout(&mut self, value: Duration) -> &mut Self {
+        self.ip4_frag_timeout = value;
+        self
+    }
+
+    /// Get how long the fragments of an incomplete IPv4 datagram are kept.
+    pub fn ip4_frag_timeout(&self) -> Duration {
+        self.ip4_frag_timeout
+    }
+
+    /// Set the maximum memory, in bytes, used to hold the fragments of incomplete IPv4 datagrams.
+    /// The oldest incomplete datagrams are evicted to make room for new fragments, and fragments
+    /// that still do not fit are dropped together with their datagram.
+    pub fn set_ip4_frag_max_memory(&mut self, value: usize) -> &mut Self {
+        self.ip4_frag_max_memory = value;
+        self
+    }
+
+    /// Get the maximum memory, in bytes, used to hold the fragments of incomplete IPv4 datagrams.
+    pub fn ip4_frag_max_memory(&self) -> usize {
+        self.ip4_frag_max_memory
+    }
+
+    /// Set the MTU of the interface. It defaults to the MTU of the link.
+    ///
+    /// Packets are never sent with an MTU lower than the minimum MTU of their protocol, 68 bytes
+    /// for IPv4 and 1280 bytes for IPv6, whatever the MTU of the interface.
+    pub fn set_mtu(&mut self, value: usize) -> &mut Self {
+        self.mtu = Some(value);
+        self
+    }
+
+    /// Get the MTU of the interface, if set.
+    pub fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    /// Set whether IPv4 payloads are sent with the Don't Fragment flag by default. Payloads
+    /// larger than the MTU are fragmented if it is not set, and rejected otherwise. It can be
+    /// overridden for each socket.
+    pub fn set_ip4_dont_fragment(&mut self, value: bool) -> &mut Self {
+        self.ip4_dont_fragment = value;
+        self
+    }
+
+    /// Get whether IPv4 payloads are sent with the Don't Fragment flag by default.
+    pub fn ip4_dont_fragment(&self) -> bool {
+        self.ip4_dont_fragment
+    }
+
+    /// Set how long a path MTU learnt from an ICMP message is used before probing the MTU of the
+    /// link again.
+    pub fn set_pmtu_timeout(&mut self, value: Duration) -> &mut Self {
+        self.pmtu_timeout = value;
+        self
+    }
+
+    /// Get how long a path MTU learnt from an ICMP message is used.
+    pub fn pmtu_timeout(&self) -> Duration {
+        self.pmtu_timeout
+    }
+
+    /// Set the maximum number of destinations in the path MTU cache.
+    pub fn set_pmtu_max_entries(&mut self, value: usize) -> &mut Self {
+        self.pmtu_max_entries = value;
+        self
+    }
+
+    /// Get the maximum number of destinations in the path MTU cache.
+    pub fn pmtu_max_entries(&self) -> usize {
+        self.pmtu_max_entries
+    }
+
+    /// Add a static route. It takes precedence over the connected and imported routes to the
+    /// same network.
+    pub fn add_route(&mut self, route: Route) -> &mut Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Add a default route through `gateway`.
+    pub fn set_default_gateway(&mut self, gateway: IpAddr) -> &mut Self {
+        self.add_route(Route::default_gateway(gateway))
+    }
+
+    /// Get the static routes.
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Set whether the routes of the interface are imported from the main routing table of the
+    /// kernel on startup.
+    pub fn set_import_routes(&mut self, value: bool) -> &mut Self {
+        self.import_routes = value;
+        self
+    }
+
+    /// Get whether the routes of the interface are imported from the kernel on startup.
+    pub fn import_routes(&self) -> bool {
+        self.import_routes
+    }
+
+    /// Set the Differentiated Services Code Point of the payloads sent by default.
+    pub fn set_dscp(&mut self, value: u8) -> &mut Self {
+        self.dscp = value & 0x3f;
+        self
+    }
+
+    /// Get the Differentiated Services Code Point of the payloads sent by default.
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Set the Explicit Congestion Notification codepoint of the payloads sent by default.
+    pub fn set_ecn(&mut self, value: u8) -> &mut Self {
+        self.ecn = value & 0x3;
+        self
+    }
+
+    /// Get the Explicit Congestion Notification codepoint of the payloads sent by default.
+    pub fn ecn(&self) -> u8 {
+        self.ecn
+    }
+
+    /// Set the TTL (or hop limit for IPv6) of the payloads sent by default.
+    pub fn set_ttl(&mut self, value: u8) -> &mut Self {
+        self.ttl = value;
+        self
+    }
+
+    /// Get the TTL (or hop limit for IPv6) of the payloads sent by default.
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    /// Set how the ID of the IPv4 payloads sent is chosen by default.
+    pub fn set_ip4_id_policy(&mut self, value: IpIdPolicy) -> &mut Self {
+        self.ip4_id_policy = value;
+        self
+    }
+
+    /// Get how the ID of the IPv4 payloads sent is chosen by default.
+    pub fn ip4_id_policy(&self) -> IpIdPolicy {
+        self.ip4_id_policy
+    }
+
+    /// Get the IP header fields of the payloads sent by default, which can be overridden for
+    /// each payload.
+    pub fn tx_options(&self) -> TxOptions {
+        TxOptions {
+            dscp:  self.dscp,
+            ecn:   self.ecn,
+            ttl:   self.ttl,
+            ip_id: self.ip4_id_policy,
+        }
+    }
 }
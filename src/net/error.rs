@@ -15,10 +15,15 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::result;
+use std::{
+    net::{IpAddr, SocketAddr},
+    result,
+};
 
 use thiserror::Error;
 
+use crate::xsk::Vlan;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Could not get bytes, buffer too short")]
@@ -27,6 +32,51 @@ pub enum Error {
     #[error("Invalid seek position, buffer too short")]
     InvalidSeekPos,
 
+    #[error("Malformed Neighbor Discovery option")]
+    InvalidNdOption,
+
+    #[error("No IPv6 bind address configured")]
+    NoBindAddress6,
+
+    #[error("Invalid IPv4 fragment")]
+    InvalidFragment,
+
+    #[error("IPv4 fragment overlaps a previous fragment")]
+    FragmentOverlap,
+
+    #[error("Reassembled IPv4 datagram too large")]
+    FragmentTooLarge,
+
+    #[error("Not enough memory to reassemble IPv4 datagram")]
+    FragmentNoMemory,
+
+    #[error("Local and remote addresses of different families")]
+    AddressFamilyMismatch,
+
+    #[error("Payload too large to be sent without fragmentation")]
+    MessageTooLong,
+
+    #[error("Too many VLAN tags")]
+    TooManyVlanTags,
+
+    #[error("No address bound on VLAN {0}")]
+    NoVlanAddress(Vlan),
+
+    #[error("Too many fragments to queue while resolving the neighbor")]
+    NeighborBacklogFull,
+
+    #[error("No route to host {0}")]
+    NoRoute(IpAddr),
+
+    #[error("Invalid destination {0}")]
+    InvalidDestination(SocketAddr),
+
+    #[error("No app to receive the datagrams sent to port {0}")]
+    NoApp(u16),
+
+    #[error("No received frame to send the payload in place of")]
+    NoRxFrame,
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
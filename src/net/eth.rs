@@ -60,6 +60,16 @@ impl EthHdr {
         self.eth_address = net::utils::htons(net::eth::EthType::IP4 as u16);
         self
     }
+
+    pub fn ip6(&mut self) -> &mut Self {
+        self.eth_address = net::utils::htons(net::eth::EthType::IP6 as u16);
+        self
+    }
+
+    pub fn set_eth_type(&mut self, v: u16) -> &mut Self {
+        self.eth_address = net::utils::htons(v);
+        self
+    }
 }
 
 impl fmt::Debug for EthHdr {
@@ -78,6 +88,9 @@ impl fmt::Debug for EthHdr {
 pub enum EthType {
     IP4 = 0x0800,
     ARP = 0x0806,
+    IP6 = 0x86dd,
+    VLAN = 0x8100,
+    QINQ = 0x88a8,
 }
 
 impl TryFrom<u16> for EthType {
@@ -88,7 +101,10 @@ impl TryFrom<u16> for EthType {
 
         match x {
             x if x == IP4 as u16 => Ok(IP4),
+            x if x == IP6 as u16 => Ok(IP6),
             x if x == ARP as u16 => Ok(ARP),
+            x if x == VLAN as u16 => Ok(VLAN),
+            x if x == QINQ as u16 => Ok(QINQ),
             _ => Err(()),
         }
     }
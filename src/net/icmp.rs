@@ -0,0 +1,175 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{convert::TryFrom, fmt, mem, result, slice};
+
+use crate::{
+    net,
+    net::{Ip4Hdr, PacketBufMut, Result},
+};
+
+/// Maximum length of the original datagram quoted in an ICMP error message, so that the whole
+/// message does not exceed the 576 bytes every host must accept (RFC 1812, section 4.3.2.3).
+pub const ICMP_ERROR_MAX_QUOTE_LEN: usize =
+    576 - mem::size_of::<Ip4Hdr>() - mem::size_of::<IcmpHdr>();
+
+#[repr(C)]
+pub struct IcmpHdr {
+    pub icmp_type: u8,
+    pub code:      u8,
+    pub checksum:  u16,
+    pub rest:      u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpType {
+    EchoReply = 0,
+    DestUnreachable = 3,
+    EchoRequest = 8,
+}
+
+/// Codes of the ICMP destination unreachable message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpUnreachCode {
+    Protocol = 2,
+    Port = 3,
+    FragmentationNeeded = 4,
+}
+
+impl TryFrom<u8> for IcmpType {
+    type Error = ();
+
+    fn try_from(x: u8) -> result::Result<Self, Self::Error> {
+        match x {
+            x if x == IcmpType::EchoReply as u8 => Ok(IcmpType::EchoReply),
+            x if x == IcmpType::DestUnreachable as u8 => Ok(IcmpType::DestUnreachable),
+            x if x == IcmpType::EchoRequest as u8 => Ok(IcmpType::EchoRequest),
+            _ => Err(()),
+        }
+    }
+}
+
+impl IcmpHdr {
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn from_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        packet
+            .get_bytes_mut(mem::size_of::<Self>())
+            .map(|l4_slice| unsafe { &mut *(l4_slice.as_mut_ptr() as *mut IcmpHdr) })
+    }
+
+    pub fn with_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        let hdr = Self::from_packet_buf(packet)?;
+        hdr.code = 0;
+        hdr.checksum = 0;
+        hdr.rest = 0;
+
+        Ok(hdr)
+    }
+
+    pub fn icmp_type(&self) -> Option<IcmpType> {
+        IcmpType::try_from(self.icmp_type).ok()
+    }
+
+    pub fn echo_reply(&mut self) -> &mut Self {
+        self.icmp_type = IcmpType::EchoReply as u8;
+        self.code = 0;
+        self
+    }
+
+    pub fn dest_unreachable(&mut self, code: IcmpUnreachCode) -> &mut Self {
+        self.icmp_type = IcmpType::DestUnreachable as u8;
+        self.code = code as u8;
+        self
+    }
+
+    /// Returns true if this is an ICMP error message, as opposed to a query.
+    pub fn is_error(&self) -> bool {
+        self.icmp_type() == Some(IcmpType::DestUnreachable)
+    }
+
+    /// Returns the MTU of the next hop reported by a fragmentation needed message (RFC 1191), or
+    /// 0 if the router does not report it.
+    pub fn next_hop_mtu(&self) -> u16 {
+        net::utils::ntohl(self.rest) as u16
+    }
+
+    /// Set the rest of the header, i.e. the identifier and sequence number of an echo message.
+    /// The value is copied as is, in network byte order.
+    pub fn set_rest(&mut self, v: u32) -> &mut Self {
+        self.rest = v;
+        self
+    }
+
+    fn sum(&self, payload: &[u8]) -> u32 {
+        let hdr = unsafe {
+            slice::from_raw_parts(self as *const IcmpHdr as *const u8, mem::size_of::<Self>())
+        };
+
+        net::utils::csum_add(net::utils::csum_add(0, hdr), payload)
+    }
+
+    /// Compute the checksum of the ICMP message made of this header followed by `payload`.
+    pub fn calc_checksum(&mut self, payload: &[u8]) {
+        self.checksum = 0;
+        self.checksum = net::utils::htons(net::utils::csum_fold(self.sum(payload)));
+    }
+
+    /// Returns true if the checksum of the ICMP message made of this header followed by
+    /// `payload` is valid.
+    pub fn is_checksum_valid(&self, payload: &[u8]) -> bool {
+        net::utils::csum_fold(self.sum(payload)) == 0
+    }
+}
+
+impl fmt::Debug for IcmpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "    IcmpHdr {{ type: {}, code: {}, checksum: 0x{:04x}, rest: 0x{:08x} }}",
+            self.icmp_type,
+            self.code,
+            net::utils::ntohs(self.checksum),
+            net::utils::ntohl(self.rest),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icmp_checksum() {
+        // Echo request with id 0x1234, sequence number 1 and a 4 bytes payload
+        let mut buf = [
+            0x08, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x01, 0x42, 0x42, 0x42, 0x42,
+        ];
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+
+        let hdr = IcmpHdr::from_packet_buf(&mut packet_buf).unwrap();
+        let payload = [0x42; 4];
+
+        hdr.calc_checksum(&payload);
+        assert_eq!(net::utils::ntohs(hdr.checksum), 0x6146);
+        assert!(hdr.is_checksum_valid(&payload));
+
+        hdr.echo_reply().calc_checksum(&payload);
+        assert_eq!(net::utils::ntohs(hdr.checksum), 0x6946);
+        assert!(hdr.is_checksum_valid(&payload));
+        assert!(!hdr.is_checksum_valid(&[0x42; 3]));
+    }
+}
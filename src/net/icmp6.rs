@@ -0,0 +1,312 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt, mem,
+    net::Ipv6Addr,
+    result, slice,
+};
+
+use crate::{
+    net,
+    net::{Error, Ip6Hdr, PacketBufMut, Result},
+};
+
+/// Maximum length of the original packet quoted in an ICMPv6 error message, so that the whole
+/// message does not exceed the minimum IPv6 MTU (RFC 4443, section 2.4 (c)).
+pub const ICMP6_ERROR_MAX_QUOTE_LEN: usize =
+    1280 - mem::size_of::<Ip6Hdr>() - mem::size_of::<Icmp6Hdr>();
+
+#[repr(C)]
+pub struct Icmp6Hdr {
+    pub icmp_type: u8,
+    pub code:      u8,
+    pub checksum:  u16,
+    pub rest:      u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Icmp6Type {
+    DestUnreachable = 1,
+    PacketTooBig = 2,
+    EchoRequest = 128,
+    EchoReply = 129,
+    NeighborSolicitation = 135,
+    NeighborAdvertisement = 136,
+}
+
+impl TryFrom<u8> for Icmp6Type {
+    type Error = ();
+
+    fn try_from(x: u8) -> result::Result<Self, Self::Error> {
+        use Icmp6Type::*;
+
+        match x {
+            x if x == DestUnreachable as u8 => Ok(DestUnreachable),
+            x if x == PacketTooBig as u8 => Ok(PacketTooBig),
+            x if x == EchoRequest as u8 => Ok(EchoRequest),
+            x if x == EchoReply as u8 => Ok(EchoReply),
+            x if x == NeighborSolicitation as u8 => Ok(NeighborSolicitation),
+            x if x == NeighborAdvertisement as u8 => Ok(NeighborAdvertisement),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Codes of the ICMPv6 destination unreachable message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Icmp6UnreachCode {
+    Port = 4,
+}
+
+/// Flags of the Neighbor Advertisement message, in host byte order.
+#[repr(u32)]
+pub enum NaFlags {
+    Router = 0x8000_0000,
+    Solicited = 0x4000_0000,
+    Override = 0x2000_0000,
+}
+
+/// Types of the Neighbor Discovery options carrying a link-layer address.
+#[derive(Clone, Copy)]
+pub enum NdOption {
+    SourceHwAddress = 1,
+    TargetHwAddress = 2,
+}
+
+/// Length of a Neighbor Discovery link-layer address option for Ethernet.
+pub const ND_HW_ADDRESS_OPTION_LEN: usize = 8;
+
+impl Icmp6Hdr {
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn from_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        packet
+            .get_bytes_mut(mem::size_of::<Self>())
+            .map(|l4_slice| unsafe { &mut *(l4_slice.as_mut_ptr() as *mut Icmp6Hdr) })
+    }
+
+    pub fn with_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        let hdr = Self::from_packet_buf(packet)?;
+        hdr.code = 0;
+        hdr.checksum = 0;
+        hdr.rest = 0;
+
+        Ok(hdr)
+    }
+
+    pub fn icmp_type(&self) -> Option<Icmp6Type> {
+        Icmp6Type::try_from(self.icmp_type).ok()
+    }
+
+    pub fn echo_reply(&mut self) -> &mut Self {
+        self.icmp_type = Icmp6Type::EchoReply as u8;
+        self
+    }
+
+    pub fn dest_unreachable(&mut self, code: Icmp6UnreachCode) -> &mut Self {
+        self.icmp_type = Icmp6Type::DestUnreachable as u8;
+        self.code = code as u8;
+        self
+    }
+
+    pub fn neighbor_solicitation(&mut self) -> &mut Self {
+        self.icmp_type = Icmp6Type::NeighborSolicitation as u8;
+        self
+    }
+
+    pub fn neighbor_advertisement(&mut self, flags: u32) -> &mut Self {
+        self.icmp_type = Icmp6Type::NeighborAdvertisement as u8;
+        self.rest = net::utils::htonl(flags);
+        self
+    }
+
+    /// Returns true if `flag` is set in a Neighbor Advertisement message.
+    pub fn has_na_flag(&self, flag: NaFlags) -> bool {
+        net::utils::ntohl(self.rest) & flag as u32 != 0
+    }
+
+    /// Returns the MTU reported by a Packet Too Big message.
+    pub fn mtu(&self) -> u32 {
+        net::utils::ntohl(self.rest)
+    }
+
+    /// Set the rest of the header, e.g. the identifier and sequence number of an echo message.
+    /// The value is copied as is, in network byte order.
+    pub fn set_rest(&mut self, v: u32) -> &mut Self {
+        self.rest = v;
+        self
+    }
+
+    fn sum(&self, pseudo_header_sum: u32, payload: &[u8]) -> u32 {
+        let hdr = unsafe {
+            slice::from_raw_parts(self as *const Icmp6Hdr as *const u8, mem::size_of::<Self>())
+        };
+
+        net::utils::csum_add(net::utils::csum_add(pseudo_header_sum, hdr), payload)
+    }
+
+    /// Compute the checksum of the ICMPv6 message made of this header followed by `payload`.
+    pub fn calc_checksum(&mut self, pseudo_header_sum: u32, payload: &[u8]) {
+        self.checksum = 0;
+        self.checksum =
+            net::utils::htons(net::utils::csum_fold(self.sum(pseudo_header_sum, payload)));
+    }
+
+    /// Returns true if the checksum of the ICMPv6 message made of this header followed by
+    /// `payload` is valid.
+    pub fn is_checksum_valid(&self, pseudo_header_sum: u32, payload: &[u8]) -> bool {
+        net::utils::csum_fold(self.sum(pseudo_header_sum, payload)) == 0
+    }
+}
+
+/// Returns true if an ICMPv6 error message may be sent about a packet from `source_address`,
+/// which must identify a single node (RFC 4443, section 2.4 (e)).
+pub fn is_error_destination(source_address: Ipv6Addr) -> bool {
+    !(source_address.is_unspecified()
+        || source_address.is_multicast()
+        || source_address.is_loopback())
+}
+
+/// Returns the target address of a Neighbor Solicitation or Advertisement message, given the
+/// `payload` following the ICMPv6 header.
+pub fn nd_target_address(payload: &[u8]) -> Option<Ipv6Addr> {
+    let target: [u8; 16] = payload.get(..16)?.try_into().ok()?;
+
+    Some(Ipv6Addr::from(target))
+}
+
+/// Returns the link-layer address carried by the `option` option, if any, among the Neighbor
+/// Discovery `options`.
+///
+/// Fails if the options are malformed, in which case the whole message must be discarded
+/// (RFC 4861, section 7.1).
+pub fn nd_hw_address_option(options: &[u8], option: NdOption) -> Result<Option<[u8; 6]>> {
+    let mut hw_address = None;
+    let mut options = options;
+
+    while !options.is_empty() {
+        let len = *options.get(1).ok_or(Error::InvalidNdOption)? as usize * 8;
+        if len == 0 || len > options.len() {
+            return Err(Error::InvalidNdOption);
+        }
+
+        if options[0] == option as u8 && len == ND_HW_ADDRESS_OPTION_LEN {
+            hw_address = Some(options[2..8].try_into().unwrap());
+        }
+
+        options = &options[len..];
+    }
+
+    Ok(hw_address)
+}
+
+/// Write a Neighbor Discovery `option` option carrying `hw_address` to `packet`.
+pub fn write_nd_hw_address_option(
+    packet: &mut PacketBufMut,
+    option: NdOption,
+    hw_address: [u8; 6],
+) -> Result<()> {
+    let buf = packet.get_bytes_mut(ND_HW_ADDRESS_OPTION_LEN)?;
+
+    buf[0] = option as u8;
+    buf[1] = (ND_HW_ADDRESS_OPTION_LEN / 8) as u8;
+    buf[2..].copy_from_slice(&hw_address);
+
+    Ok(())
+}
+
+impl fmt::Debug for Icmp6Hdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "    Icmp6Hdr {{ type: {}, code: {}, checksum: 0x{:04x}, rest: 0x{:08x} }}",
+            self.icmp_type,
+            self.code,
+            net::utils::ntohs(self.checksum),
+            net::utils::ntohl(self.rest),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nd_hw_address_option() {
+        let mut buf = [0_u8; 16];
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+
+        // An unknown option followed by a target link-layer address option
+        packet_buf
+            .get_bytes_mut(8)
+            .unwrap()
+            .copy_from_slice(&[14, 1, 0, 0, 0, 0, 0, 0]);
+        write_nd_hw_address_option(
+            &mut packet_buf,
+            NdOption::TargetHwAddress,
+            [1, 2, 3, 4, 5, 6],
+        )
+        .unwrap();
+
+        assert_eq!(
+            nd_hw_address_option(&buf, NdOption::TargetHwAddress).unwrap(),
+            Some([1, 2, 3, 4, 5, 6])
+        );
+        assert_eq!(
+            nd_hw_address_option(&buf, NdOption::SourceHwAddress).unwrap(),
+            None
+        );
+
+        // Options with a zero or an overflowing length are malformed
+        assert!(
+            nd_hw_address_option(&[1, 0, 0, 0, 0, 0, 0, 0], NdOption::SourceHwAddress).is_err()
+        );
+        assert!(
+            nd_hw_address_option(&[1, 2, 0, 0, 0, 0, 0, 0], NdOption::SourceHwAddress).is_err()
+        );
+        assert!(nd_hw_address_option(&[1], NdOption::SourceHwAddress).is_err());
+    }
+
+    #[test]
+    fn test_dest_unreachable() {
+        let mut buf = [0xff_u8; 8];
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+        let quote = [0x60, 0, 0, 0];
+
+        let hdr = Icmp6Hdr::with_packet_buf(&mut packet_buf).unwrap();
+        hdr.dest_unreachable(Icmp6UnreachCode::Port)
+            .calc_checksum(0, &quote);
+
+        assert_eq!(hdr.icmp_type(), Some(Icmp6Type::DestUnreachable));
+        assert_eq!((hdr.icmp_type, hdr.code, hdr.rest), (1, 4, 0));
+        assert!(hdr.is_checksum_valid(0, &quote));
+
+        assert_eq!(ICMP6_ERROR_MAX_QUOTE_LEN, 1232);
+    }
+
+    #[test]
+    fn test_is_error_destination() {
+        assert!(is_error_destination("fc00::1".parse().unwrap()));
+        assert!(is_error_destination("fe80::1".parse().unwrap()));
+
+        assert!(!is_error_destination(Ipv6Addr::UNSPECIFIED));
+        assert!(!is_error_destination(Ipv6Addr::LOCALHOST));
+        assert!(!is_error_destination("ff02::1".parse().unwrap()));
+    }
+}
@@ -15,49 +15,260 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::convert::TryInto;
+use std::{
+    convert::{TryFrom, TryInto},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Instant,
+};
 
 use crate::{
     net,
-    net::{ArpHdr, EthHdr, EthType, Ip4Hdr, IpProto, Packet, Result, UdpHdr},
+    net::{
+        app::{RxMetadata, Socket, UnmatchedPort},
+        icmp6, ip6, ArpHdr, ArpOpcode, Error, EthHdr, EthType, FragmentKey, Icmp6Hdr, Icmp6Type,
+        Icmp6UnreachCode, IcmpHdr, IcmpType, IcmpUnreachCode, Ip4Hdr, Ip6Hdr, IpFlags, IpProto,
+        NaFlags, NdOption, Packet, Result, RxFrame, UdpHdr, VlanHdr, VlanTag, VlanTags,
+        IP6_ALL_NODES, ND_HOP_LIMIT, VLAN_MAX_TAGS,
+    },
     xsk,
 };
 
 impl net::Net {
     pub fn do_rx_packet(&mut self, desc: xsk::Desc) -> Result<()> {
         let mut packet = Packet::new(desc.packet(), desc.len());
+        packet.timestamp = Some(Instant::now());
+        packet.desc = Some(desc);
 
         let eth_hdr = EthHdr::from_packet_buf(&mut packet.packet_buf)?;
-        let eth_addr = eth_hdr.eth_address;
+        let mut eth_type = net::utils::ntohs(eth_hdr.eth_address);
 
         packet.eth_hdr = Some(eth_hdr);
 
-        match net::utils::ntohs(eth_addr).try_into() {
+        // 802.1Q tags, preceded by an 802.1ad service tag in QinQ frames
+        while eth_type == EthType::VLAN as u16 || eth_type == EthType::QINQ as u16 {
+            let vlan = match VlanHdr::from_packet_buf(&mut packet.packet_buf) {
+                Ok(vlan) if packet.vlan.tags().len() < VLAN_MAX_TAGS => vlan,
+                _ => {
+                    self.netstack.write().unwrap().stats.vlan_invalid += 1;
+                    return Ok(());
+                }
+            };
+
+            packet.vlan.push(VlanTag {
+                tpid: eth_type,
+                tci:  net::utils::ntohs(vlan.tci),
+            })?;
+            eth_type = net::utils::ntohs(vlan.eth_type);
+        }
+
+        match eth_type.try_into() {
             Ok(EthType::IP4) => self.rx_ip4_packet(&mut packet)?,
             Ok(EthType::ARP) => self.rx_arp_packet(&mut packet)?,
-            Err(_) => return Ok(()),
+            Ok(EthType::IP6) => self.rx_ip6_packet(&mut packet)?,
+            _ => return Ok(()),
         }
 
         Ok(())
     }
 
+    /// Returns the offset of the network header of `packet`, following the Ethernet header and
+    /// the VLAN tags.
+    fn l3_offset(packet: &Packet) -> usize {
+        mem::size_of::<EthHdr>() + packet.vlan.hdr_len()
+    }
+
+    /// Validate the IPv4 header as described in RFC 1812 (section 5.2.2), skip its options and
+    /// limit the packet to its total length, excluding any link-layer padding.
     fn rx_ip4_packet<'a>(&mut self, packet: &'a mut Packet<'a>) -> Result<()> {
-        let ip4 = Ip4Hdr::from_packet_buf(&mut packet.packet_buf)?;
-        if ip4.proto != IpProto::UDP as u8 {
+        let ip4_offset = packet.packet_buf.packet_offset;
+        let available = packet.packet_buf.remaining();
+
+        let ip4 = match Ip4Hdr::from_packet_buf(&mut packet.packet_buf) {
+            Ok(ip4) => ip4,
+            Err(_) => {
+                self.netstack.write().unwrap().stats.ip4_bad_hdr_len += 1;
+                return Ok(());
+            }
+        };
+
+        let hdr_len = ip4.hdr_len() as usize * 4;
+        let total_len = net::utils::ntohs(ip4.total_len) as usize;
+
+        if ip4.version() != 4 {
+            self.netstack.write().unwrap().stats.ip4_bad_version += 1;
+            return Ok(());
+        }
+
+        if hdr_len < mem::size_of::<Ip4Hdr>() || hdr_len > available {
+            self.netstack.write().unwrap().stats.ip4_bad_hdr_len += 1;
+            return Ok(());
+        }
+
+        if total_len < hdr_len || total_len > available {
+            self.netstack.write().unwrap().stats.ip4_bad_total_len += 1;
+            return Ok(());
+        }
+
+        packet.packet_buf.seek(ip4_offset)?;
+        let hdr = packet.packet_buf.peek_bytes(hdr_len)?;
+        if net::utils::csum_fold(net::utils::csum_add(0, hdr)) != 0 {
+            self.netstack.write().unwrap().stats.ip4_bad_checksum += 1;
             return Ok(());
         }
+
+        packet.packet_buf.seek(ip4_offset + hdr_len)?;
+        packet.packet_buf.truncate(ip4_offset + total_len);
+
+        let proto = ip4.proto;
         packet.ip4_hdr = Some(ip4);
 
-        self.update_arp_cache_from_ip(packet);
+        self.update_arp_cache_from_ip(packet)?;
+
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        if ip4.flags() & IpFlags::MoreFragment as u8 != 0 || ip4.frag_offset() != 0 {
+            return self.rx_ip4_fragment(packet, ip4_offset);
+        }
+
+        if proto == IpProto::ICMP as u8 {
+            self.rx_icmp_packet(packet)
+        } else if proto == IpProto::UDP as u8 {
+            self.rx_udp_packet(packet)
+        } else {
+            // Only received when the XDP program redirects every protocol to the stack
+            self.rx_unreachable(packet, IcmpUnreachCode::Protocol)
+        }
+    }
+
+    /// Queue a fragment for reassembly, and process the whole datagram once complete.
+    fn rx_ip4_fragment(&mut self, packet: &mut Packet, ip4_offset: usize) -> Result<()> {
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        let dest_address = Ipv4Addr::from(net::utils::ntohl(ip4.dst_addr));
+        let key = FragmentKey {
+            src_address: Ipv4Addr::from(net::utils::ntohl(ip4.src_addr)),
+            dst_address: dest_address,
+            proto:       ip4.proto,
+            id:          net::utils::ntohs(ip4.id),
+        };
+        let offset = ip4.frag_offset() as usize * 8;
+        let more_fragments = ip4.flags() & IpFlags::MoreFragment as u8 != 0;
+
+        let mut netstack = self.netstack.write().unwrap();
+        if !netstack.is_local_address(packet.vlan.vlan(), dest_address) {
+            return Ok(());
+        }
+
+        let payload_offset = packet.packet_buf.packet_offset;
+        let payload_len = packet.packet_buf.remaining();
+
+        packet.packet_buf.seek(0)?;
+        let frame = packet.packet_buf.peek_bytes(payload_offset + payload_len)?;
+        let hdr = &frame[ip4_offset..payload_offset];
+        let data = &frame[payload_offset..];
+
+        let r =
+            netstack
+                .ip4_reassembler
+                .insert(key, hdr, offset, more_fragments, data, Instant::now());
+
+        let evicted = netstack.ip4_reassembler.take_evicted();
+        netstack.stats.ip4_frag_no_memory += evicted as u64;
+
+        let datagram = match r {
+            Ok(Some(datagram)) => datagram,
+            Ok(None) => return Ok(()),
+            Err(Error::FragmentOverlap) => {
+                netstack.stats.ip4_frag_overlaps += 1;
+                return Ok(());
+            }
+            Err(Error::FragmentNoMemory) => {
+                netstack.stats.ip4_frag_no_memory += 1;
+                return Ok(());
+            }
+            Err(_) => {
+                netstack.stats.ip4_frag_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        netstack.stats.ip4_frag_reassembled += 1;
+        drop(netstack);
+
+        // Process the reassembled datagram as if it was received in a single frame, with the
+        // link-layer header of the last fragment
+        let mut buf = frame[..ip4_offset].to_vec();
+        buf.extend_from_slice(&datagram);
+
+        let mut reassembled = Packet::new(buf.as_mut_ptr(), buf.len());
+        reassembled.eth_hdr = Some(EthHdr::from_packet_buf(&mut reassembled.packet_buf)?);
+        reassembled.vlan = packet.vlan;
+        reassembled.timestamp = packet.timestamp;
+        reassembled.packet_buf.seek(ip4_offset)?;
+
+        self.rx_ip4_packet(&mut reassembled)
+    }
+
+    fn rx_udp_packet<'a>(&mut self, packet: &'a mut Packet<'a>) -> Result<()> {
+        let udp = match UdpHdr::from_packet_buf(&mut packet.packet_buf) {
+            Ok(udp) => udp,
+            Err(_) => {
+                self.netstack.write().unwrap().stats.udp_invalid += 1;
+                return Ok(());
+            }
+        };
 
-        let udp = UdpHdr::from_packet_buf(&mut packet.packet_buf)?;
         let len = net::utils::ntohs(udp.len);
+        let dest_port = net::utils::ntohs(udp.dst_port);
+
+        let payload_len = match (len as usize).checked_sub(mem::size_of::<UdpHdr>()) {
+            Some(payload_len) if payload_len <= packet.packet_buf.remaining() => payload_len,
+            _ => {
+                self.netstack.write().unwrap().stats.udp_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        // A zero checksum means the sender did not compute it
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        if udp.sum != 0
+            && !udp.is_checksum_valid(
+                ip4.pseudo_header_sum(IpProto::UDP, len),
+                packet.packet_buf.peek_bytes(payload_len)?,
+            )
+        {
+            self.netstack.write().unwrap().stats.udp_bad_checksum += 1;
+            return Ok(());
+        }
 
         packet.udp_hdr = Some(udp);
 
-        let l4_payload = packet
-            .packet_buf
-            .get_bytes_mut(len as usize - std::mem::size_of::<UdpHdr>())?;
+        let dest_address = Ipv4Addr::from(net::utils::ntohl(ip4.dst_addr));
+        let (listening, unmatched_port) = {
+            let netstack = self.netstack.read().unwrap();
+            (
+                netstack.is_listening(IpAddr::V4(dest_address), dest_port),
+                netstack.configuration.unmatched_port(),
+            )
+        };
+
+        // Unfragmented datagrams are only received for the listeners: the ones with no app are
+        // mostly reassembled from fragments, whose port the XDP program cannot match
+        let app = match self
+            .apps
+            .get_mut(IpAddr::V4(dest_address), dest_port, listening)
+        {
+            Some(app) => app,
+            None if unmatched_port == UnmatchedPort::Unreachable => {
+                return self.rx_unreachable(packet, IcmpUnreachCode::Port);
+            }
+            None => {
+                self.netstack.write().unwrap().stats.no_listener += 1;
+                return Ok(());
+            }
+        };
+
+        let payload_offset = packet.packet_buf.packet_offset;
+        let l4_payload = packet.packet_buf.get_bytes_mut(payload_len)?;
 
         packet.l4_payload = Some(l4_payload);
 
@@ -66,50 +277,718 @@ impl net::Net {
 
         let source_port = net::utils::ntohs(packet.udp_hdr.as_ref().unwrap().src_port);
 
-        let socket = net::app::Socket {
+        let dont_fragment = self
+            .netstack
+            .read()
+            .unwrap()
+            .configuration
+            .ip4_dont_fragment();
+
+        let socket = Socket {
+            source_address: IpAddr::V4(source_address),
+            source_port,
+            local_address: IpAddr::V4(dest_address),
+            local_port: dest_port,
+            dont_fragment,
+            vlan: packet.vlan,
+        };
+
+        let mut netstack = self.netstack.write().unwrap();
+
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        let metadata = Self::rx_metadata(
+            &netstack,
+            &socket,
+            packet.eth_hdr.as_ref().unwrap().src_address,
+            ip4.ttl,
+            ip4.tos,
+            packet.timestamp,
+        );
+
+        // The app may reply in place of the datagram as long as it is delivered
+        netstack.rx_frame = packet.desc.as_ref().map(|desc| RxFrame {
+            addr: desc.addr(),
+            payload_offset,
+            payload_len,
+        });
+
+        let ret = app.rx_payload(
+            &mut *netstack,
+            &socket,
+            &metadata,
+            packet.l4_payload.as_mut().unwrap(),
+        );
+
+        netstack.rx_frame = None;
+        ret?;
+
+        Ok(())
+    }
+
+    fn rx_icmp_packet<'a>(&mut self, packet: &'a mut Packet<'a>) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        let source_address = Ipv4Addr::from(net::utils::ntohl(ip4.src_addr));
+        let dest_address = Ipv4Addr::from(net::utils::ntohl(ip4.dst_addr));
+
+        if !netstack.is_local_address(packet.vlan.vlan(), dest_address) {
+            return Ok(());
+        }
+
+        // The packet is limited to the IP total length, the data is everything after the header
+        let (icmp, data) = match IcmpHdr::from_packet_buf(&mut packet.packet_buf).and_then(|icmp| {
+            let data_len = packet.packet_buf.remaining();
+            Ok((icmp, packet.packet_buf.get_bytes(data_len)?))
+        }) {
+            Ok((icmp, data)) if icmp.is_checksum_valid(data) => (icmp, data),
+            _ => {
+                netstack.stats.icmp_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        match icmp.icmp_type() {
+            Some(IcmpType::EchoRequest) if icmp.code == 0 => {
+                netstack.send_icmp_echo_reply(
+                    &packet.vlan,
+                    dest_address,
+                    source_address,
+                    icmp.rest,
+                    data,
+                )?;
+            }
+            // The message quotes the header of the datagram we sent
+            Some(IcmpType::DestUnreachable)
+                if icmp.code == IcmpUnreachCode::FragmentationNeeded as u8
+                    && data.len() >= mem::size_of::<Ip4Hdr>()
+                    && data[0] >> 4 == 4 =>
+            {
+                let src_address =
+                    Ipv4Addr::from(u32::from_be_bytes(data[12..16].try_into().unwrap()));
+                let dst_address =
+                    Ipv4Addr::from(u32::from_be_bytes(data[16..20].try_into().unwrap()));
+
+                let mtu = match icmp.next_hop_mtu() {
+                    0 => net::mtu_plateau(u16::from_be_bytes([data[2], data[3]]) as usize),
+                    mtu => mtu as usize,
+                };
+
+                if netstack.is_local_address(packet.vlan.vlan(), src_address) {
+                    netstack.update_path_mtu(IpAddr::V4(dst_address), mtu);
+                }
+            }
+            _ => {}
+        }
+
+        packet.icmp_hdr = Some(icmp);
+
+        Ok(())
+    }
+
+    /// Reply to a packet for an unserved port or protocol with an ICMP destination unreachable
+    /// message.
+    ///
+    /// As required by RFC 1812 (section 4.3.2.7), no message is sent for packets which are not
+    /// addressed to us, whose source is not a unicast address or which are not the first
+    /// fragment of a datagram.
+    fn rx_unreachable(&mut self, packet: &mut Packet, code: IcmpUnreachCode) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+        netstack.stats.no_listener += 1;
+
+        let ip4 = packet.ip4_hdr.as_ref().unwrap();
+        let source_address = Ipv4Addr::from(net::utils::ntohl(ip4.src_addr));
+        let dest_address = Ipv4Addr::from(net::utils::ntohl(ip4.dst_addr));
+        let total_len = net::utils::ntohs(ip4.total_len) as usize;
+
+        if !netstack.is_local_address(packet.vlan.vlan(), dest_address)
+            || source_address.is_unspecified()
+            || source_address.is_broadcast()
+            || source_address.is_multicast()
+            || source_address.is_loopback()
+            || ip4.frag_offset() != 0
+        {
+            return Ok(());
+        }
+
+        if !netstack.icmp_ratelimit.take(Instant::now()) {
+            netstack.stats.icmp_ratelimited += 1;
+            return Ok(());
+        }
+
+        packet.packet_buf.seek(Self::l3_offset(packet))?;
+        let datagram = packet
+            .packet_buf
+            .peek_bytes(total_len.min(packet.packet_buf.remaining()))?;
+
+        netstack.send_icmp_dest_unreachable(
+            &packet.vlan,
+            dest_address,
             source_address,
+            code,
+            datagram,
+        )?;
+
+        Ok(())
+    }
+
+    /// Reply to a datagram for an unserved port with an ICMPv6 destination unreachable message,
+    /// subject to the same rate limit as the ICMP ones.
+    ///
+    /// As required by RFC 4443 (section 2.4), no message is sent for packets whose source does
+    /// not identify a single node. Only the packets sent to a local unicast address get here.
+    fn rx_unreachable6(&mut self, packet: &mut Packet, code: Icmp6UnreachCode) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+        netstack.stats.no_listener += 1;
+
+        let ip6 = packet.ip6_hdr.as_ref().unwrap();
+        let source_address = ip6.src_address();
+        let dest_address = ip6.dst_address();
+        let total_len = mem::size_of::<Ip6Hdr>() + net::utils::ntohs(ip6.payload_len) as usize;
+
+        if !icmp6::is_error_destination(source_address) {
+            return Ok(());
+        }
+
+        if !netstack.icmp_ratelimit.take(Instant::now()) {
+            netstack.stats.icmp_ratelimited += 1;
+            return Ok(());
+        }
+
+        packet.packet_buf.seek(Self::l3_offset(packet))?;
+        let datagram = packet
+            .packet_buf
+            .peek_bytes(total_len.min(packet.packet_buf.remaining()))?;
+
+        netstack.send_icmp6_dest_unreachable(
+            &packet.vlan,
+            dest_address,
+            source_address,
+            code,
+            datagram,
+        )?;
+
+        Ok(())
+    }
+
+    fn rx_ip6_packet<'a>(&mut self, packet: &'a mut Packet<'a>) -> Result<()> {
+        let ip6 = match Ip6Hdr::from_packet_buf(&mut packet.packet_buf) {
+            Ok(ip6) => ip6,
+            Err(_) => {
+                self.netstack.write().unwrap().stats.ip6_invalid += 1;
+                return Ok(());
+            }
+        };
+        let vlan = packet.vlan.vlan();
+
+        // Neighbor Solicitations are sent to the solicited-node multicast address
+        let dest_address = ip6.dst_address();
+        let for_us = {
+            let netstack = self.netstack.read().unwrap();
+
+            if netstack.is_local_address6(vlan, dest_address) {
+                true
+            } else if netstack.is_solicited_node_address(vlan, dest_address) {
+                false
+            } else {
+                return Ok(());
+            }
+        };
+
+        let next_hdr = ip6.next_hdr;
+        let payload_len = net::utils::ntohs(ip6.payload_len) as usize;
+        let payload_offset = packet.packet_buf.packet_offset;
+
+        let valid = ip6.version() == 6 && payload_len <= packet.packet_buf.remaining();
+        packet.ip6_hdr = Some(ip6);
+
+        // Exclude any link-layer padding
+        packet.packet_buf.truncate(payload_offset + payload_len);
+
+        let proto = match ip6::skip_ext_hdrs(&mut packet.packet_buf, next_hdr) {
+            Ok(Some(proto)) if valid => proto,
+            Ok(None) if valid => return Ok(()),
+            _ => {
+                self.netstack.write().unwrap().stats.ip6_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        // Length of the upper-layer packet, i.e. without the extension headers
+        let l4_len = match payload_len.checked_sub(packet.packet_buf.packet_offset - payload_offset)
+        {
+            Some(l4_len) => l4_len,
+            None => {
+                self.netstack.write().unwrap().stats.ip6_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        if proto == IpProto::ICMPV6 as u8 {
+            self.rx_icmp6_packet(packet, l4_len)
+        } else if proto == IpProto::UDP as u8 && for_us {
+            self.rx_udp6_packet(packet, l4_len)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rx_udp6_packet<'a>(&mut self, packet: &'a mut Packet<'a>, l4_len: usize) -> Result<()> {
+        let udp = match UdpHdr::from_packet_buf(&mut packet.packet_buf) {
+            Ok(udp) => udp,
+            Err(_) => {
+                self.netstack.write().unwrap().stats.udp_invalid += 1;
+                return Ok(());
+            }
+        };
+        let len = net::utils::ntohs(udp.len) as usize;
+        let source_port = net::utils::ntohs(udp.src_port);
+        let dest_port = net::utils::ntohs(udp.dst_port);
+
+        if len < mem::size_of::<UdpHdr>() || len > l4_len {
+            self.netstack.write().unwrap().stats.udp_invalid += 1;
+            return Ok(());
+        }
+
+        let payload_len = len - mem::size_of::<UdpHdr>();
+
+        // The checksum is mandatory for UDP over IPv6
+        let ip6 = packet.ip6_hdr.as_ref().unwrap();
+        if !udp.is_checksum_valid(
+            ip6.pseudo_header_sum(IpProto::UDP, len as u32),
+            packet.packet_buf.peek_bytes(payload_len)?,
+        ) {
+            self.netstack.write().unwrap().stats.udp_bad_checksum += 1;
+            return Ok(());
+        }
+
+        packet.udp_hdr = Some(udp);
+
+        let source_address = ip6.src_address();
+        let dest_address = ip6.dst_address();
+
+        let mut netstack = self.netstack.write().unwrap();
+        let listening = netstack.is_listening(IpAddr::V6(dest_address), dest_port);
+        let unmatched_port = netstack.configuration.unmatched_port();
+
+        // Datagrams are only received for the listeners, which may still have no app
+        let app = match self
+            .apps
+            .get_mut(IpAddr::V6(dest_address), dest_port, listening)
+        {
+            Some(app) => app,
+            None if unmatched_port == UnmatchedPort::Unreachable => {
+                drop(netstack);
+                return self.rx_unreachable6(packet, Icmp6UnreachCode::Port);
+            }
+            None => {
+                netstack.stats.no_listener += 1;
+                return Ok(());
+            }
+        };
+
+        let payload_offset = packet.packet_buf.packet_offset;
+        packet.l4_payload = Some(packet.packet_buf.get_bytes_mut(payload_len)?);
+
+        let socket = Socket {
+            source_address: IpAddr::V6(source_address),
             source_port,
+            local_address: IpAddr::V6(dest_address),
+            local_port: dest_port,
+            dont_fragment: netstack.configuration.ip4_dont_fragment(),
+            vlan: packet.vlan,
         };
 
-        self.app.rx_payload(
-            &mut *self.netstack.write().unwrap(),
+        let ip6 = packet.ip6_hdr.as_ref().unwrap();
+        let metadata = Self::rx_metadata(
+            &netstack,
             &socket,
+            packet.eth_hdr.as_ref().unwrap().src_address,
+            ip6.hop_limit,
+            ip6.traffic_class(),
+            packet.timestamp,
+        );
+
+        // The app may reply in place of the datagram as long as it is delivered
+        netstack.rx_frame = packet.desc.as_ref().map(|desc| RxFrame {
+            addr: desc.addr(),
+            payload_offset,
+            payload_len,
+        });
+
+        let ret = app.rx_payload(
+            &mut *netstack,
+            &socket,
+            &metadata,
             packet.l4_payload.as_mut().unwrap(),
+        );
+
+        netstack.rx_frame = None;
+        ret?;
+
+        Ok(())
+    }
+
+    /// Build the metadata of a datagram received on `socket`, from `source_hw_address` and with
+    /// the given TTL (or hop limit) and TOS (or traffic class).
+    fn rx_metadata(
+        netstack: &net::NetStack,
+        socket: &Socket,
+        source_hw_address: [u8; 6],
+        ttl: u8,
+        tos: u8,
+        timestamp: Option<Instant>,
+    ) -> RxMetadata {
+        RxMetadata {
+            source_address: socket.source_address,
+            source_port: socket.source_port,
+            dest_address: socket.local_address,
+            dest_port: socket.local_port,
+            ttl,
+            tos,
+            source_hw_address,
+            vlan: socket.vlan.vlan(),
+            rx_queue: netstack.rx_queue,
+            socket_index: netstack.socket_index,
+            timestamp: timestamp.unwrap_or_else(Instant::now),
+        }
+    }
+
+    fn rx_icmp6_packet<'a>(&mut self, packet: &'a mut Packet<'a>, l4_len: usize) -> Result<()> {
+        let ip6 = packet.ip6_hdr.as_ref().unwrap();
+        let source_address = ip6.src_address();
+        let dest_address = ip6.dst_address();
+        let hop_limit = ip6.hop_limit;
+        let pseudo_header_sum = ip6.pseudo_header_sum(IpProto::ICMPV6, l4_len as u32);
+
+        let (icmp, data) = match l4_len
+            .checked_sub(mem::size_of::<Icmp6Hdr>())
+            .map(|data_len| {
+                Ok::<_, net::Error>((
+                    Icmp6Hdr::from_packet_buf(&mut packet.packet_buf)?,
+                    packet.packet_buf.get_bytes(data_len)?,
+                ))
+            }) {
+            Some(Ok((icmp, data))) if icmp.is_checksum_valid(pseudo_header_sum, data) => {
+                (icmp, data)
+            }
+            _ => {
+                self.netstack.write().unwrap().stats.icmp6_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        match icmp.icmp_type() {
+            Some(Icmp6Type::EchoRequest) if icmp.code == 0 => {
+                let mut netstack = self.netstack.write().unwrap();
+                if netstack.is_local_address6(packet.vlan.vlan(), dest_address) {
+                    netstack.send_icmp6_echo_reply(
+                        &packet.vlan,
+                        dest_address,
+                        source_address,
+                        icmp.rest,
+                        data,
+                    )?;
+                }
+            }
+            // The message quotes the header of the packet we sent
+            Some(Icmp6Type::PacketTooBig) if data.len() >= mem::size_of::<Ip6Hdr>() => {
+                let src_address = Ipv6Addr::from(<[u8; 16]>::try_from(&data[8..24]).unwrap());
+                let dst_address = Ipv6Addr::from(<[u8; 16]>::try_from(&data[24..40]).unwrap());
+
+                let mut netstack = self.netstack.write().unwrap();
+                if netstack.is_local_address6(packet.vlan.vlan(), dest_address)
+                    && netstack.is_local_address6(packet.vlan.vlan(), src_address)
+                {
+                    netstack.update_path_mtu(IpAddr::V6(dst_address), icmp.mtu() as usize);
+                }
+            }
+            Some(Icmp6Type::NeighborSolicitation)
+                if hop_limit == ND_HOP_LIMIT && icmp.code == 0 =>
+            {
+                let eth_src = packet.eth_hdr.as_ref().unwrap().src_address;
+                self.rx_neighbor_solicitation(
+                    &packet.vlan,
+                    source_address,
+                    dest_address,
+                    eth_src,
+                    data,
+                )?;
+            }
+            Some(Icmp6Type::NeighborAdvertisement)
+                if hop_limit == ND_HOP_LIMIT && icmp.code == 0 =>
+            {
+                let solicited = icmp.has_na_flag(NaFlags::Solicited);
+                self.rx_neighbor_advertisement(packet.vlan.vlan(), dest_address, solicited, data)?;
+            }
+            Some(Icmp6Type::NeighborSolicitation) | Some(Icmp6Type::NeighborAdvertisement) => {
+                self.netstack.write().unwrap().stats.nd_invalid += 1;
+            }
+            _ => {}
+        }
+
+        packet.icmp6_hdr = Some(icmp);
+
+        Ok(())
+    }
+
+    /// Answer a Neighbor Solicitation for one of the IPv6 addresses bound on `vlan`, as described
+    /// in RFC 4861, section 7.2.3.
+    ///
+    /// Solicitations from the unspecified address come from a host performing Duplicate Address
+    /// Detection, which is told that the address is already in use.
+    fn rx_neighbor_solicitation(
+        &mut self,
+        vlan: &VlanTags,
+        source_address: Ipv6Addr,
+        dest_address: Ipv6Addr,
+        eth_src: [u8; 6],
+        data: &[u8],
+    ) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+
+        let (target, hw_address) = match (
+            icmp6::nd_target_address(data),
+            data.get(16..)
+                .map(|options| icmp6::nd_hw_address_option(options, NdOption::SourceHwAddress)),
+        ) {
+            (Some(target), Some(Ok(hw_address)))
+                if !target.is_multicast()
+                    && (!source_address.is_unspecified()
+                        || (hw_address.is_none() && dest_address.is_multicast())) =>
+            {
+                (target, hw_address)
+            }
+            _ => {
+                netstack.stats.nd_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        if !netstack.is_local_address6(vlan.vlan(), target) {
+            return Ok(());
+        }
+
+        if source_address.is_unspecified() {
+            error!(
+                "Address {} is being claimed by {}",
+                target,
+                net::utils::mac_to_string(eth_src)
+            );
+
+            netstack.send_neighbor_advertisement(
+                vlan,
+                target,
+                IP6_ALL_NODES,
+                ip6::multicast_hw_address(IP6_ALL_NODES),
+                false,
+            )?;
+
+            return Ok(());
+        }
+
+        if let Some(hw_address) = hw_address {
+            netstack.merge_neighbor6(vlan.vlan(), source_address, hw_address, true)?;
+        }
+
+        netstack.send_neighbor_advertisement(
+            vlan,
+            target,
+            source_address,
+            hw_address.unwrap_or(eth_src),
+            true,
         )?;
 
         Ok(())
     }
 
+    /// Update the Neighbor Discovery cache from a Neighbor Advertisement, as described in RFC
+    /// 4861, section 7.2.5: a solicited advertisement confirms the reachability of the target,
+    /// while an unsolicited one just updates an existing entry.
+    fn rx_neighbor_advertisement(
+        &mut self,
+        vlan: Option<xsk::Vlan>,
+        dest_address: Ipv6Addr,
+        solicited: bool,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+
+        let (target, hw_address) = match (
+            icmp6::nd_target_address(data),
+            data.get(16..)
+                .map(|options| icmp6::nd_hw_address_option(options, NdOption::TargetHwAddress)),
+        ) {
+            (Some(target), Some(Ok(hw_address)))
+                if !(target.is_multicast() || (solicited && dest_address.is_multicast())) =>
+            {
+                (target, hw_address)
+            }
+            _ => {
+                netstack.stats.nd_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        let hw_address = match hw_address {
+            Some(hw_address) if hw_address != netstack.iface_mac => hw_address,
+            _ => return Ok(()),
+        };
+
+        if solicited {
+            netstack.update_neighbor6(vlan, target, hw_address)?;
+        } else {
+            netstack.merge_neighbor6(vlan, target, hw_address, false)?;
+        }
+
+        Ok(())
+    }
+
     fn rx_arp_packet(&mut self, packet: &mut Packet) -> Result<()> {
         let arp = ArpHdr::from_packet_buf(&mut packet.packet_buf)?;
+
+        let opcode = match arp.opcode() {
+            Some(opcode) if arp.is_ethernet_ip4() => opcode,
+            _ => {
+                self.netstack.write().unwrap().stats.arp_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        let for_us = self
+            .netstack
+            .read()
+            .unwrap()
+            .is_local_address(packet.vlan.vlan(), arp.target_address());
+
         packet.arp_hdr = Some(arp);
 
-        self.update_arp_cache_from_arp(packet);
+        if self.detect_address_conflict(packet) {
+            return Ok(());
+        }
+
+        self.update_arp_cache_from_arp(packet, opcode, for_us)?;
+
+        // The bind address cannot be used until probing is complete, nor once another host has
+        // been found using it
+        let unclaimed = {
+            let netstack = self.netstack.read().unwrap();
+            let target = packet.arp_hdr.as_ref().unwrap().target_address();
+
+            packet.vlan.is_empty()
+                && target == netstack.bind_address
+                && !netstack.announcer.is_claimed()
+        };
 
-        self.send_arp_reply(packet)?;
+        if for_us && opcode == ArpOpcode::REQUEST && !unclaimed {
+            self.send_arp_reply(packet)?;
+        }
 
         Ok(())
     }
 
-    fn update_arp_cache_from_ip(&mut self, packet: &mut Packet) {
+    /// Check whether the ARP packet shows that another host is using the bind address, as
+    /// described in RFC 5227: either its sender address is the bind address, or, while probing,
+    /// it is a probe for the bind address from another host.
+    ///
+    /// Only the IPv4 bind address is probed and announced: conflicts for the addresses of the
+    /// other listeners and of the VLANs are just reported.
+    fn detect_address_conflict(&mut self, packet: &mut Packet) -> bool {
         let mut netstack = self.netstack.write().unwrap();
 
-        let mac = packet.eth_hdr.as_ref().unwrap().src_address;
-        let ip = packet.ip4_hdr.as_ref().unwrap().src_addr;
+        let vlan = packet.vlan.vlan();
+        let arp = packet.arp_hdr.as_ref().unwrap();
+        let sender = arp.sender_address();
+
+        if arp.sender_hw_addr == netstack.iface_mac {
+            return false;
+        }
+
+        let conflict = netstack.is_local_address(vlan, sender)
+            || (vlan.is_none()
+                && netstack.announcer.is_probing()
+                && sender.is_unspecified()
+                && arp.target_address() == netstack.bind_address);
+
+        if !conflict {
+            return false;
+        }
+
+        netstack.stats.arp_conflicts += 1;
+
+        let mac = net::utils::mac_to_string(arp.sender_hw_addr);
+        if let Some(vlan) = vlan {
+            error!(
+                "Address {} on VLAN {} is also claimed by {}",
+                sender, vlan, mac
+            );
+        } else if sender != netstack.bind_address && !sender.is_unspecified() {
+            error!("Address {} is also claimed by {}", sender, mac);
+        } else if netstack.announcer.conflict() {
+            error!(
+                "Address {} is already in use by {}, not claiming it",
+                netstack.bind_address, mac
+            );
+        } else {
+            error!(
+                "Address {} is also claimed by {}",
+                netstack.bind_address, mac
+            );
+        }
 
-        netstack
-            .arp_table
-            .insert(std::net::Ipv4Addr::from(net::utils::ntohl(ip)), mac);
+        true
     }
 
-    fn update_arp_cache_from_arp(&mut self, packet: &mut Packet) {
+    /// Refresh the ARP cache entry of the sender of an IPv4 packet.
+    ///
+    /// Receiving a packet does not confirm that the sender is reachable (RFC 4861, section
+    /// 7.3.1), so only an existing entry is updated, and it becomes stale if its link-layer
+    /// address changed.
+    fn update_arp_cache_from_ip(&mut self, packet: &mut Packet) -> Result<()> {
         let mut netstack = self.netstack.write().unwrap();
 
+        let vlan = packet.vlan.vlan();
         let mac = packet.eth_hdr.as_ref().unwrap().src_address;
-        let ip = packet.arp_hdr.as_ref().unwrap().sender_proto_addr;
+        let ip = Ipv4Addr::from(net::utils::ntohl(packet.ip4_hdr.as_ref().unwrap().src_addr));
 
-        netstack
-            .arp_table
-            .insert(std::net::Ipv4Addr::from(u32::from_be_bytes(ip)), mac);
+        // Packets from off-link hosts carry the link-layer address of the router, while VLANs
+        // are not routed
+        if vlan.is_some() || netstack.routes.is_on_link(IpAddr::V4(ip)) {
+            netstack.merge_neighbor(vlan, ip, mac, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Update the ARP cache following the RFC 826 merge rules: the sender of the packet is
+    /// added to the cache only if we are the target, otherwise an existing entry is just
+    /// updated.
+    ///
+    /// A reply addressed to us is considered a confirmation of the reachability of the sender.
+    fn update_arp_cache_from_arp(
+        &mut self,
+        packet: &mut Packet,
+        opcode: ArpOpcode,
+        for_us: bool,
+    ) -> Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+
+        let arp = packet.arp_hdr.as_ref().unwrap();
+        let mac = arp.sender_hw_addr;
+        let ip = arp.sender_address();
+
+        // ARP probes (RFC 5227) have an unspecified sender address
+        if ip.is_unspecified() || mac == netstack.iface_mac {
+            return Ok(());
+        }
+
+        let vlan = packet.vlan.vlan();
+        if for_us && opcode == ArpOpcode::REPLY {
+            netstack.update_neighbor(vlan, ip, mac)?;
+        } else {
+            netstack.merge_neighbor(vlan, ip, mac, for_us)?;
+        }
+
+        Ok(())
     }
 }
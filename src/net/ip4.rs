@@ -15,7 +15,14 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{fmt, mem, net::Ipv4Addr, slice};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    mem,
+    net::Ipv4Addr,
+    slice,
+};
 
 use crate::{
     net,
@@ -37,11 +44,17 @@ pub struct Ip4Hdr {
 }
 
 pub enum IpProto {
+    ICMP = 1,
     UDP = 17,
+    ICMPV6 = 58,
 }
 
 const IP4_VERSION: u8 = 4;
 
+/// Number of counters the per-destination IDs are drawn from, as destinations share a counter
+/// when they hash to the same bucket.
+const IP4_ID_BUCKETS: usize = 2048;
+
 pub enum IpFlags {
     Reserved = 1,
     DontFragment = 2,
@@ -104,23 +117,43 @@ impl Ip4Hdr {
 
     pub fn set_flags(&mut self, v: u8) -> &mut Self {
         let mut flags_frag_offset = net::utils::ntohs(self.flags_frag_offset);
-        flags_frag_offset = (flags_frag_offset & 0x2000) | ((v as u16 & 0x7) << 13);
+        flags_frag_offset = (flags_frag_offset & 0x1fff) | ((v as u16 & 0x7) << 13);
         self.flags_frag_offset = net::utils::htons(flags_frag_offset);
         self
     }
 
     pub fn set_frag_offset(&mut self, v: u16) -> &mut Self {
         let mut flags_frag_offset = net::utils::ntohs(self.flags_frag_offset);
-        flags_frag_offset = (flags_frag_offset & 0xe000) | (v & 0x2000);
+        flags_frag_offset = (flags_frag_offset & 0xe000) | (v & 0x1fff);
         self.flags_frag_offset = net::utils::htons(flags_frag_offset);
         self
     }
 
+    pub fn icmp(&mut self) -> &mut Self {
+        self.proto = IpProto::ICMP as u8;
+        self
+    }
+
     pub fn udp(&mut self) -> &mut Self {
         self.proto = IpProto::UDP as u8;
         self
     }
 
+    pub fn set_tos(&mut self, v: u8) -> &mut Self {
+        self.tos = v;
+        self
+    }
+
+    pub fn set_id(&mut self, v: u16) -> &mut Self {
+        self.id = net::utils::htons(v);
+        self
+    }
+
+    pub fn set_ttl(&mut self, v: u8) -> &mut Self {
+        self.ttl = v;
+        self
+    }
+
     pub fn set_src_address(&mut self, v: Ipv4Addr) -> &mut Self {
         self.src_addr = net::utils::htonl(v.into());
         self
@@ -134,29 +167,25 @@ impl Ip4Hdr {
     pub fn calc_checksum(&mut self) {
         self.checksum = 0;
 
-        self.checksum = unsafe {
-            net::utils::ntohs({
-                let buf: *const u8 = &*self as *const net::ip4::Ip4Hdr as *const u8;
-                let slice = slice::from_raw_parts(buf, 20);
-
-                let mut sum: u32 = 0;
-                let len = 20;
-                let mut i = 0;
-
-                while i < len {
-                    let word = (slice[i] as u32) << 8 | (slice[i + 1] as u32);
+        // The options, if any, follow the header
+        let hdr = unsafe {
+            slice::from_raw_parts(
+                self as *const Ip4Hdr as *const u8,
+                self.hdr_len() as usize * 4,
+            )
+        };
 
-                    sum += word;
-                    i += 2;
-                }
+        self.checksum = net::utils::htons(net::utils::csum_fold(net::utils::csum_add(0, hdr)));
+    }
 
-                while sum >> 16 != 0 {
-                    sum = (sum >> 16) + (sum & 0xffff);
-                }
+    /// Returns the one's complement sum of the pseudo-header used to compute the checksum of an
+    /// upper-layer packet of protocol `proto` and length `len` (RFC 768).
+    pub fn pseudo_header_sum(&self, proto: IpProto, len: u16) -> u32 {
+        let mut sum = net::utils::csum_add(0, &self.src_addr.to_ne_bytes());
+        sum = net::utils::csum_add(sum, &self.dst_addr.to_ne_bytes());
+        sum = net::utils::csum_add(sum, &len.to_be_bytes());
 
-                !sum as u16
-            })
-        };
+        net::utils::csum_add(sum, &[0, proto as u8])
     }
 }
 
@@ -174,7 +203,7 @@ impl fmt::Debug for Ip4Hdr {
             net::utils::ntohs(self.total_len),
             net::utils::ntohs(self.id),
             self.flags(),
-            net::utils::ntohs(self.frag_offset()),
+            self.frag_offset(),
             self.ttl,
             self.proto,
             net::utils::ntohs(self.checksum),
@@ -183,3 +212,92 @@ impl fmt::Debug for Ip4Hdr {
         )
     }
 }
+
+/// How the Identification field of the IPv4 datagrams sent is chosen.
+///
+/// The ID only needs to be unique for datagrams that may be fragmented (RFC 6864): datagrams
+/// sent without the Don't Fragment flag always get a per-destination ID when the policy is
+/// [`IpIdPolicy::Zero`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IpIdPolicy {
+    /// Always 0.
+    Zero,
+    /// Incremented for each datagram sent to the same destination.
+    PerDestination,
+    /// Pseudo-random.
+    Random,
+}
+
+/// Generator of the IDs of the IPv4 datagrams sent.
+pub struct Ip4IdGenerator {
+    counters: Vec<u16>,
+    hasher:   RandomState,
+    rng:      u64,
+}
+
+impl Default for Ip4IdGenerator {
+    fn default() -> Self {
+        let hasher = RandomState::new();
+        // Seed the PRNG from the random keys of the hasher, xorshift must not start from 0
+        let rng = hasher.build_hasher().finish() | 1;
+
+        Ip4IdGenerator {
+            counters: vec![0; IP4_ID_BUCKETS],
+            hasher,
+            rng,
+        }
+    }
+}
+
+impl Ip4IdGenerator {
+    /// Returns the ID of the next datagram sent to `dst_address` according to `policy`. `atomic`
+    /// tells whether the datagram is sent with the Don't Fragment flag and unfragmented.
+    pub fn next(&mut self, policy: IpIdPolicy, dst_address: Ipv4Addr, atomic: bool) -> u16 {
+        match policy {
+            IpIdPolicy::Zero if atomic => 0,
+            IpIdPolicy::Zero | IpIdPolicy::PerDestination => {
+                let bucket = self.hasher.hash_one(dst_address) as usize % IP4_ID_BUCKETS;
+
+                let counter = &mut self.counters[bucket];
+                let id = *counter;
+                *counter = counter.wrapping_add(1);
+
+                id
+            }
+            IpIdPolicy::Random => {
+                // xorshift64
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+
+                (self.rng >> 32) as u16
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip4_id_generator() {
+        let mut ids = Ip4IdGenerator::default();
+        let a = Ipv4Addr::new(198, 18, 0, 1);
+
+        assert_eq!(ids.next(IpIdPolicy::Zero, a, true), 0);
+
+        let first = ids.next(IpIdPolicy::PerDestination, a, true);
+        assert_eq!(
+            ids.next(IpIdPolicy::PerDestination, a, true),
+            first.wrapping_add(1)
+        );
+        // Datagrams that may be fragmented need a unique ID
+        assert_eq!(ids.next(IpIdPolicy::Zero, a, false), first.wrapping_add(2));
+
+        let random = (0..16)
+            .map(|_| ids.next(IpIdPolicy::Random, a, true))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(random.len() > 1);
+    }
+}
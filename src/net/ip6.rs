@@ -0,0 +1,232 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fmt, mem, net::Ipv6Addr};
+
+use crate::{
+    net,
+    net::{IpProto, PacketBufMut, Result},
+};
+
+#[repr(C)]
+pub struct Ip6Hdr {
+    pub version_tc_flow: u32,
+    pub payload_len:     u16,
+    pub next_hdr:        u8,
+    pub hop_limit:       u8,
+    pub src_addr:        [u8; 16],
+    pub dst_addr:        [u8; 16],
+}
+
+/// IPv6 extension headers which can be skipped to reach the upper-layer header.
+pub enum Ip6ExtHdr {
+    HopByHop = 0,
+    Routing = 43,
+    Fragment = 44,
+    DestOpts = 60,
+}
+
+const IP6_VERSION: u8 = 6;
+
+/// Hop limit of the Neighbor Discovery messages, which must not be forwarded (RFC 4861).
+pub const ND_HOP_LIMIT: u8 = 255;
+
+/// The all-nodes link-local multicast address.
+pub const IP6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+impl Ip6Hdr {
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn from_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        packet
+            .get_bytes_mut(mem::size_of::<Self>())
+            .map(|l3_slice| unsafe { &mut *(l3_slice.as_mut_ptr() as *mut Ip6Hdr) })
+    }
+
+    pub fn with_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        let hdr = Self::from_packet_buf(packet)?;
+
+        hdr.version_tc_flow = net::utils::htonl((IP6_VERSION as u32) << 28);
+        hdr.hop_limit = 64;
+
+        Ok(hdr)
+    }
+
+    pub fn version(&self) -> u8 {
+        (net::utils::ntohl(self.version_tc_flow) >> 28) as u8
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (net::utils::ntohl(self.version_tc_flow) >> 20) as u8
+    }
+
+    pub fn src_address(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.src_addr)
+    }
+
+    pub fn dst_address(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.dst_addr)
+    }
+
+    pub fn set_payload_length(&mut self, v: u16) -> &mut Self {
+        self.payload_len = net::utils::htons(v);
+        self
+    }
+
+    pub fn set_traffic_class(&mut self, v: u8) -> &mut Self {
+        let version_tc_flow = net::utils::ntohl(self.version_tc_flow);
+        self.version_tc_flow =
+            net::utils::htonl((version_tc_flow & 0xf00f_ffff) | ((v as u32) << 20));
+        self
+    }
+
+    pub fn set_hop_limit(&mut self, v: u8) -> &mut Self {
+        self.hop_limit = v;
+        self
+    }
+
+    pub fn udp(&mut self) -> &mut Self {
+        self.next_hdr = IpProto::UDP as u8;
+        self
+    }
+
+    pub fn icmp6(&mut self) -> &mut Self {
+        self.next_hdr = IpProto::ICMPV6 as u8;
+        self
+    }
+
+    pub fn set_src_address(&mut self, v: Ipv6Addr) -> &mut Self {
+        self.src_addr = v.octets();
+        self
+    }
+
+    pub fn set_dst_address(&mut self, v: Ipv6Addr) -> &mut Self {
+        self.dst_addr = v.octets();
+        self
+    }
+
+    /// Returns the one's complement sum of the pseudo-header used to compute the checksum of an
+    /// upper-layer packet of protocol `proto` and length `len` (RFC 8200, section 8.1).
+    pub fn pseudo_header_sum(&self, proto: IpProto, len: u32) -> u32 {
+        let mut sum = net::utils::csum_add(0, &self.src_addr);
+        sum = net::utils::csum_add(sum, &self.dst_addr);
+        sum = net::utils::csum_add(sum, &len.to_be_bytes());
+
+        net::utils::csum_add(sum, &[0, 0, 0, proto as u8])
+    }
+}
+
+/// Skip the extension headers following an IPv6 header whose next header is `next_hdr`,
+/// leaving `packet` at the beginning of the upper-layer header.
+///
+/// Returns the upper-layer protocol, or `None` if there is no upper-layer header or the packet
+/// is a fragment.
+pub fn skip_ext_hdrs(packet: &mut PacketBufMut, mut next_hdr: u8) -> Result<Option<u8>> {
+    loop {
+        let len = match next_hdr {
+            x if x == Ip6ExtHdr::HopByHop as u8
+                || x == Ip6ExtHdr::Routing as u8
+                || x == Ip6ExtHdr::DestOpts as u8 =>
+            {
+                let hdr = packet.peek_bytes(2)?;
+                (hdr[1] as usize + 1) * 8
+            }
+            x if x == Ip6ExtHdr::Fragment as u8 => return Ok(None),
+            _ => return Ok(Some(next_hdr)),
+        };
+
+        next_hdr = packet.get_bytes(len)?[0];
+    }
+}
+
+/// Returns the solicited-node multicast address of `address` (RFC 4291, section 2.7.1).
+pub fn solicited_node_address(address: Ipv6Addr) -> Ipv6Addr {
+    let o = address.octets();
+
+    Ipv6Addr::from([
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, o[13], o[14], o[15],
+    ])
+}
+
+/// Returns the Ethernet address the multicast `address` is mapped to (RFC 2464, section 7).
+pub fn multicast_hw_address(address: Ipv6Addr) -> [u8; 6] {
+    let o = address.octets();
+
+    [0x33, 0x33, o[12], o[13], o[14], o[15]]
+}
+
+impl fmt::Debug for Ip6Hdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "  Ip6Hdr {{ version: {}, payload_len: {}, next_hdr: {}, hop_limit: {}, \
+            src_addr: {}, dst_addr: {} }}",
+            self.version(),
+            net::utils::ntohs(self.payload_len),
+            self.next_hdr,
+            self.hop_limit,
+            self.src_address(),
+            self.dst_address(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_ext_hdrs() {
+        let mut buf = [0_u8; 32];
+        // Hop-by-Hop options header (8 bytes) followed by a Destination options header (16 bytes)
+        buf[0] = Ip6ExtHdr::DestOpts as u8;
+        buf[8] = IpProto::UDP as u8;
+        buf[9] = 1;
+
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+        let next_hdr = skip_ext_hdrs(&mut packet_buf, Ip6ExtHdr::HopByHop as u8).unwrap();
+
+        assert_eq!(next_hdr, Some(IpProto::UDP as u8));
+        assert_eq!(packet_buf.packet_offset, 24);
+
+        let mut buf = [0_u8; 8];
+        buf[0] = IpProto::UDP as u8;
+
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+        let next_hdr = skip_ext_hdrs(&mut packet_buf, Ip6ExtHdr::Fragment as u8).unwrap();
+        assert_eq!(next_hdr, None);
+
+        // Truncated extension header
+        let mut buf = [0_u8, 1, 0, 0];
+        let mut packet_buf = PacketBufMut::from_slice(&mut buf);
+        assert!(skip_ext_hdrs(&mut packet_buf, Ip6ExtHdr::Routing as u8).is_err());
+    }
+
+    #[test]
+    fn test_multicast_addresses() {
+        let address: Ipv6Addr = "fc00::c612:302".parse().unwrap();
+
+        let solicited_node = solicited_node_address(address);
+        assert_eq!(
+            solicited_node,
+            "ff02::1:ff12:302".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            multicast_hw_address(solicited_node),
+            [0x33, 0x33, 0xff, 0x12, 0x03, 0x02]
+        );
+    }
+}
@@ -16,10 +16,11 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
-    collections::HashMap,
-    net::Ipv4Addr,
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     rc::Rc,
     sync::{Arc, RwLock},
+    time::Instant,
 };
 
 pub mod error;
@@ -40,15 +41,48 @@ pub mod output;
 
 pub mod utils;
 
+pub mod stats;
+pub use self::stats::*;
+
+pub mod neighbor;
+pub use self::neighbor::*;
+
+pub mod announce;
+pub use self::announce::*;
+
+pub mod reassembly;
+pub use self::reassembly::*;
+
+pub mod pmtu;
+pub use self::pmtu::*;
+
+pub mod route;
+pub use self::route::*;
+
+pub mod rate_limit;
+pub use self::rate_limit::*;
+
 pub mod eth;
 pub use self::eth::*;
 
+pub mod vlan;
+pub use self::vlan::*;
+
 pub mod arp;
 pub use self::arp::*;
 
 pub mod ip4;
 pub use self::ip4::*;
 
+pub mod icmp;
+pub use self::icmp::*;
+
+pub mod ip6;
+pub use self::ip6::*;
+
+pub mod icmp6;
+pub use self::icmp6::*;
+
 pub mod udp;
 pub use self::udp::*;
 
@@ -56,22 +90,174 @@ pub mod app;
 
 use crate::xsk;
 
+/// MTU used when the one of the link cannot be read.
+const DEFAULT_MTU: usize = 1500;
+
 pub struct NetStack {
     configuration: Configuration,
     xsk_handle:    Rc<RwLock<xsk::net::Handle>>,
 
-    iface_mac:    [u8; 6],
-    bind_address: Ipv4Addr,
-    bind_port:    u16,
+    iface_mac:      [u8; 6],
+    bind_address:   Ipv4Addr,
+    bind_address6:  Option<Ipv6Addr>,
+    bind_addresses: Vec<IpAddr>,
+    vlan_addresses: Vec<(xsk::Vlan, IpAddr)>,
+    /// Extended by the sockets created by the apps, hence the lock.
+    listeners:      RwLock<Vec<SocketAddr>>,
+    rx_queue:       usize,
+    socket_index:   usize,
+    mtu:            usize,
+    ip4_ids:        Ip4IdGenerator,
+    rx_frame:       Option<RxFrame>,
+
+    routes:    RoutingTable,
+    arp_table: NeighborCache<(Option<xsk::Vlan>, Ipv4Addr), xsk::Desc>,
+    nd_table:  NeighborCache<(Option<xsk::Vlan>, Ipv6Addr), xsk::Desc>,
+    announcer: Announcer,
 
-    arp_table: HashMap<Ipv4Addr, [u8; 6]>,
+    icmp_ratelimit:  TokenBucket,
+    ip4_reassembler: Reassembler,
+    pmtu_cache:      PmtuCache,
+
+    stats: Stats,
 }
 
 unsafe impl Send for NetStack {}
 unsafe impl Sync for NetStack {}
 
+impl NetStack {
+    /// Returns true if `address` is one of the addresses the stack is bound to on `vlan`, or on
+    /// the untagged network if `vlan` is `None`.
+    pub fn is_local_address(&self, vlan: Option<xsk::Vlan>, address: Ipv4Addr) -> bool {
+        match vlan {
+            Some(vlan) => self.vlan_addresses.contains(&(vlan, IpAddr::V4(address))),
+            None => self.bind_addresses.contains(&IpAddr::V4(address)),
+        }
+    }
+
+    /// Returns true if `address` is one of the IPv6 addresses the stack is bound to on `vlan`,
+    /// or on the untagged network if `vlan` is `None`.
+    pub fn is_local_address6(&self, vlan: Option<xsk::Vlan>, address: Ipv6Addr) -> bool {
+        match vlan {
+            Some(vlan) => self.vlan_addresses.contains(&(vlan, IpAddr::V6(address))),
+            None => self.bind_addresses.contains(&IpAddr::V6(address)),
+        }
+    }
+
+    /// Returns true if `address` is the solicited-node multicast address of one of the IPv6
+    /// addresses the stack is bound to on `vlan`.
+    pub fn is_solicited_node_address(&self, vlan: Option<xsk::Vlan>, address: Ipv6Addr) -> bool {
+        let is_solicited_node = |a: &IpAddr| match a {
+            IpAddr::V6(a) => ip6::solicited_node_address(*a) == address,
+            IpAddr::V4(_) => false,
+        };
+
+        match vlan {
+            Some(vlan) => self
+                .vlan_addresses
+                .iter()
+                .any(|(v, a)| *v == vlan && is_solicited_node(a)),
+            None => self.bind_addresses.iter().any(is_solicited_node),
+        }
+    }
+
+    /// Returns true if datagrams sent to `address` and `port` are accepted.
+    pub fn is_listening(&self, address: IpAddr, port: u16) -> bool {
+        self.listeners
+            .read()
+            .unwrap()
+            .iter()
+            .any(|l| l.ip() == address && l.port() == port)
+    }
+
+    /// Redirects the datagrams sent to `port` on `address`, or on all the local addresses if it
+    /// is `None`, to the stack, as the XDP program only passes the ones for its listeners, and
+    /// accepts them.
+    fn add_listener(&self, address: Option<IpAddr>, port: u16) -> anyhow::Result<()> {
+        let addresses: Vec<IpAddr> = match address {
+            Some(address) => vec![address],
+            None => self
+                .bind_addresses
+                .iter()
+                .copied()
+                .chain(self.vlan_addresses.iter().map(|&(_, address)| address))
+                .collect(),
+        };
+
+        let xsk_handle = self.xsk_handle.read().unwrap();
+        for address in addresses {
+            let listener = SocketAddr::new(address, port);
+            xsk_handle.add_listener(listener)?;
+
+            let mut listeners = self.listeners.write().unwrap();
+            if !listeners.contains(&listener) {
+                listeners.push(listener);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the IPv4 address used as source on `vlan`, or on the untagged network if `vlan`
+    /// is `None`.
+    pub fn link_address(&self, vlan: Option<xsk::Vlan>) -> Result<Ipv4Addr> {
+        let vlan = match vlan {
+            Some(vlan) => vlan,
+            None => return Ok(self.bind_address),
+        };
+
+        self.vlan_addresses
+            .iter()
+            .find_map(|&(v, a)| match a {
+                IpAddr::V4(a) if v == vlan => Some(a),
+                _ => None,
+            })
+            .ok_or(Error::NoVlanAddress(vlan))
+    }
+
+    /// Returns the IPv6 address used as source on `vlan`, or on the untagged network if `vlan`
+    /// is `None`.
+    pub fn link_address6(&self, vlan: Option<xsk::Vlan>) -> Result<Ipv6Addr> {
+        let vlan = match vlan {
+            Some(vlan) => vlan,
+            None => return self.bind_address6.ok_or(Error::NoBindAddress6),
+        };
+
+        self.vlan_addresses
+            .iter()
+            .find_map(|&(v, a)| match a {
+                IpAddr::V6(a) if v == vlan => Some(a),
+                _ => None,
+            })
+            .ok_or(Error::NoVlanAddress(vlan))
+    }
+
+    /// Returns the MTU of the path toward `address`, never lower than the minimum MTU of its
+    /// protocol, even if the one of the link is.
+    pub fn path_mtu(&self, address: IpAddr) -> usize {
+        let mtu = self
+            .pmtu_cache
+            .get(address, Instant::now())
+            .map_or(self.mtu, |mtu| mtu.min(self.mtu));
+
+        clamp_mtu(address, mtu)
+    }
+
+    /// Lowers the MTU of the path toward `address` after an ICMP message reported that a packet
+    /// was too big.
+    pub fn update_path_mtu(&mut self, address: IpAddr, mtu: usize) {
+        let current_mtu = self.path_mtu(address);
+        if self
+            .pmtu_cache
+            .update(address, mtu, current_mtu, Instant::now())
+        {
+            self.stats.pmtu_updates += 1;
+        }
+    }
+}
+
 pub struct Net {
-    app:      Box<dyn app::App>,
+    apps:     app::AppRegistry,
     netstack: Arc<RwLock<NetStack>>,
 }
 
@@ -82,37 +268,157 @@ impl Net {
     pub fn new(mut configuration: Configuration) -> Self {
         let xsk_handle = Rc::new(RwLock::new(configuration.take_xsk_handle()));
 
-        let (interface, bind_address, bind_port) = {
+        let (
+            interface,
+            bind_address,
+            bind_address6,
+            bind_addresses,
+            vlan_addresses,
+            listeners,
+            rx_queue,
+            socket_index,
+            frame_size,
+        ) = {
             let xsk_handle = xsk_handle.read().unwrap();
             let cfg = xsk_handle.configuration();
 
             (
                 String::from(cfg.interface()),
                 cfg.bind_address(),
-                cfg.bind_port(),
+                cfg.bind_address6(),
+                cfg.bind_addresses(),
+                cfg.vlan_bind_addresses().to_vec(),
+                cfg.listeners(),
+                xsk_handle.queue(),
+                xsk_handle.socket_index(),
+                cfg.frame_size(),
             )
         };
 
         let iface_mac = utils::get_phy_mac_addr(&interface).unwrap();
 
+        // A packet must fit in a single UMEM frame, even with VLAN tags
+        let mtu = configuration
+            .mtu()
+            .or_else(|| utils::get_mtu(&interface))
+            .unwrap_or(DEFAULT_MTU)
+            .min(frame_size - mem::size_of::<EthHdr>() - VLAN_MAX_TAGS * mem::size_of::<VlanHdr>());
+
+        let routes = Self::routing_table(&configuration, &interface);
+        let arp_table = NeighborCache::new(&configuration);
+        let nd_table = NeighborCache::new(&configuration);
+        let announcer = Announcer::new(&configuration, Instant::now());
+        let icmp_ratelimit = TokenBucket::new(
+            configuration.icmp_ratelimit(),
+            configuration.icmp_ratelimit_burst(),
+            Instant::now(),
+        );
+        let ip4_reassembler = Reassembler::new(&configuration);
+        let pmtu_cache = PmtuCache::new(&configuration);
+
         let netstack = Arc::new(RwLock::new(NetStack {
             configuration,
             xsk_handle,
 
             iface_mac,
             bind_address,
-            bind_port,
+            bind_address6,
+            bind_addresses,
+            vlan_addresses,
+            listeners: RwLock::new(listeners),
+            rx_queue,
+            socket_index,
+            mtu,
+            ip4_ids: Ip4IdGenerator::default(),
+            rx_frame: None,
+
+            routes,
+            arp_table,
+            nd_table,
+            announcer,
 
-            arp_table: HashMap::new(),
+            icmp_ratelimit,
+            ip4_reassembler,
+            pmtu_cache,
+
+            stats: Stats::default(),
         }));
 
-        let app = {
-            let n = netstack.clone();
-            let netstack = netstack.write().unwrap();
-            (netstack.configuration.app_allocator())(n)
+        let apps = {
+            let mut apps = app::AppRegistry::default();
+            let n = netstack.write().unwrap();
+
+            if let Some(app_allocator) = n.configuration.app_allocator() {
+                apps.set_default(app_allocator(netstack.clone()));
+            }
+
+            for (address, port, app_allocator) in n.configuration.app_allocators() {
+                apps.add(*address, *port, app_allocator(netstack.clone()));
+
+                n.add_listener(*address, *port)
+                    .unwrap_or_else(|e| error!("Cannot listen on port {}: {}", port, e));
+            }
+
+            apps
         };
 
-        Net { netstack, app }
+        let net = Net { netstack, apps };
+        net.netstack
+            .write()
+            .unwrap()
+            .run_announcer(Instant::now())
+            .unwrap_or_else(|e| error!("Cannot announce {}: {}", bind_address, e));
+
+        net
+    }
+
+    /// Build the routing table from the prefixes of `interface`, the routes imported from the
+    /// kernel, if enabled, and the static routes, in this order of precedence.
+    fn routing_table(configuration: &Configuration, interface: &str) -> RoutingTable {
+        let mut routes = RoutingTable::default();
+
+        for (address, prefix_len) in utils::get_iface_prefixes(interface) {
+            routes.add(Route::connected(address, prefix_len));
+        }
+
+        if configuration.import_routes() {
+            let kernel_routes = nix::net::if_::if_nametoindex(interface)
+                .map_err(|e| e.into())
+                .and_then(import_kernel_routes);
+
+            match kernel_routes {
+                Ok(kernel_routes) => kernel_routes.into_iter().for_each(|r| routes.add(r)),
+                Err(e) => error!("Cannot import the routes of {}: {}", interface, e),
+            }
+        }
+
+        for route in configuration.routes() {
+            routes.add(*route);
+        }
+
+        routes
+    }
+
+    /// Probe and announce the bind address again, e.g. after it has been changed.
+    pub fn announce(&mut self) -> anyhow::Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+        let now = Instant::now();
+
+        netstack.announcer.restart(now);
+        netstack.run_announcer(now)
+    }
+
+    /// Returns true if another host was found using the bind address while probing it.
+    ///
+    /// The address is then never used, and the stack reports itself as failed to `xsk`, which
+    /// stops (RFC 5227, section 2.1.1).
+    pub fn has_address_conflict(&self) -> bool {
+        self.netstack.read().unwrap().announcer.state() == AnnouncerState::Conflict
+    }
+
+    /// Returns a snapshot of the network stack counters.
+    pub fn stats(&self) -> Stats {
+        self.netstack.read().unwrap().stats.clone()
     }
 }
 
@@ -121,4 +427,22 @@ impl xsk::net::Net for Net {
         self.do_rx_packet(desc)?;
         Ok(())
     }
+
+    fn tick(&mut self) -> anyhow::Result<()> {
+        let mut netstack = self.netstack.write().unwrap();
+        let now = Instant::now();
+
+        netstack.expire_neighbors(now)?;
+
+        let expired = netstack.ip4_reassembler.expire(now);
+        netstack.stats.ip4_frag_timeouts += expired as u64;
+
+        netstack.pmtu_cache.expire(now);
+
+        netstack.run_announcer(now)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.has_address_conflict()
+    }
 }
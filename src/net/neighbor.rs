@@ -0,0 +1,536 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Neighbor resolution.
+//!
+//! The cache itself is protocol agnostic: it keeps track of the state of each neighbor, queues
+//! the packets waiting for a resolution and tells the caller when a solicitation (e.g. an ARP
+//! request) has to be sent, leaving to the caller the job of actually sending it.
+//!
+//! Neighbors go through the states described in RFC 4861, section 7.3.2 (minus DELAY, as
+//! entries are probed as soon as a stale entry is used):
+//!
+//! * `Incomplete`: resolution in progress, packets are queued;
+//! * `Reachable`: the link-layer address was confirmed less than `reachable_time` ago;
+//! * `Stale`: the link-layer address is still used, but it needs to be confirmed;
+//! * `Probe`: the link-layer address is being confirmed with unicast solicitations;
+//! * `Failed`: the neighbor did not answer to the solicitations.
+//!
+//! All the timers are driven by [`NeighborCache::expire`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::net::Configuration;
+
+/// State of a neighbor cache entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Probe,
+    Failed,
+}
+
+struct Neighbor<P> {
+    state:      NeighborState,
+    hw_address: Option<[u8; 6]>,
+
+    pending:      VecDeque<P>,
+    solicits:     usize,
+    next_solicit: Instant,
+
+    // Time of the last state transition
+    updated: Instant,
+    // Time of the last lookup, used for the LRU eviction
+    used:    Instant,
+}
+
+impl<P> Neighbor<P> {
+    fn new(now: Instant) -> Self {
+        Neighbor {
+            state:      NeighborState::Incomplete,
+            hw_address: None,
+
+            pending:      VecDeque::new(),
+            solicits:     0,
+            next_solicit: now,
+
+            updated: now,
+            used:    now,
+        }
+    }
+
+    fn set_state(&mut self, state: NeighborState, now: Instant) {
+        self.state = state;
+        self.updated = now;
+    }
+}
+
+/// Outcome of [`NeighborCache::resolve`].
+pub enum Resolution<P> {
+    /// The packet must be sent to the given link-layer address.
+    Send(P, [u8; 6]),
+    /// The packet must be sent to the given link-layer address, and the neighbor must be probed
+    /// with a unicast solicitation as its entry is stale.
+    SendAndProbe(P, [u8; 6]),
+    /// The packet has been queued and a new solicitation must be broadcast.
+    Solicit,
+    /// The packet has been queued behind an already outstanding solicitation.
+    Queued,
+    /// The backlog (or the cache) is full, the packet is handed back to the caller.
+    Full(P),
+}
+
+/// Outcome of [`NeighborCache::expire`].
+pub struct Expired<A, P> {
+    /// Neighbors for which a solicitation must be sent, either broadcast (`None`) or unicast to
+    /// the given link-layer address.
+    pub solicit: Vec<(A, Option<[u8; 6]>)>,
+    /// Packets whose neighbor could not be resolved in time.
+    pub failed:  Vec<P>,
+}
+
+/// A cache mapping protocol addresses of type `A` to link-layer addresses, holding packets of
+/// type `P` while the resolution is in progress.
+pub struct NeighborCache<A, P> {
+    entries: HashMap<A, Neighbor<P>>,
+
+    backlog_size:   usize,
+    retrans_time:   Duration,
+    max_solicits:   usize,
+    reachable_time: Duration,
+    stale_time:     Duration,
+    max_entries:    usize,
+}
+
+impl<A: Copy + Eq + Hash, P> NeighborCache<A, P> {
+    pub fn new(configuration: &Configuration) -> Self {
+        NeighborCache {
+            entries: HashMap::new(),
+
+            backlog_size:   configuration.neigh_backlog_size(),
+            retrans_time:   configuration.neigh_retrans_time(),
+            max_solicits:   configuration.neigh_max_solicits(),
+            reachable_time: configuration.neigh_reachable_time(),
+            stale_time:     configuration.neigh_stale_time(),
+            max_entries:    configuration.neigh_max_entries(),
+        }
+    }
+
+    /// Returns the state of `addr`, if it is in the cache.
+    pub fn state(&self, addr: &A) -> Option<NeighborState> {
+        self.entries.get(addr).map(|n| n.state)
+    }
+
+    /// Returns the link-layer address of `addr`, if it is known.
+    pub fn hw_address(&self, addr: &A) -> Option<[u8; 6]> {
+        self.entries.get(addr).and_then(|n| n.hw_address)
+    }
+
+    /// Returns how many packets for `addr` can still be passed to [`NeighborCache::resolve`]
+    /// without being handed back because its backlog is full: unlimited if its link-layer
+    /// address is known.
+    pub fn backlog_room(&self, addr: &A) -> usize {
+        if self.is_resolved(addr) {
+            return usize::MAX;
+        }
+
+        match self.entries.get(addr) {
+            Some(neighbor) => self.backlog_size.saturating_sub(neighbor.pending.len()),
+            None => self.backlog_size,
+        }
+    }
+
+    /// Returns true if the packets for `addr` passed to [`NeighborCache::resolve`] are sent right
+    /// away rather than queued, as its link-layer address is known.
+    pub fn is_resolved(&self, addr: &A) -> bool {
+        self.entries.get(addr).is_some_and(|neighbor| {
+            matches!(
+                (neighbor.state, neighbor.hw_address),
+                (NeighborState::Reachable, Some(_))
+                    | (NeighborState::Probe, Some(_))
+                    | (NeighborState::Stale, Some(_))
+            )
+        })
+    }
+
+    /// Looks up the link-layer address of `addr` in order to send `packet` to it.
+    pub fn resolve(&mut self, addr: A, packet: P, now: Instant) -> Resolution<P> {
+        if !self.entries.contains_key(&addr) {
+            if !self.make_room() {
+                return Resolution::Full(packet);
+            }
+
+            self.entries.insert(addr, Neighbor::new(now));
+        }
+
+        let neighbor = self.entries.get_mut(&addr).unwrap();
+        neighbor.used = now;
+
+        match (neighbor.state, neighbor.hw_address) {
+            (NeighborState::Reachable, Some(hw_address))
+            | (NeighborState::Probe, Some(hw_address)) => Resolution::Send(packet, hw_address),
+
+            (NeighborState::Stale, Some(hw_address)) => {
+                neighbor.set_state(NeighborState::Probe, now);
+                neighbor.solicits = 1;
+                neighbor.next_solicit = now + self.retrans_time;
+
+                Resolution::SendAndProbe(packet, hw_address)
+            }
+
+            _ => {
+                if neighbor.pending.len() >= self.backlog_size {
+                    return Resolution::Full(packet);
+                }
+                neighbor.pending.push_back(packet);
+
+                if neighbor.state == NeighborState::Incomplete && neighbor.solicits > 0 {
+                    return Resolution::Queued;
+                }
+
+                neighbor.set_state(NeighborState::Incomplete, now);
+                neighbor.hw_address = None;
+                neighbor.solicits = 1;
+                neighbor.next_solicit = now + self.retrans_time;
+
+                Resolution::Solicit
+            }
+        }
+    }
+
+    /// Records that `addr` has been confirmed to be reachable at `hw_address` and returns the
+    /// packets that were waiting for it.
+    pub fn confirm(&mut self, addr: A, hw_address: [u8; 6], now: Instant) -> VecDeque<P> {
+        if !self.entries.contains_key(&addr) {
+            if !self.make_room() {
+                return VecDeque::new();
+            }
+
+            self.entries.insert(addr, Neighbor::new(now));
+        }
+
+        let neighbor = self.entries.get_mut(&addr).unwrap();
+
+        neighbor.set_state(NeighborState::Reachable, now);
+        neighbor.hw_address = Some(hw_address);
+        neighbor.solicits = 0;
+
+        neighbor.pending.split_off(0)
+    }
+
+    /// Records an unsolicited indication (e.g. an ARP request) that `addr` is at `hw_address`
+    /// and returns the packets that were waiting for it.
+    ///
+    /// A new entry is added only if `create` is true, otherwise only an existing entry is
+    /// updated (i.e. the RFC 826 merge rule). Entries whose link-layer address changes become
+    /// stale, so that they will be probed before being considered reachable again.
+    pub fn update(
+        &mut self,
+        addr: A,
+        hw_address: [u8; 6],
+        create: bool,
+        now: Instant,
+    ) -> VecDeque<P> {
+        if !self.entries.contains_key(&addr) {
+            if !create || !self.make_room() {
+                return VecDeque::new();
+            }
+
+            self.entries.insert(addr, Neighbor::new(now));
+        }
+
+        let neighbor = self.entries.get_mut(&addr).unwrap();
+
+        if neighbor.hw_address != Some(hw_address) {
+            neighbor.set_state(NeighborState::Stale, now);
+            neighbor.hw_address = Some(hw_address);
+            neighbor.solicits = 0;
+        }
+
+        neighbor.pending.split_off(0)
+    }
+
+    /// Runs the timers of the neighbor cache.
+    ///
+    /// Reachable neighbors which have not been confirmed for `reachable_time` become stale,
+    /// neighbors in the `Incomplete` and `Probe` states get a new solicitation every
+    /// `retrans_time` up to `max_solicits`, after which they become failed. Stale and failed
+    /// neighbors are removed after `stale_time`.
+    pub fn expire(&mut self, now: Instant) -> Expired<A, P> {
+        let mut expired = Expired {
+            solicit: Vec::new(),
+            failed:  Vec::new(),
+        };
+
+        let max_solicits = self.max_solicits;
+        let retrans_time = self.retrans_time;
+        let reachable_time = self.reachable_time;
+        let stale_time = self.stale_time;
+
+        self.entries.retain(|addr, neighbor| match neighbor.state {
+            NeighborState::Reachable => {
+                if now >= neighbor.updated + reachable_time {
+                    neighbor.set_state(NeighborState::Stale, now);
+                }
+
+                true
+            }
+
+            NeighborState::Stale | NeighborState::Failed => now < neighbor.updated + stale_time,
+
+            NeighborState::Incomplete | NeighborState::Probe => {
+                if neighbor.next_solicit > now {
+                    return true;
+                }
+
+                if neighbor.solicits >= max_solicits {
+                    expired.failed.extend(neighbor.pending.drain(..));
+
+                    neighbor.set_state(NeighborState::Failed, now);
+                    neighbor.hw_address = None;
+
+                    return true;
+                }
+
+                neighbor.solicits += 1;
+                neighbor.next_solicit = now + retrans_time;
+                expired.solicit.push((*addr, neighbor.hw_address));
+
+                true
+            }
+        });
+
+        expired
+    }
+
+    /// Makes room for a new entry, evicting the least recently used one if the cache is full.
+    ///
+    /// Incomplete entries are never evicted, as they hold packets waiting for a resolution.
+    fn make_room(&mut self) -> bool {
+        if self.entries.len() < self.max_entries {
+            return true;
+        }
+
+        let lru = self
+            .entries
+            .iter()
+            .filter(|(_, n)| n.state != NeighborState::Incomplete)
+            .min_by_key(|(_, n)| n.used)
+            .map(|(addr, _)| *addr);
+
+        match lru {
+            Some(addr) => {
+                self.entries.remove(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0, 1, 2, 3, 4, 5];
+
+    fn new_cache() -> NeighborCache<u32, usize> {
+        let mut cfg = Configuration::default();
+        cfg.set_neigh_backlog_size(2)
+            .set_neigh_retrans_time(Duration::from_secs(1))
+            .set_neigh_max_solicits(3)
+            .set_neigh_reachable_time(Duration::from_secs(30))
+            .set_neigh_stale_time(Duration::from_secs(60))
+            .set_neigh_max_entries(2);
+
+        NeighborCache::new(&cfg)
+    }
+
+    #[test]
+    fn test_resolve_and_confirm() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        assert!(cache.hw_address(&1).is_none());
+        assert!(matches!(cache.resolve(1, 10, now), Resolution::Solicit));
+        assert!(matches!(cache.resolve(1, 11, now), Resolution::Queued));
+        assert!(matches!(cache.resolve(1, 12, now), Resolution::Full(12)));
+        assert_eq!(cache.state(&1), Some(NeighborState::Incomplete));
+
+        let pending = cache.confirm(1, MAC, now);
+        assert_eq!(pending, vec![10, 11]);
+        assert_eq!(cache.hw_address(&1), Some(MAC));
+        assert_eq!(cache.state(&1), Some(NeighborState::Reachable));
+
+        assert!(matches!(
+            cache.resolve(1, 13, now),
+            Resolution::Send(13, MAC)
+        ));
+    }
+
+    #[test]
+    fn test_incomplete_to_failed() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        cache.resolve(1, 10, now);
+
+        let expired = cache.expire(now);
+        assert!(expired.solicit.is_empty());
+
+        for i in 1..3 {
+            let expired = cache.expire(now + Duration::from_secs(i));
+            assert_eq!(expired.solicit, vec![(1, None)]);
+            assert!(expired.failed.is_empty());
+        }
+
+        let expired = cache.expire(now + Duration::from_secs(3));
+        assert!(expired.solicit.is_empty());
+        assert_eq!(expired.failed, vec![10]);
+        assert_eq!(cache.state(&1), Some(NeighborState::Failed));
+
+        // A failed neighbor is solicited again the next time it is used
+        let now = now + Duration::from_secs(4);
+        assert!(matches!(cache.resolve(1, 11, now), Resolution::Solicit));
+
+        // and removed if it is not used anymore
+        for i in 1..4 {
+            cache.expire(now + Duration::from_secs(i));
+        }
+        assert_eq!(cache.state(&1), Some(NeighborState::Failed));
+
+        cache.expire(now + Duration::from_secs(70));
+        assert!(cache.state(&1).is_none());
+    }
+
+    #[test]
+    fn test_reachable_to_stale_to_probe() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        cache.confirm(1, MAC, now);
+
+        let now = now + Duration::from_secs(30);
+        cache.expire(now);
+        assert_eq!(cache.state(&1), Some(NeighborState::Stale));
+
+        assert!(matches!(
+            cache.resolve(1, 10, now),
+            Resolution::SendAndProbe(10, MAC)
+        ));
+        assert_eq!(cache.state(&1), Some(NeighborState::Probe));
+
+        // Probe retransmissions are unicast
+        let expired = cache.expire(now + Duration::from_secs(1));
+        assert_eq!(expired.solicit, vec![(1, Some(MAC))]);
+
+        cache.expire(now + Duration::from_secs(2));
+        cache.expire(now + Duration::from_secs(3));
+        assert_eq!(cache.state(&1), Some(NeighborState::Failed));
+        assert!(cache.hw_address(&1).is_none());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        // Without `create` only existing entries are updated
+        cache.update(1, MAC, false, now);
+        assert!(cache.state(&1).is_none());
+
+        cache.resolve(1, 10, now);
+        assert_eq!(cache.update(1, MAC, false, now), vec![10]);
+        assert_eq!(cache.state(&1), Some(NeighborState::Stale));
+
+        cache.confirm(1, MAC, now);
+        cache.update(1, MAC, false, now);
+        assert_eq!(cache.state(&1), Some(NeighborState::Reachable));
+
+        // A new link-layer address makes the entry stale
+        let new_mac = [5, 4, 3, 2, 1, 0];
+        cache.update(1, new_mac, false, now);
+        assert_eq!(cache.state(&1), Some(NeighborState::Stale));
+        assert_eq!(cache.hw_address(&1), Some(new_mac));
+
+        cache.update(2, MAC, true, now);
+        assert_eq!(cache.state(&2), Some(NeighborState::Stale));
+    }
+
+    #[test]
+    fn test_backlog_room() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        assert_eq!(cache.backlog_room(&1), 2);
+
+        cache.resolve(1, 10, now);
+        assert_eq!(cache.backlog_room(&1), 1);
+
+        cache.resolve(1, 11, now);
+        assert_eq!(cache.backlog_room(&1), 0);
+
+        cache.confirm(1, MAC, now);
+        assert_eq!(cache.backlog_room(&1), usize::MAX);
+    }
+
+    #[test]
+    fn test_is_resolved() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        assert!(!cache.is_resolved(&1));
+
+        cache.resolve(1, 10, now);
+        assert!(!cache.is_resolved(&1));
+
+        cache.confirm(1, MAC, now);
+        assert!(cache.is_resolved(&1));
+
+        cache.expire(now + Duration::from_secs(30));
+        assert_eq!(cache.state(&1), Some(NeighborState::Stale));
+        assert!(cache.is_resolved(&1));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = new_cache();
+        let now = Instant::now();
+
+        cache.confirm(1, MAC, now);
+        cache.confirm(2, MAC, now);
+
+        cache.resolve(1, 10, now + Duration::from_secs(1));
+        cache.confirm(3, MAC, now + Duration::from_secs(2));
+
+        assert!(cache.state(&1).is_some());
+        assert!(cache.state(&2).is_none());
+        assert!(cache.state(&3).is_some());
+
+        // Incomplete entries are never evicted
+        let mut cache = new_cache();
+        cache.resolve(1, 10, now);
+        cache.resolve(2, 11, now);
+        assert!(matches!(cache.resolve(3, 12, now), Resolution::Full(12)));
+    }
+}
@@ -15,13 +15,28 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::mem;
+use std::{
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Instant,
+};
 
 use crate::{
     net,
-    net::{app::Socket, ArpHdr, EthHdr, Ip4Hdr, Packet, PacketBufMut, UdpHdr},
+    net::{
+        app::Socket, icmp6, ip6, Announcement, ArpHdr, EthHdr, EthType, Icmp6Hdr, Icmp6UnreachCode,
+        IcmpHdr, IcmpUnreachCode, Ip4Hdr, Ip6Hdr, IpFlags, IpProto, NaFlags, NdOption, Packet,
+        PacketBufMut, Resolution, UdpHdr, VlanHdr, VlanTags, ICMP6_ERROR_MAX_QUOTE_LEN,
+        ICMP_ERROR_MAX_QUOTE_LEN, ND_HOP_LIMIT, ND_HW_ADDRESS_OPTION_LEN,
+    },
+    xsk,
 };
 
+const ETH_BROADCAST: [u8; 6] = [0xff; 6];
+
+/// Maximum length of the payload of an IPv4 datagram.
+const IP4_MAX_PAYLOAD_LEN: usize = 65535 - mem::size_of::<Ip4Hdr>();
+
 impl net::Net {
     pub fn send_arp_reply(&mut self, rx_packet: &Packet<'_>) -> anyhow::Result<()> {
         let netstack = self.netstack.write().unwrap();
@@ -38,10 +53,9 @@ impl net::Net {
         };
 
         let rx_eth = rx_packet.eth_hdr.as_ref().unwrap();
-        EthHdr::from_packet_buf(&mut packet_buf)?
-            .set_src_address(netstack.iface_mac)
-            .set_dst_address(rx_eth.src_address)
-            .arp();
+        netstack
+            .write_eth_hdr(&mut packet_buf, &rx_packet.vlan, EthType::ARP)?
+            .set_dst_address(rx_eth.src_address);
 
         let rx_arp = rx_packet.arp_hdr.as_ref().unwrap();
         ArpHdr::from_packet_buf(&mut packet_buf)?
@@ -52,15 +66,421 @@ impl net::Net {
             .set_target_proto_address(rx_arp.sender_proto_addr);
 
         tx_desc.set_len(packet_buf.as_slice().len());
-        netstack.xsk_handle.write().unwrap().tx(&tx_desc)?;
+        netstack.xsk_handle.write().unwrap().tx(tx_desc)?;
+
+        Ok(())
+    }
+}
+
+impl net::NetStack {
+    /// Write the Ethernet header of a frame sent from the interface, followed by the `vlan` tags,
+    /// leaving `packet_buf` at the offset of the `eth_type` payload.
+    ///
+    /// The destination address is left to the caller.
+    pub fn write_eth_hdr<'a>(
+        &self,
+        packet_buf: &mut PacketBufMut<'a>,
+        vlan: &VlanTags,
+        eth_type: EthType,
+    ) -> net::Result<&'a mut EthHdr> {
+        let eth_type = eth_type as u16;
+        let tags = vlan.tags();
+
+        // Each header holds the type of the following one
+        let eth = EthHdr::with_packet_buf(packet_buf)?;
+        eth.set_src_address(self.iface_mac)
+            .set_eth_type(tags.first().map_or(eth_type, |tag| tag.tpid));
+
+        for (i, tag) in tags.iter().enumerate() {
+            VlanHdr::with_packet_buf(packet_buf)?
+                .set_tci(tag.tci)
+                .set_eth_type(tags.get(i + 1).map_or(eth_type, |tag| tag.tpid));
+        }
+
+        Ok(eth)
+    }
+
+    /// Send an ARP request for `target` on `vlan`, either broadcast or unicast to
+    /// `dst_hw_address` when probing a stale neighbor.
+    pub fn send_arp_request(
+        &mut self,
+        vlan: &VlanTags,
+        target: Ipv4Addr,
+        dst_hw_address: Option<[u8; 6]>,
+    ) -> anyhow::Result<()> {
+        let sender = self.link_address(vlan.vlan())?;
+
+        self.send_arp(vlan, sender, target, dst_hw_address)?;
+        self.stats.arp_requests_sent += 1;
+
+        Ok(())
+    }
+
+    /// Send an ARP probe (RFC 5227) to check whether the bind address is already in use.
+    pub fn send_arp_probe(&mut self) -> anyhow::Result<()> {
+        self.send_arp(
+            &VlanTags::default(),
+            Ipv4Addr::UNSPECIFIED,
+            self.bind_address,
+            None,
+        )?;
+        self.stats.arp_probes_sent += 1;
+
+        Ok(())
+    }
+
+    /// Send a gratuitous ARP announcing the bind address.
+    pub fn send_arp_announcement(&mut self) -> anyhow::Result<()> {
+        self.send_arp(
+            &VlanTags::default(),
+            self.bind_address,
+            self.bind_address,
+            None,
+        )?;
+        self.stats.arp_announcements_sent += 1;
+
+        Ok(())
+    }
+
+    fn send_arp(
+        &mut self,
+        vlan: &VlanTags,
+        sender: Ipv4Addr,
+        target: Ipv4Addr,
+        dst_hw_address: Option<[u8; 6]>,
+    ) -> anyhow::Result<()> {
+        let (mut tx_desc, mut packet_buf) = {
+            let mut xsk_handle = self.xsk_handle.write().unwrap();
+
+            let tx_desc = xsk_handle.next_tx_slot()?;
+            let packet_buf = PacketBufMut::from_raw_parts(
+                tx_desc.packet(),
+                xsk_handle.configuration().frame_size(),
+            );
+
+            (tx_desc, packet_buf)
+        };
+
+        self.write_eth_hdr(&mut packet_buf, vlan, EthType::ARP)?
+            .set_dst_address(dst_hw_address.unwrap_or(ETH_BROADCAST));
+
+        ArpHdr::from_packet_buf(&mut packet_buf)?
+            .arp_request_ip()
+            .set_sender_hw_address(self.iface_mac)
+            .set_sender_proto_address(sender.octets())
+            .set_target_hw_address([0; 6])
+            .set_target_proto_address(target.octets());
+
+        tx_desc.set_len(packet_buf.as_slice().len());
+        self.xsk_handle.write().unwrap().tx(tx_desc)?;
+
+        Ok(())
+    }
+
+    /// Send the ARP probes and announcements of the bind address which are due.
+    pub fn run_announcer(&mut self, now: Instant) -> anyhow::Result<()> {
+        while let Some(announcement) = self.announcer.poll(now) {
+            match announcement {
+                Announcement::Probe => self.send_arp_probe()?,
+                Announcement::Announce => self.send_arp_announcement()?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer an ICMP echo request from `dst_address` to `src_address`, echoing back its
+    /// identifier and sequence number (`rest`, in network byte order) and its data.
+    pub fn send_icmp_echo_reply(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv4Addr,
+        dst_address: Ipv4Addr,
+        rest: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        self.send_icmp(vlan, src_address, dst_address, data, |icmp| {
+            icmp.echo_reply().set_rest(rest);
+        })?;
+        self.stats.icmp_echo_replies_sent += 1;
+
+        Ok(())
+    }
+
+    /// Send an ICMP destination unreachable message to `dst_address`, quoting as much of the
+    /// offending `datagram` as RFC 1812 allows.
+    pub fn send_icmp_dest_unreachable(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv4Addr,
+        dst_address: Ipv4Addr,
+        code: IcmpUnreachCode,
+        datagram: &[u8],
+    ) -> anyhow::Result<()> {
+        let quote_len = datagram.len().min(ICMP_ERROR_MAX_QUOTE_LEN);
+
+        self.send_icmp(
+            vlan,
+            src_address,
+            dst_address,
+            &datagram[..quote_len],
+            |icmp| {
+                icmp.dest_unreachable(code);
+            },
+        )?;
+        self.stats.icmp_unreachable_sent += 1;
+
+        Ok(())
+    }
+
+    fn send_icmp<F>(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv4Addr,
+        dst_address: Ipv4Addr,
+        data: &[u8],
+        set_hdr: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut IcmpHdr),
+    {
+        let (mut tx_desc, mut packet_buf) = {
+            let mut xsk_handle = self.xsk_handle.write().unwrap();
+
+            let tx_desc = xsk_handle.next_tx_slot()?;
+            let packet_buf = PacketBufMut::from_raw_parts(
+                tx_desc.packet(),
+                xsk_handle.configuration().frame_size(),
+            );
+
+            (tx_desc, packet_buf)
+        };
+
+        // The destination address is set by `ip4_output` once the neighbor is resolved
+        self.write_eth_hdr(&mut packet_buf, vlan, EthType::IP4)?;
+
+        Ip4Hdr::with_packet_buf(&mut packet_buf)?
+            .set_total_length(
+                (mem::size_of::<Ip4Hdr>() + mem::size_of::<IcmpHdr>() + data.len()) as u16,
+            )
+            .icmp()
+            .set_src_address(src_address)
+            .set_dst_address(dst_address)
+            .calc_checksum();
+
+        let icmp = IcmpHdr::with_packet_buf(&mut packet_buf)?;
+        packet_buf.get_bytes_mut(data.len())?.copy_from_slice(data);
+
+        set_hdr(icmp);
+        icmp.calc_checksum(data);
+
+        tx_desc.set_len(packet_buf.as_slice().len());
+
+        self.ip4_output(vlan, dst_address, tx_desc)
+    }
+
+    /// Transmit the IPv4 frame in `desc`, tagged with `vlan`, to `dst_address`.
+    ///
+    /// If the link-layer address of `dst_address` is not known yet, the frame is queued and an
+    /// ARP request is sent.
+    ///
+    /// The routing table only applies to the untagged network: hosts on a VLAN are always
+    /// reached directly.
+    pub fn ip4_output(
+        &mut self,
+        vlan: &VlanTags,
+        dst_address: Ipv4Addr,
+        desc: xsk::Desc,
+    ) -> anyhow::Result<()> {
+        let next_hop = match self.ip4_next_hop(vlan, dst_address) {
+            Some(next_hop) => next_hop,
+            None => return Err(self.no_route(IpAddr::V4(dst_address), desc).into()),
+        };
+
+        match self
+            .arp_table
+            .resolve((vlan.vlan(), next_hop), desc, Instant::now())
+        {
+            Resolution::Send(desc, hw_address) => self.eth_output(hw_address, desc)?,
+            Resolution::SendAndProbe(desc, hw_address) => {
+                self.eth_output(hw_address, desc)?;
+                self.send_arp_request(vlan, next_hop, Some(hw_address))?;
+            }
+            Resolution::Solicit => self.send_arp_request(vlan, next_hop, None)?,
+            Resolution::Queued => {}
+            Resolution::Full(_desc) => {
+                // Dropping the frame releases its descriptor
+                self.stats.neigh_backlog_full += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next hop toward `dst_address` on `vlan`, if any.
+    fn ip4_next_hop(&self, vlan: &VlanTags, dst_address: Ipv4Addr) -> Option<Ipv4Addr> {
+        match vlan.vlan() {
+            Some(_) => Some(dst_address),
+            None => match self.routes.next_hop(IpAddr::V4(dst_address)) {
+                Some(IpAddr::V4(next_hop)) => Some(next_hop),
+                _ => None,
+            },
+        }
+    }
+
+    /// Drop the frame in `desc`, whose destination is not reachable.
+    fn no_route(&mut self, dst_address: IpAddr, desc: xsk::Desc) -> net::Error {
+        self.stats.no_route += 1;
+        drop(desc);
+
+        net::Error::NoRoute(dst_address)
+    }
+
+    /// Set the destination address of the Ethernet frame in `desc` and transmit it.
+    pub fn eth_output(&mut self, dst_hw_address: [u8; 6], desc: xsk::Desc) -> anyhow::Result<()> {
+        let mut packet_buf = PacketBufMut::from_raw_parts(desc.packet(), desc.len());
+        EthHdr::from_packet_buf(&mut packet_buf)?.set_dst_address(dst_hw_address);
+
+        self.xsk_handle.write().unwrap().tx(desc)?;
+
+        Ok(())
+    }
+
+    /// Record that a neighbor on `vlan` is reachable at `hw_address`, flushing the frames waiting
+    /// for it.
+    pub fn update_neighbor(
+        &mut self,
+        vlan: Option<xsk::Vlan>,
+        address: Ipv4Addr,
+        hw_address: [u8; 6],
+    ) -> anyhow::Result<()> {
+        for desc in self
+            .arp_table
+            .confirm((vlan, address), hw_address, Instant::now())
+        {
+            self.eth_output(hw_address, desc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record an unsolicited indication that a neighbor on `vlan` is at `hw_address`, flushing
+    /// the frames waiting for it. A new entry is created only if `create` is true.
+    pub fn merge_neighbor(
+        &mut self,
+        vlan: Option<xsk::Vlan>,
+        address: Ipv4Addr,
+        hw_address: [u8; 6],
+        create: bool,
+    ) -> anyhow::Result<()> {
+        for desc in self
+            .arp_table
+            .update((vlan, address), hw_address, create, Instant::now())
+        {
+            self.eth_output(hw_address, desc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the ARP and Neighbor Discovery cache timers, sending the due solicitations and
+    /// dropping the frames whose neighbor could not be resolved.
+    pub fn expire_neighbors(&mut self, now: Instant) -> anyhow::Result<()> {
+        let expired = self.arp_table.expire(now);
+
+        // Dropping the frames releases their descriptors
+        self.stats.neigh_unresolved += expired.failed.len() as u64;
+
+        for ((vlan, address), hw_address) in expired.solicit {
+            self.send_arp_request(&VlanTags::new(vlan), address, hw_address)?;
+        }
+
+        let expired = self.nd_table.expire(now);
+
+        self.stats.neigh_unresolved += expired.failed.len() as u64;
+
+        for ((vlan, address), hw_address) in expired.solicit {
+            self.send_neighbor_solicitation(&VlanTags::new(vlan), address, hw_address)?;
+        }
 
         Ok(())
     }
 }
 
 impl net::app::Handle for net::NetStack {
-    /// Return a new `net::app::PayloadBuf` object.
-    fn new_tx_payload_buf<'a>(&mut self) -> anyhow::Result<net::app::PayloadBuf<'a>> {
+    fn new_socket(&self, remote: SocketAddr, local_port: u16) -> anyhow::Result<Socket> {
+        let address = remote.ip();
+        if address.is_unspecified() || remote.port() == 0 {
+            return Err(net::Error::InvalidDestination(remote).into());
+        }
+
+        // Check the route now, rather than failing once the first payload is sent. IPv6
+        // multicast destinations are always reached directly
+        let multicast6 = matches!(address, IpAddr::V6(a) if a.is_multicast());
+        if !multicast6 && self.routes.next_hop(address).is_none() {
+            return Err(net::Error::NoRoute(address).into());
+        }
+
+        let local_address = match address {
+            IpAddr::V4(_) => IpAddr::V4(self.link_address(None)?),
+            IpAddr::V6(_) => IpAddr::V6(self.link_address6(None)?),
+        };
+
+        // Replies are delivered to the app registered for the port, or else to the default app
+        let has_app = self.configuration.app_allocator().is_some()
+            || self
+                .configuration
+                .app_allocators()
+                .iter()
+                .any(|&(address, port, _)| {
+                    port == local_port && address.is_none_or(|a| a == local_address)
+                });
+        if !has_app {
+            return Err(net::Error::NoApp(local_port).into());
+        }
+
+        // Replies are only redirected to the stack for its listeners
+        self.add_listener(Some(local_address), local_port)?;
+
+        Ok(Socket {
+            source_address: address,
+            source_port: remote.port(),
+            local_address,
+            local_port,
+            dont_fragment: self.configuration.ip4_dont_fragment(),
+            vlan: VlanTags::new(None),
+        })
+    }
+
+    /// Return a new `net::app::PayloadBuf` object, which will be sent to `socket`.
+    fn new_tx_payload_buf<'a>(
+        &mut self,
+        socket: &Socket,
+        len: usize,
+    ) -> anyhow::Result<net::app::PayloadBuf<'a>> {
+        let ip_hdr_len = match socket.source_address {
+            IpAddr::V4(_) => mem::size_of::<Ip4Hdr>(),
+            IpAddr::V6(_) => mem::size_of::<Ip6Hdr>(),
+        };
+        let l2_hdr_len = mem::size_of::<EthHdr>() + socket.vlan.hdr_len();
+        let hdrs_len = l2_hdr_len + ip_hdr_len + mem::size_of::<UdpHdr>();
+
+        if len > self.max_payload_len(socket) {
+            // Only IPv4 payloads can be fragmented
+            if socket.dont_fragment
+                || socket.source_address.is_ipv6()
+                || mem::size_of::<UdpHdr>() + len > IP4_MAX_PAYLOAD_LEN
+            {
+                return Err(net::Error::MessageTooLong.into());
+            }
+
+            let mut payload_buf =
+                net::app::PayloadBuf::with_len(hdrs_len + len, self.configuration.tx_options());
+            payload_buf.packet_buf().seek(hdrs_len)?;
+
+            return Ok(payload_buf);
+        }
+
         let mut xsk_handle = self.xsk_handle.write().unwrap();
 
         // Get a new TX descriptor from XSK
@@ -74,10 +494,14 @@ impl net::app::Handle for net::NetStack {
 
         // Seek packet_buf to the offset of the L4 payload, so that the app will be able to write
         // the payload data to the correct offset.
-        packet_buf
-            .seek(mem::size_of::<EthHdr>() + mem::size_of::<Ip4Hdr>() + mem::size_of::<UdpHdr>())?;
+        packet_buf.truncate(l2_hdr_len + self.path_mtu(socket.source_address));
+        packet_buf.seek(hdrs_len)?;
 
-        Ok(net::app::PayloadBuf::new(xdp_desc, packet_buf))
+        Ok(net::app::PayloadBuf::new(
+            xdp_desc,
+            packet_buf,
+            self.configuration.tx_options(),
+        ))
     }
 
     fn send_payload(
@@ -85,34 +509,523 @@ impl net::app::Handle for net::NetStack {
         socket: &Socket,
         payload_buf: &mut net::app::PayloadBuf,
     ) -> anyhow::Result<()> {
+        // Replies are sourced from the address and port the socket received datagrams on
+        match (socket.local_address, socket.source_address) {
+            (IpAddr::V4(src_address), IpAddr::V4(dst_address)) => {
+                self.send_payload4(src_address, dst_address, socket, payload_buf)
+            }
+            (IpAddr::V6(src_address), IpAddr::V6(dst_address)) => {
+                self.send_payload6(src_address, dst_address, socket, payload_buf)
+            }
+            _ => Err(net::Error::AddressFamilyMismatch.into()),
+        }
+    }
+
+    fn send_rx_payload(&mut self, socket: &Socket, len: usize) -> anyhow::Result<()> {
+        let rx_frame = self.rx_frame.take().ok_or(net::Error::NoRxFrame)?;
+
+        // A reply waiting in the neighbor backlog would outlive the received frame, which goes
+        // back to the fill ring once the RX batch is processed
+        if !self.next_hop_resolved(&socket.vlan, socket.source_address) {
+            return Err(net::Error::NoRxFrame.into());
+        }
+
+        if len > rx_frame.payload_len || len > self.max_payload_len(socket) {
+            return Err(net::Error::MessageTooLong.into());
+        }
+
+        let ip_hdr_len = match socket.source_address {
+            IpAddr::V4(_) => mem::size_of::<Ip4Hdr>(),
+            IpAddr::V6(_) => mem::size_of::<Ip6Hdr>(),
+        };
+        let l2_hdr_len = mem::size_of::<EthHdr>() + socket.vlan.hdr_len();
+        let hdrs_len = l2_hdr_len + ip_hdr_len + mem::size_of::<UdpHdr>();
+
+        // The headers of the reply end where the payload starts: they are shorter than the
+        // received ones if those had IP options or extension headers
+        let offset = rx_frame
+            .payload_offset
+            .checked_sub(hdrs_len)
+            .ok_or(net::Error::NotEnoughBytes)?;
+
+        let xdp_desc = self.xsk_handle.write().unwrap().rx_to_tx_slot(
+            rx_frame.addr,
+            offset,
+            hdrs_len + len,
+        )?;
+
+        let mut packet_buf = PacketBufMut::from_raw_parts(xdp_desc.packet(), hdrs_len + len);
+        packet_buf.seek(hdrs_len + len)?;
+
+        let mut payload_buf =
+            net::app::PayloadBuf::new(xdp_desc, packet_buf, self.configuration.tx_options());
+
+        self.send_payload(socket, &mut payload_buf)
+    }
+
+    fn max_payload_len(&self, socket: &Socket) -> usize {
+        let ip_hdr_len = match socket.source_address {
+            IpAddr::V4(_) => mem::size_of::<Ip4Hdr>(),
+            IpAddr::V6(_) => mem::size_of::<Ip6Hdr>(),
+        };
+
+        self.path_mtu(socket.source_address) - ip_hdr_len - mem::size_of::<UdpHdr>()
+    }
+}
+
+impl net::NetStack {
+    fn send_payload4(
+        &mut self,
+        src_address: Ipv4Addr,
+        dst_address: Ipv4Addr,
+        socket: &Socket,
+        payload_buf: &mut net::app::PayloadBuf,
+    ) -> anyhow::Result<()> {
+        let vlan = &socket.vlan;
+
         // Set the position of the packet buffer back to 0 so that we can write the L2, L3 and L4 headers
         payload_buf.packet_buf().seek(0)?;
         let packet_len = payload_buf.packet_buf().as_slice().len();
+        let l2_hdr_len = mem::size_of::<EthHdr>() + vlan.hdr_len();
+
+        // The destination address is set by `ip4_output` once the neighbor is resolved
+        self.write_eth_hdr(payload_buf.packet_buf(), vlan, EthType::IP4)?;
+
+        let udp_len = (packet_len - l2_hdr_len - mem::size_of::<Ip4Hdr>()) as u16;
 
-        EthHdr::with_packet_buf(payload_buf.packet_buf())?
-            .set_src_address(self.iface_mac)
-            // TODO: handle missing ARP entry
-            .set_dst_address(*self.arp_table.get(&socket.source_address).unwrap())
-            .ip4();
+        // Payloads without a TX descriptor are fragmented, and so are not atomic
+        let tx_options = *payload_buf.tx_options();
+        let atomic = socket.dont_fragment && payload_buf.xdp_desc().is_some();
+        let id = self.ip4_ids.next(tx_options.ip_id, dst_address, atomic);
 
-        Ip4Hdr::with_packet_buf(payload_buf.packet_buf())?
-            .set_total_length((packet_len - std::mem::size_of::<EthHdr>()) as u16)
+        let ip4 = Ip4Hdr::with_packet_buf(payload_buf.packet_buf())?;
+        ip4.set_total_length((packet_len - l2_hdr_len) as u16)
+            .set_tos(tx_options.tos())
+            .set_id(id)
+            .set_ttl(tx_options.ttl)
             .udp()
-            .set_src_address(self.bind_address)
-            .set_dst_address(socket.source_address)
-            .calc_checksum();
+            .set_src_address(src_address)
+            .set_dst_address(dst_address);
+
+        if !socket.dont_fragment {
+            ip4.set_flags(0);
+        }
+        ip4.calc_checksum();
+
+        let udp = UdpHdr::with_packet_buf(payload_buf.packet_buf())?;
+        udp.set_src_port(socket.local_port)
+            .set_dst_port(socket.source_port)
+            .set_length(udp_len);
+
+        if self.configuration.ip4_udp_checksum() {
+            let payload_len = udp_len as usize - mem::size_of::<UdpHdr>();
+            let payload = payload_buf.packet_buf().peek_bytes(payload_len)?;
+            udp.calc_checksum(ip4.pseudo_header_sum(IpProto::UDP, udp_len), payload);
+        }
+
+        match payload_buf.take_xdp_desc() {
+            Some(mut xdp_desc) => {
+                xdp_desc.set_len(packet_len);
+                self.ip4_output(vlan, dst_address, xdp_desc)
+            }
+            None => {
+                self.ip4_fragment_output(vlan, dst_address, payload_buf.packet_buf().as_slice())
+            }
+        }
+    }
+
+    /// Send `packet`, an IPv4 packet (link-layer header included) larger than the MTU, as
+    /// several fragments (RFC 791), each in its own UMEM frame. The fragments keep the ID of
+    /// `packet`.
+    fn ip4_fragment_output(
+        &mut self,
+        vlan: &VlanTags,
+        dst_address: Ipv4Addr,
+        packet: &[u8],
+    ) -> anyhow::Result<()> {
+        let l2_hdr_len = mem::size_of::<EthHdr>() + vlan.hdr_len();
+        let (hdrs, payload) = packet.split_at(l2_hdr_len + mem::size_of::<Ip4Hdr>());
+
+        // The offset of each fragment is a multiple of 8 bytes
+        let mtu = self.path_mtu(IpAddr::V4(dst_address));
+        let max_fragment_len = (mtu - mem::size_of::<Ip4Hdr>()) & !7;
+
+        // Fragments dropped because the backlog of an unresolved neighbor is full would make
+        // the whole datagram lost, so it is rejected upfront
+        if let Some(next_hop) = self.ip4_next_hop(vlan, dst_address) {
+            let num_fragments = payload.len().div_ceil(max_fragment_len);
+            if num_fragments > self.arp_table.backlog_room(&(vlan.vlan(), next_hop)) {
+                self.stats.neigh_backlog_full += 1;
+                return Err(net::Error::NeighborBacklogFull.into());
+            }
+        }
+
+        for (i, fragment) in payload.chunks(max_fragment_len).enumerate() {
+            let offset = i * max_fragment_len;
+            let more_fragments = offset + fragment.len() < payload.len();
+
+            let (mut tx_desc, mut packet_buf) = {
+                let mut xsk_handle = self.xsk_handle.write().unwrap();
+
+                let tx_desc = xsk_handle.next_tx_slot()?;
+                let packet_buf = PacketBufMut::from_raw_parts(
+                    tx_desc.packet(),
+                    xsk_handle.configuration().frame_size(),
+                );
+
+                (tx_desc, packet_buf)
+            };
+
+            packet_buf.get_bytes_mut(hdrs.len())?.copy_from_slice(hdrs);
+            packet_buf
+                .get_bytes_mut(fragment.len())?
+                .copy_from_slice(fragment);
+            tx_desc.set_len(packet_buf.as_slice().len());
+
+            packet_buf.seek(l2_hdr_len)?;
+            let ip4 = Ip4Hdr::from_packet_buf(&mut packet_buf)?;
+            ip4.set_total_length((mem::size_of::<Ip4Hdr>() + fragment.len()) as u16)
+                .set_flags(if more_fragments {
+                    IpFlags::MoreFragment as u8
+                } else {
+                    0
+                })
+                .set_frag_offset((offset / 8) as u16)
+                .calc_checksum();
+
+            self.ip4_output(vlan, dst_address, tx_desc)?;
+            self.stats.ip4_frags_sent += 1;
+        }
+
+        Ok(())
+    }
+
+    fn send_payload6(
+        &mut self,
+        src_address: Ipv6Addr,
+        dst_address: Ipv6Addr,
+        socket: &Socket,
+        payload_buf: &mut net::app::PayloadBuf,
+    ) -> anyhow::Result<()> {
+        let vlan = &socket.vlan;
 
-        UdpHdr::with_packet_buf(payload_buf.packet_buf())?
-            .set_src_port(self.bind_port)
+        // Set the position of the packet buffer back to 0 so that we can write the L2, L3 and L4 headers
+        payload_buf.packet_buf().seek(0)?;
+        let packet_len = payload_buf.packet_buf().as_slice().len();
+        let l2_hdr_len = mem::size_of::<EthHdr>() + vlan.hdr_len();
+        let udp_len = (packet_len - l2_hdr_len - mem::size_of::<Ip6Hdr>()) as u16;
+
+        // The destination address is set by `ip6_output` once the neighbor is resolved
+        self.write_eth_hdr(payload_buf.packet_buf(), vlan, EthType::IP6)?;
+
+        let tx_options = *payload_buf.tx_options();
+
+        let ip6 = Ip6Hdr::with_packet_buf(payload_buf.packet_buf())?;
+        ip6.set_payload_length(udp_len)
+            .set_traffic_class(tx_options.tos())
+            .set_hop_limit(tx_options.ttl)
+            .udp()
+            .set_src_address(src_address)
+            .set_dst_address(dst_address);
+
+        let udp = UdpHdr::with_packet_buf(payload_buf.packet_buf())?;
+        udp.set_src_port(socket.local_port)
             .set_dst_port(socket.source_port)
-            .set_length((packet_len - mem::size_of::<EthHdr>() - mem::size_of::<Ip4Hdr>()) as u16);
+            .set_length(udp_len);
+
+        // The checksum is mandatory for UDP over IPv6
+        let payload_len = udp_len as usize - mem::size_of::<UdpHdr>();
+        let payload = payload_buf.packet_buf().peek_bytes(payload_len)?;
+        udp.calc_checksum(ip6.pseudo_header_sum(IpProto::UDP, udp_len as u32), payload);
+
+        // IPv6 payloads are never fragmented, so they always fit in a frame
+        let mut xdp_desc = payload_buf
+            .take_xdp_desc()
+            .ok_or(net::Error::MessageTooLong)?;
+        xdp_desc.set_len(packet_len);
+
+        self.ip6_output(vlan, dst_address, xdp_desc)
+    }
+
+    /// Send a Neighbor Solicitation for `target` on `vlan`, either to its solicited-node
+    /// multicast address or unicast to `dst_hw_address` when probing a stale neighbor.
+    pub fn send_neighbor_solicitation(
+        &mut self,
+        vlan: &VlanTags,
+        target: Ipv6Addr,
+        dst_hw_address: Option<[u8; 6]>,
+    ) -> anyhow::Result<()> {
+        let src_address = self.link_address6(vlan.vlan())?;
 
-        payload_buf.xdp_desc().set_len(packet_len);
+        let (dst_address, dst_hw_address) = match dst_hw_address {
+            Some(dst_hw_address) => (target, dst_hw_address),
+            None => {
+                let dst_address = ip6::solicited_node_address(target);
+                (dst_address, ip6::multicast_hw_address(dst_address))
+            }
+        };
+
+        let mut body = [0; 16 + ND_HW_ADDRESS_OPTION_LEN];
+        body[..16].copy_from_slice(&target.octets());
+        icmp6::write_nd_hw_address_option(
+            &mut PacketBufMut::from_slice(&mut body[16..]),
+            NdOption::SourceHwAddress,
+            self.iface_mac,
+        )?;
+
+        self.send_icmp6(
+            vlan,
+            src_address,
+            dst_address,
+            Some(dst_hw_address),
+            ND_HOP_LIMIT,
+            &body,
+            |icmp| {
+                icmp.neighbor_solicitation();
+            },
+        )?;
+        self.stats.nd_solicits_sent += 1;
+
+        Ok(())
+    }
+
+    /// Advertise `target`, one of the IPv6 addresses bound on `vlan`, to `dst_address`, whose
+    /// link-layer address is `dst_hw_address`.
+    pub fn send_neighbor_advertisement(
+        &mut self,
+        vlan: &VlanTags,
+        target: Ipv6Addr,
+        dst_address: Ipv6Addr,
+        dst_hw_address: [u8; 6],
+        solicited: bool,
+    ) -> anyhow::Result<()> {
+        let mut body = [0; 16 + ND_HW_ADDRESS_OPTION_LEN];
+        body[..16].copy_from_slice(&target.octets());
+        icmp6::write_nd_hw_address_option(
+            &mut PacketBufMut::from_slice(&mut body[16..]),
+            NdOption::TargetHwAddress,
+            self.iface_mac,
+        )?;
+
+        let mut flags = NaFlags::Override as u32;
+        if solicited {
+            flags |= NaFlags::Solicited as u32;
+        }
+
+        self.send_icmp6(
+            vlan,
+            target,
+            dst_address,
+            Some(dst_hw_address),
+            ND_HOP_LIMIT,
+            &body,
+            |icmp| {
+                icmp.neighbor_advertisement(flags);
+            },
+        )
+    }
+
+    /// Answer an ICMPv6 echo request from `dst_address` to `src_address`, echoing back its
+    /// identifier and sequence number (`rest`, in network byte order) and its data.
+    pub fn send_icmp6_echo_reply(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv6Addr,
+        dst_address: Ipv6Addr,
+        rest: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        self.send_icmp6(vlan, src_address, dst_address, None, 64, data, |icmp| {
+            icmp.echo_reply().set_rest(rest);
+        })?;
+        self.stats.icmp_echo_replies_sent += 1;
+
+        Ok(())
+    }
+
+    /// Send an ICMPv6 destination unreachable message to `dst_address`, quoting as much of the
+    /// offending `packet` as RFC 4443 allows.
+    pub fn send_icmp6_dest_unreachable(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv6Addr,
+        dst_address: Ipv6Addr,
+        code: Icmp6UnreachCode,
+        packet: &[u8],
+    ) -> anyhow::Result<()> {
+        let quote_len = packet.len().min(ICMP6_ERROR_MAX_QUOTE_LEN);
+
+        self.send_icmp6(
+            vlan,
+            src_address,
+            dst_address,
+            None,
+            64,
+            &packet[..quote_len],
+            |icmp| {
+                icmp.dest_unreachable(code);
+            },
+        )?;
+        self.stats.icmp_unreachable_sent += 1;
+
+        Ok(())
+    }
+
+    /// Send an ICMPv6 message to `dst_address`, either directly to `dst_hw_address` or through
+    /// neighbor resolution.
+    #[allow(clippy::too_many_arguments)]
+    fn send_icmp6<F>(
+        &mut self,
+        vlan: &VlanTags,
+        src_address: Ipv6Addr,
+        dst_address: Ipv6Addr,
+        dst_hw_address: Option<[u8; 6]>,
+        hop_limit: u8,
+        data: &[u8],
+        set_hdr: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Icmp6Hdr),
+    {
+        let (mut tx_desc, mut packet_buf) = {
+            let mut xsk_handle = self.xsk_handle.write().unwrap();
+
+            let tx_desc = xsk_handle.next_tx_slot()?;
+            let packet_buf = PacketBufMut::from_raw_parts(
+                tx_desc.packet(),
+                xsk_handle.configuration().frame_size(),
+            );
 
-        self.xsk_handle
-            .write()
-            .unwrap()
-            .tx(payload_buf.xdp_desc())?;
+            (tx_desc, packet_buf)
+        };
+
+        self.write_eth_hdr(&mut packet_buf, vlan, EthType::IP6)?;
+
+        let icmp_len = mem::size_of::<Icmp6Hdr>() + data.len();
+
+        let ip6 = Ip6Hdr::with_packet_buf(&mut packet_buf)?;
+        ip6.set_payload_length(icmp_len as u16)
+            .icmp6()
+            .set_hop_limit(hop_limit)
+            .set_src_address(src_address)
+            .set_dst_address(dst_address);
+
+        let icmp = Icmp6Hdr::with_packet_buf(&mut packet_buf)?;
+        packet_buf.get_bytes_mut(data.len())?.copy_from_slice(data);
+
+        set_hdr(icmp);
+        icmp.calc_checksum(
+            ip6.pseudo_header_sum(IpProto::ICMPV6, icmp_len as u32),
+            data,
+        );
+
+        tx_desc.set_len(packet_buf.as_slice().len());
+
+        match dst_hw_address {
+            Some(dst_hw_address) => self.eth_output(dst_hw_address, tx_desc),
+            None => self.ip6_output(vlan, dst_address, tx_desc),
+        }
+    }
+
+    /// Transmit the IPv6 frame in `desc`, tagged with `vlan`, to `dst_address`.
+    ///
+    /// If the link-layer address of `dst_address` is not known yet, the frame is queued and a
+    /// Neighbor Solicitation is sent.
+    ///
+    /// As for IPv4, hosts on a VLAN are always reached directly.
+    pub fn ip6_output(
+        &mut self,
+        vlan: &VlanTags,
+        dst_address: Ipv6Addr,
+        desc: xsk::Desc,
+    ) -> anyhow::Result<()> {
+        if dst_address.is_multicast() {
+            return self.eth_output(ip6::multicast_hw_address(dst_address), desc);
+        }
+
+        let next_hop = match self.ip6_next_hop(vlan, dst_address) {
+            Some(next_hop) => next_hop,
+            None => return Err(self.no_route(IpAddr::V6(dst_address), desc).into()),
+        };
+
+        match self
+            .nd_table
+            .resolve((vlan.vlan(), next_hop), desc, Instant::now())
+        {
+            Resolution::Send(desc, hw_address) => self.eth_output(hw_address, desc)?,
+            Resolution::SendAndProbe(desc, hw_address) => {
+                self.eth_output(hw_address, desc)?;
+                self.send_neighbor_solicitation(vlan, next_hop, Some(hw_address))?;
+            }
+            Resolution::Solicit => self.send_neighbor_solicitation(vlan, next_hop, None)?,
+            Resolution::Queued => {}
+            Resolution::Full(_desc) => {
+                // Dropping the frame releases its descriptor
+                self.stats.neigh_backlog_full += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next hop toward `dst_address` on `vlan`, if any.
+    fn ip6_next_hop(&self, vlan: &VlanTags, dst_address: Ipv6Addr) -> Option<Ipv6Addr> {
+        match vlan.vlan() {
+            Some(_) => Some(dst_address),
+            None => match self.routes.next_hop(IpAddr::V6(dst_address)) {
+                Some(IpAddr::V6(next_hop)) => Some(next_hop),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns true if frames to `dst_address` on `vlan` are sent right away, without waiting for
+    /// the link-layer address of the next hop to be resolved.
+    fn next_hop_resolved(&self, vlan: &VlanTags, dst_address: IpAddr) -> bool {
+        match dst_address {
+            IpAddr::V4(dst_address) => self
+                .ip4_next_hop(vlan, dst_address)
+                .is_some_and(|next_hop| self.arp_table.is_resolved(&(vlan.vlan(), next_hop))),
+            IpAddr::V6(dst_address) if dst_address.is_multicast() => true,
+            IpAddr::V6(dst_address) => self
+                .ip6_next_hop(vlan, dst_address)
+                .is_some_and(|next_hop| self.nd_table.is_resolved(&(vlan.vlan(), next_hop))),
+        }
+    }
+
+    /// Record that an IPv6 neighbor on `vlan` is reachable at `hw_address`, flushing the frames
+    /// waiting for it.
+    pub fn update_neighbor6(
+        &mut self,
+        vlan: Option<xsk::Vlan>,
+        address: Ipv6Addr,
+        hw_address: [u8; 6],
+    ) -> anyhow::Result<()> {
+        for desc in self
+            .nd_table
+            .confirm((vlan, address), hw_address, Instant::now())
+        {
+            self.eth_output(hw_address, desc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record an unsolicited indication that an IPv6 neighbor on `vlan` is at `hw_address`,
+    /// flushing the frames waiting for it. A new entry is created only if `create` is true.
+    pub fn merge_neighbor6(
+        &mut self,
+        vlan: Option<xsk::Vlan>,
+        address: Ipv6Addr,
+        hw_address: [u8; 6],
+        create: bool,
+    ) -> anyhow::Result<()> {
+        for desc in self
+            .nd_table
+            .update((vlan, address), hw_address, create, Instant::now())
+        {
+            self.eth_output(hw_address, desc)?;
+        }
 
         Ok(())
     }
@@ -15,14 +15,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::net::{ArpHdr, EthHdr, Ip4Hdr, PacketBufMut, UdpHdr};
+use std::time::Instant;
+
+use crate::{
+    net::{ArpHdr, EthHdr, Icmp6Hdr, IcmpHdr, Ip4Hdr, Ip6Hdr, PacketBufMut, UdpHdr, VlanTags},
+    xsk,
+};
 
 #[derive(Default)]
 pub struct Packet<'a> {
     pub packet_buf: PacketBufMut<'a>,
+    /// RX descriptor of the frame holding the packet, if it was not reassembled.
+    pub desc:       Option<xsk::Desc>,
     pub eth_hdr:    Option<&'a mut EthHdr>,
+    pub vlan:       VlanTags,
+    /// Time at which the frame was taken from the RX ring.
+    pub timestamp:  Option<Instant>,
     pub arp_hdr:    Option<&'a mut ArpHdr>,
     pub ip4_hdr:    Option<&'a mut Ip4Hdr>,
+    pub icmp_hdr:   Option<&'a mut IcmpHdr>,
+    pub ip6_hdr:    Option<&'a mut Ip6Hdr>,
+    pub icmp6_hdr:  Option<&'a mut Icmp6Hdr>,
     pub udp_hdr:    Option<&'a mut UdpHdr>,
     pub l4_payload: Option<&'a mut [u8]>,
 }
@@ -35,3 +48,13 @@ impl Packet<'_> {
         }
     }
 }
+
+/// The frame of the datagram being delivered to an app, which can be reused to send a reply in
+/// place of the datagram.
+pub struct RxFrame {
+    /// Address of the received packet in the UMEM.
+    pub addr:           u64,
+    /// Offset of the UDP payload in the frame.
+    pub payload_offset: usize,
+    pub payload_len:    usize,
+}
@@ -17,7 +17,12 @@
 
 use crate::net::{Error, Result};
 
-use std::{cmp::max, convert::TryInto, marker::PhantomData, ptr, slice};
+use std::{
+    cmp::{max, min},
+    convert::TryInto,
+    marker::PhantomData,
+    ptr, slice,
+};
 
 enum OffsetOp {
     Set(usize),
@@ -135,6 +140,16 @@ impl<'a> PacketBufMut<'a> {
         unsafe { slice::from_raw_parts(self.buffer_ptr, self.packet_len) }
     }
 
+    /// Returns the number of bytes between the current position and the end of the buffer.
+    pub fn remaining(&self) -> usize {
+        self.buffer_len.saturating_sub(self.packet_offset)
+    }
+
+    /// Limits the buffer to its first `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.buffer_len = min(self.buffer_len, len);
+    }
+
     pub fn seek(&mut self, packet_offset: usize) -> Result<()> {
         if packet_offset > self.buffer_len {
             return Err(Error::InvalidSeekPos);
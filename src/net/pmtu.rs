@@ -0,0 +1,196 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Path MTU discovery (RFC 1191, RFC 8201).
+//!
+//! The cache keeps the MTU of the paths toward the destinations for which an ICMP
+//! "fragmentation needed" or ICMPv6 "packet too big" message was received. Entries are dropped
+//! after `timeout`, so that an increase of the path MTU is eventually detected.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::net::Configuration;
+
+/// Minimum MTU of an IPv4 path (RFC 791).
+pub const IP4_MIN_MTU: usize = 68;
+
+/// Minimum MTU of an IPv6 path (RFC 8200).
+pub const IP6_MIN_MTU: usize = 1280;
+
+/// Plateau values used to estimate the path MTU when a router does not report the MTU of the
+/// next hop (RFC 1191, section 7).
+const MTU_PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+struct PathMtu {
+    mtu:     usize,
+    expires: Instant,
+}
+
+pub struct PmtuCache {
+    paths:       HashMap<IpAddr, PathMtu>,
+    max_entries: usize,
+    timeout:     Duration,
+}
+
+impl PmtuCache {
+    /// Creates a new [`PmtuCache`] object.
+    pub fn new(configuration: &Configuration) -> Self {
+        PmtuCache {
+            paths:       HashMap::new(),
+            max_entries: configuration.pmtu_max_entries(),
+            timeout:     configuration.pmtu_timeout(),
+        }
+    }
+
+    /// Returns the MTU of the path toward `address`, if it is lower than the MTU of the link.
+    pub fn get(&self, address: IpAddr, now: Instant) -> Option<usize> {
+        self.paths
+            .get(&address)
+            .filter(|path| path.expires > now)
+            .map(|path| path.mtu)
+    }
+
+    /// Lowers the MTU of the path toward `address` to `mtu`, returning true if it was updated.
+    ///
+    /// `mtu` is raised to the minimum MTU of the protocol, and ignored if it is not lower than
+    /// the current one, as RFC 1191 requires hosts not to increase the path MTU when receiving a
+    /// message.
+    pub fn update(
+        &mut self,
+        address: IpAddr,
+        mtu: usize,
+        current_mtu: usize,
+        now: Instant,
+    ) -> bool {
+        let mtu = clamp_mtu(address, mtu);
+
+        if mtu >= current_mtu {
+            return false;
+        }
+
+        if !self.paths.contains_key(&address) && self.paths.len() >= self.max_entries {
+            self.expire(now);
+
+            // Evict the entry closest to expiration
+            if self.paths.len() >= self.max_entries {
+                let oldest = self
+                    .paths
+                    .iter()
+                    .min_by_key(|(_, path)| path.expires)
+                    .map(|(address, _)| *address);
+
+                if let Some(oldest) = oldest {
+                    self.paths.remove(&oldest);
+                }
+            }
+        }
+
+        self.paths.insert(
+            address,
+            PathMtu {
+                mtu,
+                expires: now + self.timeout,
+            },
+        );
+
+        true
+    }
+
+    /// Drops the entries which are older than `timeout`.
+    pub fn expire(&mut self, now: Instant) {
+        self.paths.retain(|_, path| path.expires > now);
+    }
+}
+
+/// Raises `mtu` to the minimum MTU of the protocol of `address`, which every path supports.
+pub fn clamp_mtu(address: IpAddr, mtu: usize) -> usize {
+    match address {
+        IpAddr::V4(_) => mtu.max(IP4_MIN_MTU),
+        IpAddr::V6(_) => mtu.max(IP6_MIN_MTU),
+    }
+}
+
+/// Returns an estimate of the path MTU for a datagram of length `len` which was too big for a
+/// router that did not report the MTU of the next hop.
+pub fn mtu_plateau(len: usize) -> usize {
+    MTU_PLATEAUS
+        .iter()
+        .copied()
+        .find(|&mtu| mtu < len)
+        .unwrap_or(IP4_MIN_MTU)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_pmtu_cache() {
+        let now = Instant::now();
+        let mut configuration = Configuration::default();
+        configuration.set_pmtu_max_entries(2);
+        let mut cache = PmtuCache::new(&configuration);
+
+        let a = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let c = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        assert!(cache.update(a, 1400, 1500, now));
+        assert_eq!(cache.get(a, now), Some(1400));
+
+        // The path MTU is never increased, nor lowered below the minimum
+        assert!(!cache.update(a, 1450, 1400, now));
+        assert!(cache.update(a, 10, 1400, now));
+        assert_eq!(cache.get(a, now), Some(IP4_MIN_MTU));
+        let soon = now + Duration::from_millis(500);
+        assert!(cache.update(c, 1000, 1500, soon));
+        assert_eq!(cache.get(c, soon), Some(IP6_MIN_MTU));
+
+        // The entry closest to expiration is evicted
+        let later = now + Duration::from_secs(1);
+        assert!(cache.update(b, 1400, 1500, later));
+        assert_eq!(cache.get(a, later), None);
+        assert_eq!(cache.get(b, later), Some(1400));
+
+        let expired = later + configuration.pmtu_timeout();
+        assert_eq!(cache.get(b, expired), None);
+    }
+
+    #[test]
+    fn test_mtu_plateau() {
+        assert_eq!(mtu_plateau(1500), 1492);
+        assert_eq!(mtu_plateau(1492), 1006);
+        assert_eq!(mtu_plateau(60), IP4_MIN_MTU);
+    }
+
+    #[test]
+    fn test_clamp_mtu() {
+        let a = "192.168.0.1".parse().unwrap();
+        let b = "fc00::1".parse().unwrap();
+
+        assert_eq!(clamp_mtu(a, 20), IP4_MIN_MTU);
+        assert_eq!(clamp_mtu(a, 1500), 1500);
+        assert_eq!(clamp_mtu(b, 576), IP6_MIN_MTU);
+        assert_eq!(clamp_mtu(b, 9000), 9000);
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Token bucket rate limiter.
+
+use std::time::Instant;
+
+/// A token bucket refilled with `rate` tokens per second, holding at most `burst` tokens.
+pub struct TokenBucket {
+    rate:   u32,
+    burst:  u32,
+    tokens: f64,
+    last:   Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new full [`TokenBucket`]. A zero `rate` means no rate limit.
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    /// Takes a token from the bucket, returning false if it is empty.
+    pub fn take(&mut self, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+
+        // One token every 100ms
+        assert!(!bucket.take(now + Duration::from_millis(50)));
+        assert!(bucket.take(now + Duration::from_millis(100)));
+        assert!(!bucket.take(now + Duration::from_millis(100)));
+
+        // No more than `burst` tokens accumulate
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.take(later));
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn test_token_bucket_unlimited() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(0, 0, now);
+
+        for _ in 0..100 {
+            assert!(bucket.take(now));
+        }
+    }
+}
@@ -0,0 +1,465 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! IPv4 fragment reassembly (RFC 791, RFC 815).
+
+use std::{
+    collections::HashMap,
+    mem,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use crate::net::{Configuration, Error, Ip4Hdr, PacketBufMut, Result};
+
+/// Maximum length of an IPv4 datagram.
+const IP4_MAX_LEN: usize = 65535;
+
+/// Identifies the fragments of the same datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src_address: Ipv4Addr,
+    pub dst_address: Ipv4Addr,
+    pub proto:       u8,
+    pub id:          u16,
+}
+
+struct Datagram {
+    /// Header of the first fragment, including its options.
+    hdr:     Vec<u8>,
+    payload: Vec<u8>,
+    /// Sorted, non-overlapping ranges of the payload received so far.
+    ranges:  Vec<(usize, usize)>,
+    /// Length of the payload, known once the last fragment is received.
+    len:     Option<usize>,
+    expires: Instant,
+}
+
+impl Datagram {
+    fn new(expires: Instant) -> Self {
+        Datagram {
+            hdr: Vec::new(),
+            payload: Vec::new(),
+            ranges: Vec::new(),
+            len: None,
+            expires,
+        }
+    }
+
+    /// Returns the memory used by the datagram.
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.hdr.len()
+            + self.payload.len()
+            + self.ranges.len() * mem::size_of::<(usize, usize)>()
+    }
+
+    fn is_complete(&self) -> bool {
+        let received = self.ranges.iter().map(|(start, end)| end - start).sum();
+        self.len == Some(received)
+    }
+
+    /// Rebuilds the original datagram from the header of the first fragment and the payload.
+    fn into_datagram(self) -> Result<Vec<u8>> {
+        let len = self.hdr.len() + self.len.unwrap();
+
+        let mut datagram = self.hdr;
+        datagram.extend_from_slice(&self.payload);
+
+        let mut packet = PacketBufMut::from_slice(&mut datagram);
+        let ip4 = Ip4Hdr::from_packet_buf(&mut packet)?;
+        ip4.set_total_length(len as u16);
+        ip4.flags_frag_offset = 0;
+        ip4.calc_checksum();
+
+        Ok(datagram)
+    }
+}
+
+/// Reassembles fragmented IPv4 datagrams, holding at most `max_memory` bytes of incomplete
+/// datagrams for `timeout` each.
+///
+/// When the memory limit is reached, the oldest incomplete datagrams are evicted to make room
+/// for new fragments, so that a stream of fragments that are never completed cannot prevent
+/// other datagrams from being reassembled.
+///
+/// Overlapping fragments are not merged: as for IPv6 (RFC 5722), a datagram with overlapping
+/// fragments is silently discarded.
+pub struct Reassembler {
+    datagrams:  HashMap<FragmentKey, Datagram>,
+    memory:     usize,
+    max_memory: usize,
+    timeout:    Duration,
+    evicted:    usize,
+}
+
+impl Reassembler {
+    /// Creates a new [`Reassembler`] object.
+    pub fn new(configuration: &Configuration) -> Self {
+        Reassembler {
+            datagrams:  HashMap::new(),
+            memory:     0,
+            max_memory: configuration.ip4_frag_max_memory(),
+            timeout:    configuration.ip4_frag_timeout(),
+            evicted:    0,
+        }
+    }
+
+    /// Returns the number of datagrams being reassembled.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Returns true if no datagram is being reassembled.
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Returns the memory used by the datagrams being reassembled.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Returns the number of datagrams evicted to make room for new fragments since the last
+    /// call.
+    pub fn take_evicted(&mut self) -> usize {
+        mem::take(&mut self.evicted)
+    }
+
+    /// Adds the fragment with header `hdr` (options included), carrying `data` at `offset` bytes
+    /// in the original payload.
+    ///
+    /// Returns the whole datagram, with a rebuilt header, once all of its fragments have been
+    /// received. On error the fragment and the datagram it belongs to are dropped.
+    pub fn insert(
+        &mut self,
+        key: FragmentKey,
+        hdr: &[u8],
+        offset: usize,
+        more_fragments: bool,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        let end = offset + data.len();
+
+        // All fragments but the last carry a multiple of 8 bytes
+        if data.is_empty() || (more_fragments && data.len() & 7 != 0) {
+            self.remove(&key);
+            return Err(Error::InvalidFragment);
+        }
+
+        if hdr.len() + end > IP4_MAX_LEN {
+            self.remove(&key);
+            return Err(Error::FragmentTooLarge);
+        }
+
+        let timeout = self.timeout;
+        let memory = &mut self.memory;
+        let datagram = self.datagrams.entry(key).or_insert_with(|| {
+            let datagram = Datagram::new(now + timeout);
+            *memory += datagram.size();
+            datagram
+        });
+
+        let valid_len = match datagram.len {
+            Some(len) => end <= len && (more_fragments || end == len),
+            None => more_fragments || datagram.ranges.iter().all(|&(_, e)| e <= end),
+        };
+
+        if !valid_len {
+            self.remove(&key);
+            return Err(Error::InvalidFragment);
+        }
+
+        if datagram
+            .ranges
+            .iter()
+            .any(|&(start, e)| start < end && offset < e)
+        {
+            self.remove(&key);
+            return Err(Error::FragmentOverlap);
+        }
+
+        let growth = end.saturating_sub(datagram.payload.len())
+            + if offset == 0 { hdr.len() } else { 0 }
+            + mem::size_of::<(usize, usize)>();
+        if !self.make_room(&key, growth) {
+            self.remove(&key);
+            return Err(Error::FragmentNoMemory);
+        }
+
+        let datagram = self.datagrams.get_mut(&key).unwrap();
+        let old_size = datagram.size();
+
+        if datagram.payload.len() < end {
+            datagram.payload.resize(end, 0);
+        }
+        datagram.payload[offset..end].copy_from_slice(data);
+
+        let pos = datagram
+            .ranges
+            .partition_point(|&(start, _)| start < offset);
+        datagram.ranges.insert(pos, (offset, end));
+
+        if offset == 0 {
+            datagram.hdr = hdr.to_vec();
+        }
+
+        if !more_fragments {
+            datagram.len = Some(end);
+        }
+
+        self.memory = self.memory + datagram.size() - old_size;
+
+        if !datagram.is_complete() {
+            return Ok(None);
+        }
+
+        let datagram = self.datagrams.remove(&key).unwrap();
+        self.memory -= datagram.size();
+
+        datagram.into_datagram().map(Some)
+    }
+
+    /// Drops the datagrams that have not been completed in time, returning how many were dropped.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired = self
+            .datagrams
+            .iter()
+            .filter(|(_, datagram)| datagram.expires <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in &expired {
+            self.remove(key);
+        }
+
+        expired.len()
+    }
+
+    /// Evicts the oldest incomplete datagrams other than the one of `key` until `growth` more
+    /// bytes fit in the memory limit, returning false if they cannot.
+    fn make_room(&mut self, key: &FragmentKey, growth: usize) -> bool {
+        while self.memory + growth > self.max_memory {
+            // Datagrams all have the same timeout, the oldest one expires first
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(k, _)| *k != key)
+                .min_by_key(|(_, datagram)| datagram.expires)
+                .map(|(k, _)| *k);
+
+            match oldest {
+                Some(oldest) => {
+                    self.remove(&oldest);
+                    self.evicted += 1;
+                }
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    fn remove(&mut self, key: &FragmentKey) {
+        if let Some(datagram) = self.datagrams.remove(key) {
+            self.memory -= datagram.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::net::utils;
+
+    const KEY: FragmentKey = FragmentKey {
+        src_address: Ipv4Addr::new(192, 168, 0, 1),
+        dst_address: Ipv4Addr::new(192, 168, 0, 2),
+        proto:       17,
+        id:          0x1234,
+    };
+
+    fn fragment_hdr(offset: usize, more_fragments: bool) -> Vec<u8> {
+        let flags_frag_offset = (offset / 8) as u16 | if more_fragments { 0x2000 } else { 0 };
+        let mut hdr = vec![
+            0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, 17, 0, 0, 192, 168, 0, 1, 192, 168, 0, 2,
+        ];
+        hdr[6..8].copy_from_slice(&flags_frag_offset.to_be_bytes());
+        hdr
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(&Configuration::default());
+        let payload = (0..40).collect::<Vec<u8>>();
+
+        // Fragments can arrive in any order
+        for &(offset, end) in &[(32, 40), (0, 16)] {
+            let hdr = fragment_hdr(offset, end != payload.len());
+            let r = reassembler.insert(
+                KEY,
+                &hdr,
+                offset,
+                end != payload.len(),
+                &payload[offset..end],
+                now,
+            );
+            assert!(matches!(r, Ok(None)));
+        }
+
+        assert_eq!(reassembler.len(), 1);
+
+        let hdr = fragment_hdr(16, true);
+        let datagram = reassembler
+            .insert(KEY, &hdr, 16, true, &payload[16..32], now)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&datagram[20..], &payload[..]);
+        assert_eq!(&datagram[2..4], &60u16.to_be_bytes());
+        assert_eq!(&datagram[6..8], &[0, 0]);
+        assert_eq!(utils::csum_fold(utils::csum_add(0, &datagram[..20])), 0);
+
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(&Configuration::default());
+        let data = [0; 16];
+
+        let hdr = fragment_hdr(0, true);
+        assert!(matches!(
+            reassembler.insert(KEY, &hdr, 0, true, &data, now),
+            Ok(None)
+        ));
+
+        let hdr = fragment_hdr(8, false);
+        let r = reassembler.insert(KEY, &hdr, 8, false, &data, now);
+        assert!(matches!(r, Err(Error::FragmentOverlap)));
+
+        // The whole datagram is dropped
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn test_invalid_fragment() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(&Configuration::default());
+
+        // Not a multiple of 8 bytes
+        let hdr = fragment_hdr(0, true);
+        let r = reassembler.insert(KEY, &hdr, 0, true, &[0; 12], now);
+        assert!(matches!(r, Err(Error::InvalidFragment)));
+
+        // Beyond the maximum datagram length
+        let hdr = fragment_hdr(65528, false);
+        let r = reassembler.insert(KEY, &hdr, 65528, false, &[0; 8], now);
+        assert!(matches!(r, Err(Error::FragmentTooLarge)));
+
+        // Beyond the end of the datagram
+        let hdr = fragment_hdr(16, false);
+        assert!(matches!(
+            reassembler.insert(KEY, &hdr, 16, false, &[0; 8], now),
+            Ok(None)
+        ));
+        let hdr = fragment_hdr(24, true);
+        let r = reassembler.insert(KEY, &hdr, 24, true, &[0; 8], now);
+        assert!(matches!(r, Err(Error::InvalidFragment)));
+
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let now = Instant::now();
+        let mut configuration = Configuration::default();
+        configuration.set_ip4_frag_max_memory(1024);
+        let mut reassembler = Reassembler::new(&configuration);
+
+        let hdr = fragment_hdr(0, true);
+        assert!(matches!(
+            reassembler.insert(KEY, &hdr, 0, true, &[0; 64], now),
+            Ok(None)
+        ));
+
+        let hdr = fragment_hdr(2048, false);
+        let r = reassembler.insert(KEY, &hdr, 2048, false, &[0; 8], now);
+        assert!(matches!(r, Err(Error::FragmentNoMemory)));
+        assert_eq!(reassembler.memory(), 0);
+
+        let hdr = fragment_hdr(0, true);
+        assert!(matches!(
+            reassembler.insert(KEY, &hdr, 0, true, &[0; 64], now),
+            Ok(None)
+        ));
+
+        assert_eq!(reassembler.expire(now), 0);
+        assert_eq!(
+            reassembler.expire(now + configuration.ip4_frag_timeout()),
+            1
+        );
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let now = Instant::now();
+        let mut configuration = Configuration::default();
+        configuration.set_ip4_frag_max_memory(1024);
+        let mut reassembler = Reassembler::new(&configuration);
+
+        let keys = (0..3)
+            .map(|id| FragmentKey { id, ..KEY })
+            .collect::<Vec<_>>();
+
+        // Three incomplete datagrams do not fit: the oldest one is evicted
+        let hdr = fragment_hdr(0, true);
+        for (i, key) in keys.iter().enumerate() {
+            let r = reassembler.insert(
+                *key,
+                &hdr,
+                0,
+                true,
+                &[0; 256],
+                now + Duration::from_secs(i as u64),
+            );
+            assert!(matches!(r, Ok(None)));
+        }
+
+        assert_eq!(reassembler.len(), 2);
+        assert_eq!(reassembler.take_evicted(), 1);
+        assert_eq!(reassembler.take_evicted(), 0);
+        assert!(reassembler.memory() <= 1024);
+
+        // The remaining datagrams can still be completed
+        let hdr = fragment_hdr(256, false);
+        let r = reassembler.insert(keys[1], &hdr, 256, false, &[0; 8], now);
+        assert!(matches!(r, Ok(Some(_))));
+
+        let r = reassembler.insert(keys[0], &hdr, 256, false, &[0; 8], now);
+        assert!(matches!(r, Ok(None)));
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! IPv4 and IPv6 routing.
+//!
+//! The routing table holds the prefixes directly connected to the interface, the static routes
+//! from the [`Configuration`](crate::net::Configuration) and, optionally, the routes of the
+//! interface imported from the main table of the kernel. Destinations are looked up with a
+//! longest prefix match, and neighbors are resolved for the next hop of the matching route.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// A route toward the `destination`/`prefix_len` network, either directly connected or through
+/// `gateway`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_len:  u8,
+    pub gateway:     Option<IpAddr>,
+}
+
+impl Route {
+    /// Creates a new [`Route`] object. The host bits of `destination` are cleared.
+    pub fn new(destination: IpAddr, prefix_len: u8, gateway: Option<IpAddr>) -> Self {
+        Route {
+            destination: mask(destination, prefix_len),
+            prefix_len,
+            gateway,
+        }
+    }
+
+    /// Creates a new [`Route`] object for a directly connected network.
+    pub fn connected(destination: IpAddr, prefix_len: u8) -> Self {
+        Route::new(destination, prefix_len, None)
+    }
+
+    /// Creates a new default [`Route`] object through `gateway`.
+    pub fn default_gateway(gateway: IpAddr) -> Self {
+        let destination = match gateway {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        Route::new(destination, 0, Some(gateway))
+    }
+
+    /// Returns true if `address` belongs to the destination network of the route.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        address.is_ipv4() == self.destination.is_ipv4()
+            && mask(address, self.prefix_len) == self.destination
+    }
+}
+
+fn mask(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix_len.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix_len.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RoutingTable {
+    // Sorted by decreasing prefix length, so that the first match is the longest one
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    /// Adds `route` to the table, replacing the route to the same network, if any.
+    pub fn add(&mut self, route: Route) {
+        self.routes
+            .retain(|r| r.destination != route.destination || r.prefix_len != route.prefix_len);
+
+        let pos = self
+            .routes
+            .iter()
+            .position(|r| r.prefix_len < route.prefix_len)
+            .unwrap_or(self.routes.len());
+        self.routes.insert(pos, route);
+    }
+
+    /// Returns the most specific route toward `address`.
+    pub fn lookup(&self, address: IpAddr) -> Option<&Route> {
+        self.routes.iter().find(|route| route.contains(address))
+    }
+
+    /// Returns the address of the neighbor packets toward `address` are sent to: either the
+    /// gateway of the matching route or `address` itself if it is directly connected.
+    pub fn next_hop(&self, address: IpAddr) -> Option<IpAddr> {
+        self.lookup(address)
+            .map(|route| route.gateway.unwrap_or(address))
+    }
+
+    /// Returns true if `address` is directly connected.
+    pub fn is_on_link(&self, address: IpAddr) -> bool {
+        matches!(self.lookup(address), Some(route) if route.gateway.is_none())
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+const NLMSG_HDR_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const RTA_HDR_LEN: usize = 4;
+
+fn nl_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Returns the unicast routes of the main table of the kernel whose output interface is
+/// `ifindex`, using a rtnetlink dump (see rtnetlink(7)).
+pub fn import_kernel_routes(ifindex: u32) -> io::Result<Vec<Route>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let routes = dump_routes(fd, ifindex);
+    unsafe { libc::close(fd) };
+
+    routes
+}
+
+fn dump_routes(fd: libc::c_int, ifindex: u32) -> io::Result<Vec<Route>> {
+    // struct nlmsghdr followed by a struct rtmsg with an unspecified family, to get both the
+    // IPv4 and the IPv6 routes
+    let mut req = [0u8; NLMSG_HDR_LEN + RTMSG_LEN];
+    req[0..4].copy_from_slice(&((NLMSG_HDR_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    req[4..6].copy_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    req[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    req[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    req[NLMSG_HDR_LEN] = libc::AF_UNSPEC as u8;
+
+    let ret = unsafe { libc::send(fd, req.as_ptr() as *const libc::c_void, req.len(), 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut routes = Vec::new();
+    let mut buf = vec![0u8; 32 * 1024];
+
+    loop {
+        let len = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut msgs = &buf[..len as usize];
+        while msgs.len() >= NLMSG_HDR_LEN {
+            let msg_len = u32::from_ne_bytes([msgs[0], msgs[1], msgs[2], msgs[3]]) as usize;
+            let msg_type = u16::from_ne_bytes([msgs[4], msgs[5]]);
+
+            if msg_len < NLMSG_HDR_LEN || msg_len > msgs.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated message",
+                ));
+            }
+
+            match msg_type as libc::c_int {
+                libc::NLMSG_DONE => return Ok(routes),
+                libc::NLMSG_ERROR if msg_len >= NLMSG_HDR_LEN + 4 => {
+                    let errno = i32::from_ne_bytes(msgs[16..20].try_into().unwrap());
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                _ if msg_type == libc::RTM_NEWROUTE => {
+                    if let Some(route) = parse_route(&msgs[NLMSG_HDR_LEN..msg_len], ifindex) {
+                        routes.push(route);
+                    }
+                }
+                _ => {}
+            }
+
+            msgs = &msgs[nl_align(msg_len).min(msgs.len())..];
+        }
+    }
+}
+
+/// Parses a struct rtmsg and its attributes.
+fn parse_route(msg: &[u8], ifindex: u32) -> Option<Route> {
+    if msg.len() < RTMSG_LEN {
+        return None;
+    }
+
+    let family = msg[0] as libc::c_int;
+    let prefix_len = msg[1];
+    let table = msg[4];
+    let route_type = msg[7];
+
+    if table != libc::RT_TABLE_MAIN || route_type != libc::RTN_UNICAST {
+        return None;
+    }
+
+    let parse_address = |data: &[u8]| match family {
+        libc::AF_INET if data.len() == 4 => {
+            Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)))
+        }
+        libc::AF_INET6 if data.len() == 16 => {
+            Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)))
+        }
+        _ => None,
+    };
+
+    let mut destination = match family {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => return None,
+    };
+    let mut gateway = None;
+    let mut oif = None;
+
+    let mut attrs = &msg[RTMSG_LEN..];
+    while attrs.len() >= RTA_HDR_LEN {
+        let attr_len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([attrs[2], attrs[3]]);
+
+        if attr_len < RTA_HDR_LEN || attr_len > attrs.len() {
+            return None;
+        }
+
+        let data = &attrs[RTA_HDR_LEN..attr_len];
+        match attr_type {
+            libc::RTA_DST => destination = parse_address(data)?,
+            libc::RTA_GATEWAY => gateway = Some(parse_address(data)?),
+            libc::RTA_OIF if data.len() == mem::size_of::<u32>() => {
+                oif = Some(u32::from_ne_bytes(data.try_into().unwrap()))
+            }
+            _ => {}
+        }
+
+        attrs = &attrs[nl_align(attr_len).min(attrs.len())..];
+    }
+
+    if oif != Some(ifindex) {
+        return None;
+    }
+
+    Some(Route::new(destination, prefix_len, gateway))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(address: &str) -> IpAddr {
+        IpAddr::V4(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let mut table = RoutingTable::default();
+
+        table.add(Route::default_gateway(v4("198.18.0.1")));
+        table.add(Route::connected(v4("198.18.0.2"), 24));
+        table.add(Route::new(v4("10.0.0.0"), 8, Some(v4("198.18.0.254"))));
+        table.add(Route::new(v4("10.1.0.0"), 16, Some(v4("198.18.0.253"))));
+
+        assert_eq!(table.routes()[0].destination, v4("198.18.0.0"));
+
+        assert_eq!(table.next_hop(v4("198.18.0.42")), Some(v4("198.18.0.42")));
+        assert_eq!(table.next_hop(v4("10.1.2.3")), Some(v4("198.18.0.253")));
+        assert_eq!(table.next_hop(v4("10.2.3.4")), Some(v4("198.18.0.254")));
+        assert_eq!(table.next_hop(v4("1.1.1.1")), Some(v4("198.18.0.1")));
+        assert!(table.is_on_link(v4("198.18.0.42")));
+        assert!(!table.is_on_link(v4("1.1.1.1")));
+
+        // IPv6 addresses never match IPv4 routes
+        let address6 = IpAddr::V6("fc00::1".parse().unwrap());
+        assert_eq!(table.next_hop(address6), None);
+
+        table.add(Route::connected("fc00::c612:2".parse().unwrap(), 120));
+        assert_eq!(table.next_hop(address6), None);
+        assert!(table.is_on_link(IpAddr::V6("fc00::c612:42".parse().unwrap())));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Network stack counters.
+
+/// Counters of the events (mostly drops) happening in the network stack.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Frames dropped because of a truncated VLAN tag or more than two tags.
+    pub vlan_invalid:           u64,
+    /// ARP packets dropped because of an unsupported opcode, hardware or protocol type.
+    pub arp_invalid:            u64,
+    /// ARP requests sent to resolve a neighbor.
+    pub arp_requests_sent:      u64,
+    /// ARP probes sent to check whether the bind address is already in use.
+    pub arp_probes_sent:        u64,
+    /// Gratuitous ARP packets sent to announce the bind address.
+    pub arp_announcements_sent: u64,
+    /// ARP packets received from another host claiming the bind address.
+    pub arp_conflicts:          u64,
+    /// IPv4 packets dropped because of an unexpected version.
+    pub ip4_bad_version:        u64,
+    /// IPv4 packets dropped because of a header length shorter than the minimum or longer than
+    /// the packet.
+    pub ip4_bad_hdr_len:        u64,
+    /// IPv4 packets dropped because of a total length shorter than the header or longer than the
+    /// packet.
+    pub ip4_bad_total_len:      u64,
+    /// IPv4 packets dropped because of an invalid header checksum.
+    pub ip4_bad_checksum:       u64,
+    /// IPv4 datagrams reassembled from their fragments.
+    pub ip4_frag_reassembled:   u64,
+    /// IPv4 datagrams dropped because one of their fragments overlaps another.
+    pub ip4_frag_overlaps:      u64,
+    /// IPv4 datagrams dropped because of an invalid fragment or a reassembled length too large.
+    pub ip4_frag_invalid:       u64,
+    /// IPv4 datagrams dropped because the reassembly memory limit was reached, either evicted to
+    /// make room for newer ones or too large to fit.
+    pub ip4_frag_no_memory:     u64,
+    /// IPv4 datagrams dropped because they were not reassembled in time.
+    pub ip4_frag_timeouts:      u64,
+    /// IPv4 fragments sent for payloads larger than the MTU.
+    pub ip4_frags_sent:         u64,
+    /// Path MTUs lowered by ICMP fragmentation needed or ICMPv6 packet too big messages.
+    pub pmtu_updates:           u64,
+    /// Packets dropped because there is no route toward their destination.
+    pub no_route:               u64,
+    /// ICMP messages dropped because they are truncated or have an invalid checksum.
+    pub icmp_invalid:           u64,
+    /// ICMP echo requests answered.
+    pub icmp_echo_replies_sent: u64,
+    /// ICMP destination unreachable messages sent for unserved ports and protocols.
+    pub icmp_unreachable_sent:  u64,
+    /// ICMP error messages not sent because of the rate limit.
+    pub icmp_ratelimited:       u64,
+    /// Packets dropped because they were addressed to an unserved port or protocol.
+    pub no_listener:            u64,
+    /// IPv6 packets dropped because of an invalid header.
+    pub ip6_invalid:            u64,
+    /// ICMPv6 messages dropped because they are truncated or have an invalid checksum.
+    pub icmp6_invalid:          u64,
+    /// Neighbor Discovery messages dropped because they failed validation.
+    pub nd_invalid:             u64,
+    /// Neighbor Solicitations sent to resolve a neighbor.
+    pub nd_solicits_sent:       u64,
+    /// UDP datagrams dropped because their length is shorter than the header or longer than the
+    /// packet.
+    pub udp_invalid:            u64,
+    /// UDP datagrams dropped because of an invalid checksum.
+    pub udp_bad_checksum:       u64,
+    /// Payloads dropped because the backlog of an unresolved neighbor was full.
+    pub neigh_backlog_full:     u64,
+    /// Payloads dropped because their neighbor could not be resolved.
+    pub neigh_unresolved:       u64,
+}
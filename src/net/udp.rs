@@ -15,7 +15,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{fmt, mem};
+use std::{fmt, mem, slice};
 
 use crate::{
     net,
@@ -59,6 +59,30 @@ impl UdpHdr {
         self.len = net::utils::htons(v);
         self
     }
+
+    fn sum(&self, pseudo_header_sum: u32, payload: &[u8]) -> u32 {
+        let hdr = unsafe {
+            slice::from_raw_parts(self as *const UdpHdr as *const u8, mem::size_of::<Self>())
+        };
+
+        net::utils::csum_add(net::utils::csum_add(pseudo_header_sum, hdr), payload)
+    }
+
+    /// Compute the checksum of the UDP datagram made of this header followed by `payload`.
+    ///
+    /// A computed checksum of zero is transmitted as all ones, as zero means no checksum.
+    pub fn calc_checksum(&mut self, pseudo_header_sum: u32, payload: &[u8]) {
+        self.sum = 0;
+
+        let sum = net::utils::csum_fold(self.sum(pseudo_header_sum, payload));
+        self.sum = net::utils::htons(if sum == 0 { 0xffff } else { sum });
+    }
+
+    /// Returns true if the checksum of the UDP datagram made of this header followed by
+    /// `payload` is valid. A datagram without checksum is never valid.
+    pub fn is_checksum_valid(&self, pseudo_header_sum: u32, payload: &[u8]) -> bool {
+        self.sum != 0 && net::utils::csum_fold(self.sum(pseudo_header_sum, payload)) == 0
+    }
 }
 
 impl fmt::Debug for UdpHdr {
@@ -15,6 +15,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::{fs, net::IpAddr};
+
 use nix::ifaddrs::getifaddrs;
 
 pub fn ntohs(x: u16) -> u16 {
@@ -33,6 +35,46 @@ pub fn htonl(x: u32) -> u32 {
     x.to_be()
 }
 
+/// Add `data` to the one's complement sum `sum`.
+///
+/// `data` is summed as a sequence of 16 bit big endian words, padded with a zero byte if its
+/// length is odd, so only the last chunk of a message can have an odd length.
+pub fn csum_add(sum: u32, data: &[u8]) -> u32 {
+    let mut sum = (sum >> 16) + (sum & 0xffff);
+
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += (word[0] as u32) << 8 | word[1] as u32;
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    if let [b] = chunks.remainder() {
+        sum += (*b as u32) << 8;
+    }
+
+    sum
+}
+
+/// Fold the one's complement sum `sum` into a 16 bit checksum, in host byte order.
+pub fn csum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    !sum as u16
+}
+
+/// Update `checksum` (in host byte order) after the `old` bytes of the checksummed data have
+/// been replaced by the `new` ones, without summing the whole data again (RFC 1624).
+///
+/// `old` and `new` must have the same length and start at an even offset.
+pub fn csum_update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    // ~C' = ~C + ~m + m'
+    let sum = !checksum as u32 + csum_fold(csum_add(0, old)) as u32;
+
+    csum_fold(csum_add(sum, new))
+}
+
 pub fn mac_to_string(addr: [u8; 6]) -> String {
     addr.iter()
         .map(|x| format!("{:02X}", x))
@@ -51,3 +93,77 @@ pub fn get_phy_mac_addr(iface: &str) -> Option<[u8; 6]> {
 
     None
 }
+
+/// Returns the IPv4 and IPv6 addresses of `iface`, along with the length of their prefix.
+pub fn get_iface_prefixes(iface: &str) -> Vec<(IpAddr, u8)> {
+    let mut prefixes = Vec::new();
+
+    if let Ok(addrs) = getifaddrs() {
+        for addr in addrs.filter(|v| v.interface_name == iface) {
+            let (address, netmask) = match (addr.address, addr.netmask) {
+                (Some(address), Some(netmask)) => (address, netmask),
+                _ => continue,
+            };
+
+            if let (Some(address), Some(netmask)) =
+                (address.as_sockaddr_in(), netmask.as_sockaddr_in())
+            {
+                let prefix_len = u32::from(netmask.ip()).count_ones() as u8;
+                prefixes.push((IpAddr::V4(address.ip()), prefix_len));
+            } else if let (Some(address), Some(netmask)) =
+                (address.as_sockaddr_in6(), netmask.as_sockaddr_in6())
+            {
+                let prefix_len = u128::from(netmask.ip()).count_ones() as u8;
+                prefixes.push((IpAddr::V6(address.ip()), prefix_len));
+            }
+        }
+    }
+
+    prefixes
+}
+
+pub fn get_mtu(iface: &str) -> Option<usize> {
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", iface))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csum() {
+        // Example from RFC 1071, section 3
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(csum_fold(csum_add(0, &data)), !0xddf2);
+
+        // Summing in chunks gives the same result
+        let sum = csum_add(csum_add(0, &data[..4]), &data[4..]);
+        assert_eq!(csum_fold(sum), !0xddf2);
+
+        // Odd length data is padded with a zero byte
+        assert_eq!(
+            csum_fold(csum_add(0, &data[..7])),
+            csum_fold(csum_add(
+                0,
+                &[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0x00]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_csum_update() {
+        let mut data = [0x45, 0x00, 0x00, 0x54, 0x12, 0x34, 0x40, 0x00, 0x40, 0x01];
+        let checksum = csum_fold(csum_add(0, &data));
+
+        // Decrement the TTL
+        let old = [data[8], data[9]];
+        data[8] -= 1;
+
+        assert_eq!(
+            csum_update(checksum, &old, &data[8..10]),
+            csum_fold(csum_add(0, &data))
+        );
+    }
+}
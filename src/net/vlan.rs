@@ -0,0 +1,212 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fmt, mem};
+
+use crate::{
+    net,
+    net::{Error, PacketBufMut, Result},
+    xsk::Vlan,
+};
+
+/// Maximum number of VLAN tags parsed in a frame: an 802.1ad service tag followed by an 802.1Q
+/// customer tag.
+pub const VLAN_MAX_TAGS: usize = 2;
+
+const VLAN_VID_MASK: u16 = 0x0fff;
+
+/// The part of an 802.1Q tag following its TPID, which is the EtherType field of the preceding
+/// header.
+#[repr(C)]
+pub struct VlanHdr {
+    pub tci:      u16,
+    pub eth_type: u16,
+}
+
+impl VlanHdr {
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn from_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        packet
+            .get_bytes_mut(mem::size_of::<Self>())
+            .map(|l2_slice| unsafe { &mut *(l2_slice.as_mut_ptr() as *mut VlanHdr) })
+    }
+
+    pub fn with_packet_buf<'a>(packet: &mut PacketBufMut<'a>) -> Result<&'a mut Self> {
+        Self::from_packet_buf(packet)
+    }
+
+    pub fn vid(&self) -> u16 {
+        net::utils::ntohs(self.tci) & VLAN_VID_MASK
+    }
+
+    pub fn pcp(&self) -> u8 {
+        (net::utils::ntohs(self.tci) >> 13) as u8
+    }
+
+    pub fn set_tci(&mut self, v: u16) -> &mut Self {
+        self.tci = net::utils::htons(v);
+        self
+    }
+
+    pub fn set_eth_type(&mut self, v: u16) -> &mut Self {
+        self.eth_type = net::utils::htons(v);
+        self
+    }
+}
+
+impl fmt::Debug for VlanHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "VlanHdr {{ pcp: {}, vid: {}, eth_type: 0x{:04x} }}",
+            self.pcp(),
+            self.vid(),
+            net::utils::ntohs(self.eth_type),
+        )
+    }
+}
+
+/// A VLAN tag, in host byte order.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct VlanTag {
+    pub tpid: u16,
+    pub tci:  u16,
+}
+
+impl VlanTag {
+    pub fn vid(&self) -> u16 {
+        self.tci & VLAN_VID_MASK
+    }
+}
+
+/// The stack of VLAN tags of a frame, outermost first.
+///
+/// Replies carry the tags of the frame they answer, so that they are sent back on the same VLAN
+/// with the same priority.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct VlanTags {
+    tags: [VlanTag; VLAN_MAX_TAGS],
+    len:  usize,
+}
+
+impl VlanTags {
+    /// Creates the tags of frames sent on `vlan`, or untagged if `vlan` is `None`.
+    pub fn new(vlan: Option<Vlan>) -> Self {
+        let mut tags = VlanTags::default();
+
+        match vlan {
+            Some(Vlan {
+                outer_vid: Some(outer_vid),
+                vid,
+            }) => {
+                tags.tags[0] = VlanTag {
+                    tpid: net::EthType::QINQ as u16,
+                    tci:  outer_vid,
+                };
+                tags.tags[1] = VlanTag {
+                    tpid: net::EthType::VLAN as u16,
+                    tci:  vid,
+                };
+                tags.len = 2;
+            }
+            Some(Vlan {
+                outer_vid: None,
+                vid,
+            }) => {
+                tags.tags[0] = VlanTag {
+                    tpid: net::EthType::VLAN as u16,
+                    tci:  vid,
+                };
+                tags.len = 1;
+            }
+            None => {}
+        }
+
+        tags
+    }
+
+    /// Appends an inner tag.
+    pub fn push(&mut self, tag: VlanTag) -> Result<()> {
+        if self.len == VLAN_MAX_TAGS {
+            return Err(Error::TooManyVlanTags);
+        }
+
+        self.tags[self.len] = tag;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    pub fn tags(&self) -> &[VlanTag] {
+        &self.tags[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the length of the tags in a frame.
+    pub fn hdr_len(&self) -> usize {
+        self.len * mem::size_of::<VlanHdr>()
+    }
+
+    /// Returns the VLAN of the frame, or `None` if it is untagged.
+    ///
+    /// A single tag with a VID of 0 only carries the priority of the frame (802.1p), which
+    /// belongs to the untagged network.
+    pub fn vlan(&self) -> Option<Vlan> {
+        match self.tags() {
+            [tag] if tag.vid() == 0 => None,
+            [tag] => Some(Vlan::new(tag.vid())),
+            [outer, inner] => Some(Vlan::qinq(outer.vid(), inner.vid())),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vlan_tags() {
+        assert_eq!(VlanTags::new(None).hdr_len(), 0);
+        assert_eq!(VlanTags::new(None).vlan(), None);
+
+        let tags = VlanTags::new(Some(Vlan::new(100)));
+        assert_eq!(tags.hdr_len(), 4);
+        assert_eq!(tags.vlan(), Some(Vlan::new(100)));
+
+        let tags = VlanTags::new(Some(Vlan::qinq(10, 100)));
+        assert_eq!(tags.hdr_len(), 8);
+        assert_eq!(tags.tags()[0].tpid, net::EthType::QINQ as u16);
+        assert_eq!(tags.vlan(), Some(Vlan::qinq(10, 100)));
+
+        // Priority tagged frames belong to the untagged network
+        let mut tags = VlanTags::default();
+        tags.push(VlanTag {
+            tpid: net::EthType::VLAN as u16,
+            tci:  5 << 13,
+        })
+        .unwrap();
+        assert_eq!(tags.hdr_len(), 4);
+        assert_eq!(tags.vlan(), None);
+
+        tags.push(VlanTag::default()).unwrap();
+        assert!(tags.push(VlanTag::default()).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! CPU and NUMA placement of the XSK threads and UMEMs.
+
+use libc::{c_ulong, c_void};
+
+use std::{fs, mem};
+
+use crate::xsk::{Configuration, CpuAffinity, Error::*, Result};
+
+/// Returns the CPU the RX thread of each socket is pinned to, in the order the sockets are
+/// created: the sockets of the first queue, then the ones of the second queue and so on.
+pub fn socket_cpus(cfg: &Configuration) -> Vec<Option<usize>> {
+    let socks_per_queue = cfg.socks_per_queue();
+    let sockets = 0..cfg.queues().len() * socks_per_queue;
+
+    match cfg.cpu_affinity() {
+        CpuAffinity::None => sockets.map(|_| None).collect(),
+        CpuAffinity::Cpus(cpus) if cpus.is_empty() => sockets.map(|_| None).collect(),
+        CpuAffinity::Cpus(cpus) => sockets.map(|n| Some(cpus[n % cpus.len()])).collect(),
+        CpuAffinity::Irq => cfg
+            .queues()
+            .iter()
+            .flat_map(|&queue| {
+                let cpu = queue_irq_cpu(cfg.interface(), queue);
+                if cpu.is_none() {
+                    warn!("Cannot find the IRQ affinity of queue {}", queue);
+                }
+
+                (0..socks_per_queue).map(move |_| cpu)
+            })
+            .collect(),
+    }
+}
+
+/// Checks that the CPUs the RX threads are to be pinned to are online, when the affinity is a
+/// CPU list.
+pub fn validate_cpus(cfg: &Configuration) -> Result<()> {
+    let cpus = match cfg.cpu_affinity() {
+        CpuAffinity::Cpus(cpus) => cpus,
+        _ => return Ok(()),
+    };
+
+    let online = match fs::read_to_string("/sys/devices/system/cpu/online")
+        .ok()
+        .and_then(|s| parse_cpu_list(&s))
+    {
+        Some(online) => online,
+        None => {
+            warn!("Cannot get the online CPUs");
+            return Ok(());
+        }
+    };
+
+    check_cpus(cpus, &online)
+}
+
+/// Returns an error for the first of `cpus` which is not one of the `online` ones.
+fn check_cpus(cpus: &[usize], online: &[usize]) -> Result<()> {
+    match cpus.iter().find(|cpu| !online.contains(cpu)) {
+        Some(&cpu) => Err(OfflineCpu(cpu)),
+        None => Ok(()),
+    }
+}
+
+/// Parses a CPU list such as `0-3,8`, in the format of `/proc/irq/*/smp_affinity_list`.
+///
+/// CPUs which do not fit in a `cpu_set_t`, i.e. not lower than `CPU_SETSIZE`, are rejected.
+pub fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in s.trim().split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last || last >= libc::CPU_SETSIZE as usize {
+                    return None;
+                }
+
+                cpus.extend(first..=last);
+            }
+            None => match range.parse().ok()? {
+                cpu if cpu < libc::CPU_SETSIZE as usize => cpus.push(cpu),
+                _ => return None,
+            },
+        }
+    }
+
+    Some(cpus)
+}
+
+/// Returns the first CPU of the affinity of the IRQ of `queue` of `interface`.
+fn queue_irq_cpu(interface: &str, queue: usize) -> Option<usize> {
+    let interrupts = fs::read_to_string("/proc/interrupts").ok()?;
+
+    let irq = interrupts.lines().find_map(|line| {
+        let (irq, rest) = line.trim_start().split_once(':')?;
+        let name = rest.split_whitespace().last()?;
+
+        if is_queue_irq(name, interface, queue) {
+            irq.parse::<usize>().ok()
+        } else {
+            None
+        }
+    })?;
+
+    let cpus = fs::read_to_string(format!("/proc/irq/{}/smp_affinity_list", irq)).ok()?;
+    parse_cpu_list(&cpus)?.first().copied()
+}
+
+/// Returns whether an IRQ named `name` belongs to `queue` of `interface`.
+///
+/// Drivers name the IRQs of the queues after the interface and the queue number, e.g.
+/// `eth0-TxRx-3` or `eth0-rx-3`.
+fn is_queue_irq(name: &str, interface: &str, queue: usize) -> bool {
+    let suffix = match name.strip_prefix(interface) {
+        Some(suffix) if suffix.starts_with(|c: char| !c.is_ascii_alphanumeric()) => suffix,
+        _ => return false,
+    };
+
+    match suffix.rsplit_once(|c: char| !c.is_ascii_digit()) {
+        Some((_, num)) => num.parse() == Ok(queue),
+        None => false,
+    }
+}
+
+/// Returns the NUMA node of `cpu`.
+pub fn cpu_node(cpu: usize) -> Option<usize> {
+    fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+}
+
+/// Pins the calling thread to `cpu`.
+pub fn pin_thread(cpu: usize) -> Result<()> {
+    // CPU_SET() panics on CPUs out of the set
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(SchedSetaffinityFailed(libc::EINVAL));
+    }
+
+    let ret = unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if ret == -1 {
+        return Err(SchedSetaffinityFailed(nix::errno::Errno::last_raw()));
+    }
+
+    Ok(())
+}
+
+/// Sets the memory policy of the `len` bytes at `addr` to prefer NUMA `node`, moving the pages
+/// that have already been allocated.
+///
+/// This has to happen before the memory is pinned, i.e. before it is registered as an UMEM.
+pub fn bind_to_node(addr: *mut c_void, len: usize, node: usize) -> Result<()> {
+    const MPOL_PREFERRED: libc::c_int = 1;
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+    let mut nodemask = [0 as c_ulong; 16];
+    let bits = mem::size_of::<c_ulong>() * 8;
+
+    if node >= nodemask.len() * bits {
+        return Err(MbindFailed(libc::EINVAL));
+    }
+    nodemask[node / bits] |= 1 << (node % bits);
+
+    // Like libnuma, pass one more node than the mask holds, as the kernel ignores the last one
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            MPOL_PREFERRED,
+            nodemask.as_ptr(),
+            nodemask.len() * bits + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+
+    if ret == -1 {
+        return Err(MbindFailed(nix::errno::Errno::last_raw()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3"), Some(vec![3]));
+        assert_eq!(parse_cpu_list("0-3,8\n"), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("3-0"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+        assert_eq!(parse_cpu_list(""), None);
+
+        // CPU_SETSIZE is 1024
+        assert_eq!(parse_cpu_list("1023"), Some(vec![1023]));
+        assert_eq!(parse_cpu_list("1024"), None);
+        assert_eq!(parse_cpu_list("0-1024"), None);
+    }
+
+    #[test]
+    fn test_check_cpus() {
+        let online = parse_cpu_list("0-3").unwrap();
+
+        assert!(check_cpus(&[0, 3], &online).is_ok());
+        assert!(matches!(check_cpus(&[2, 4], &online), Err(OfflineCpu(4))));
+    }
+
+    #[test]
+    fn test_is_queue_irq() {
+        assert!(is_queue_irq("eth1-TxRx-3", "eth1", 3));
+        assert!(is_queue_irq("eth1-rx-12", "eth1", 12));
+        assert!(!is_queue_irq("eth1-TxRx-3", "eth1", 2));
+        assert!(!is_queue_irq("eth10-TxRx-3", "eth1", 3));
+        assert!(!is_queue_irq("eth1", "eth1", 1));
+    }
+
+    #[test]
+    fn test_socket_cpus() {
+        let mut cfg = Configuration::default();
+        cfg.set_queues(vec![0, 1]).set_socks_per_queue(2);
+        assert_eq!(socket_cpus(&cfg), vec![None; 4]);
+
+        cfg.set_cpu_affinity(CpuAffinity::Cpus(vec![4, 5, 6]));
+        assert_eq!(socket_cpus(&cfg), vec![Some(4), Some(5), Some(6), Some(4)]);
+    }
+}
@@ -17,26 +17,38 @@
 
 //! A type for dealing with XSK configuration.
 
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
 
 use crate::{
     xsk,
-    xsk::{net::NetAllocator, Error, Result},
+    xsk::{affinity, net::NetAllocator, Error, Result},
 };
 
 /// Configuration builder for an XSK object.
 pub struct Configuration {
     interface:     Option<String>,
     address:       Option<Ipv4Addr>,
+    address6:      Option<Ipv6Addr>,
     port:          Option<u16>,
+    vlan_addrs:    Vec<(Vlan, IpAddr)>,
+    listeners:     Vec<SocketAddr>,
+    all_protocols: bool,
     net_allocator: Option<Box<NetAllocator>>,
 
     xdp_prog_path:   String,
     queues:          Vec<usize>,
+    all_queues:      bool,
+    validate_queues: bool,
     socks_per_queue: usize,
     rx_size:         usize,
     tx_size:         usize,
     frame_size:      usize,
+    shared_umem:     bool,
+    cpu_affinity:    CpuAffinity,
     mode:            XskMode,
     needs_wakeup:    NeedsWakeup,
 }
@@ -47,15 +59,23 @@ impl Default for Configuration {
         Configuration {
             interface:     None,
             address:       None,
+            address6:      None,
             port:          None,
+            vlan_addrs:    Vec::new(),
+            listeners:     Vec::new(),
+            all_protocols: false,
             net_allocator: None,
 
             xdp_prog_path:   "./kern/xsk_kern.o".to_string(),
             queues:          vec![0],
+            all_queues:      false,
+            validate_queues: true,
             socks_per_queue: 1,
             rx_size:         xsk::sys::XSK_RING_PROD__DEFAULT_NUM_DESCS as usize,
             tx_size:         xsk::sys::XSK_RING_PROD__DEFAULT_NUM_DESCS as usize,
             frame_size:      xsk::sys::XSK_UMEM__DEFAULT_FRAME_SIZE as usize,
+            shared_umem:     false,
+            cpu_affinity:    CpuAffinity::None,
             mode:            XskMode::Skb,
             needs_wakeup:    NeedsWakeup::new(true),
         }
@@ -85,6 +105,17 @@ impl Configuration {
         self.address.unwrap()
     }
 
+    /// Set the listening IPv6 address.
+    pub fn set_bind_address6(&mut self, addr: Ipv6Addr) -> &mut Self {
+        self.address6 = Some(addr);
+        self
+    }
+
+    /// Get the listening IPv6 address, if any.
+    pub fn bind_address6(&self) -> Option<Ipv6Addr> {
+        self.address6
+    }
+
     /// Set the listening port.
     pub fn set_bind_port(&mut self, port: u16) -> &mut Self {
         self.port = Some(port);
@@ -96,6 +127,83 @@ impl Configuration {
         self.port.unwrap()
     }
 
+    /// Add a listening IPv4 or IPv6 address on a VLAN.
+    pub fn add_vlan_bind_address(&mut self, vlan: Vlan, addr: IpAddr) -> &mut Self {
+        self.vlan_addrs.push((vlan, addr));
+        self
+    }
+
+    /// Get the listening addresses of each VLAN.
+    pub fn vlan_bind_addresses(&self) -> &[(Vlan, IpAddr)] {
+        &self.vlan_addrs
+    }
+
+    /// Add a listening address and port, in addition to the bind addresses and port.
+    ///
+    /// The address is bound on the untagged network, unless it is also bound on a VLAN.
+    ///
+    /// Only the UDP datagrams for the listeners are redirected to the sockets, except for
+    /// fragmented IPv4 datagrams: the XDP program cannot match their port, so all the fragmented
+    /// datagrams sent to a bound address are handled by the network stack, and never reach the
+    /// kernel.
+    pub fn add_listener(&mut self, addr: SocketAddr) -> &mut Self {
+        self.listeners.push(addr);
+        self
+    }
+
+    /// Get all the listening addresses and ports: the bind addresses, including the ones of the
+    /// VLANs, with the bind port, followed by the additional listeners.
+    pub fn listeners(&self) -> Vec<SocketAddr> {
+        let mut listeners: Vec<SocketAddr> = Some(IpAddr::V4(self.bind_address()))
+            .into_iter()
+            .chain(self.address6.map(IpAddr::V6))
+            .chain(self.vlan_addrs.iter().map(|&(_, addr)| addr))
+            .map(|addr| SocketAddr::new(addr, self.bind_port()))
+            .collect();
+
+        for listener in &self.listeners {
+            if !listeners.contains(listener) {
+                listeners.push(*listener);
+            }
+        }
+
+        listeners
+    }
+
+    /// Set whether the IPv4 packets of every protocol sent to the bind addresses are redirected
+    /// to the sockets, so that the network stack answers the ones of unserved protocols with ICMP
+    /// protocol unreachable messages.
+    ///
+    /// By default only UDP datagrams for the listeners and the ICMP messages handled by the
+    /// network stack are redirected, everything else being left to the kernel.
+    pub fn set_all_protocols(&mut self, value: bool) -> &mut Self {
+        self.all_protocols = value;
+        self
+    }
+
+    /// Get whether the IPv4 packets of every protocol sent to the bind addresses are redirected
+    /// to the sockets.
+    pub fn all_protocols(&self) -> bool {
+        self.all_protocols
+    }
+
+    /// Get the listening addresses of the untagged network: the IPv4 and IPv6 bind addresses
+    /// and the addresses of the listeners which are not bound on a VLAN.
+    pub fn bind_addresses(&self) -> Vec<IpAddr> {
+        let mut addrs = vec![IpAddr::V4(self.bind_address())];
+        addrs.extend(self.address6.map(IpAddr::V6));
+
+        for listener in &self.listeners {
+            let addr = listener.ip();
+
+            if !addrs.contains(&addr) && self.vlan_addrs.iter().all(|&(_, a)| a != addr) {
+                addrs.push(addr);
+            }
+        }
+
+        addrs
+    }
+
     /// Set the NetAllocator callback.
     pub fn set_net_allocator(&mut self, net_allocator: Box<NetAllocator>) -> &mut Self {
         self.net_allocator = Some(net_allocator);
@@ -129,6 +237,31 @@ impl Configuration {
         self.queues.as_ref()
     }
 
+    /// Set whether sockets should be created on all the RX queues of the interface, as reported
+    /// by its channels configuration, instead of on the configured queues.
+    pub fn set_all_queues(&mut self, value: bool) -> &mut Self {
+        self.all_queues = value;
+        self
+    }
+
+    /// Get whether sockets should be created on all the RX queues of the interface.
+    pub fn all_queues(&self) -> bool {
+        self.all_queues
+    }
+
+    /// Set whether the configured queues should be checked against the RX queues of the
+    /// interface before creating the sockets. Failing to query the RX queues is then an error.
+    pub fn set_validate_queues(&mut self, value: bool) -> &mut Self {
+        self.validate_queues = value;
+        self
+    }
+
+    /// Get whether the configured queues should be checked against the RX queues of the
+    /// interface.
+    pub fn validate_queues(&self) -> bool {
+        self.validate_queues
+    }
+
     /// Set the number of XSK sockets per queue.
     pub fn set_socks_per_queue(&mut self, value: usize) -> &mut Self {
         self.socks_per_queue = value;
@@ -173,6 +306,31 @@ impl Configuration {
         self.frame_size
     }
 
+    /// Set whether all the queues share a single UMEM (`XDP_SHARED_UMEM`), rather than each
+    /// queue having its own. Frames can then move between queues, while each queue still has its
+    /// own fill and completion rings.
+    pub fn set_shared_umem(&mut self, value: bool) -> &mut Self {
+        self.shared_umem = value;
+        self
+    }
+
+    /// Get whether all the queues share a single UMEM.
+    pub fn shared_umem(&self) -> bool {
+        self.shared_umem
+    }
+
+    /// Set how the RX threads of the sockets are pinned to CPUs. Each UMEM is then allocated on
+    /// the NUMA node of the CPU of the first socket using it.
+    pub fn set_cpu_affinity(&mut self, value: CpuAffinity) -> &mut Self {
+        self.cpu_affinity = value;
+        self
+    }
+
+    /// Get how the RX threads of the sockets are pinned to CPUs.
+    pub fn cpu_affinity(&self) -> &CpuAffinity {
+        &self.cpu_affinity
+    }
+
     /// Set the the XSK mode of operation.
     pub fn set_mode(&mut self, value: XskMode) -> &mut Self {
         self.mode = value;
@@ -228,7 +386,7 @@ impl Configuration {
 }
 
 /// XSK mode of operation.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum XskMode {
     /// Skb mode.
     Skb,
@@ -238,6 +396,9 @@ pub enum XskMode {
 
     /// Zerocopy driver mode.
     DrvZeroCopy,
+
+    /// The first mode supported by the interface among zerocopy driver, driver and skb modes.
+    Auto,
 }
 
 impl XskMode {
@@ -245,7 +406,7 @@ impl XskMode {
     pub fn into_xdp_flags(self) -> u32 {
         match self {
             XskMode::Skb => xsk::sys::XDP_FLAGS_SKB_MODE,
-            XskMode::Drv | XskMode::DrvZeroCopy => xsk::sys::XDP_FLAGS_DRV_MODE,
+            XskMode::Drv | XskMode::DrvZeroCopy | XskMode::Auto => xsk::sys::XDP_FLAGS_DRV_MODE,
         }
     }
 
@@ -253,17 +414,50 @@ impl XskMode {
     pub fn into_bind_flags(self) -> u16 {
         match self {
             XskMode::Skb | XskMode::Drv => xsk::sys::XDP_COPY as u16,
-            XskMode::DrvZeroCopy => xsk::sys::XDP_ZEROCOPY as u16,
+            XskMode::DrvZeroCopy | XskMode::Auto => xsk::sys::XDP_ZEROCOPY as u16,
+        }
+    }
+
+    /// Returns the mode the XDP program is attached with.
+    pub fn into_attach_mode(self) -> xsk::sys::xdp_attach_mode {
+        match self {
+            XskMode::Skb => xsk::sys::xdp_attach_mode_XDP_MODE_SKB,
+            XskMode::Drv | XskMode::DrvZeroCopy | XskMode::Auto => {
+                xsk::sys::xdp_attach_mode_XDP_MODE_NATIVE
+            }
+        }
+    }
+
+    /// Returns the modes to try, in order, to run in this mode.
+    pub fn candidates(self) -> &'static [XskMode] {
+        match self {
+            XskMode::Skb => &[XskMode::Skb],
+            XskMode::Drv => &[XskMode::Drv],
+            XskMode::DrvZeroCopy => &[XskMode::DrvZeroCopy],
+            XskMode::Auto => &[XskMode::DrvZeroCopy, XskMode::Drv, XskMode::Skb],
         }
     }
 }
 
+impl fmt::Display for XskMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            XskMode::Skb => "skb",
+            XskMode::Drv => "drv",
+            XskMode::DrvZeroCopy => "drv-zc",
+            XskMode::Auto => "auto",
+        };
+
+        write!(f, "{}", mode)
+    }
+}
+
 impl FromStr for XskMode {
     type Err = Error;
 
     /// Creates a new XskMode object from a string.
     ///
-    /// Possible values for the input string are `skb`, `drv` and `drv-zc`.
+    /// Possible values for the input string are `skb`, `drv`, `drv-zc` and `auto`.
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         use XskMode::*;
 
@@ -271,11 +465,108 @@ impl FromStr for XskMode {
             "skb" => Ok(Skb),
             "drv" => Ok(Drv),
             "drv-zc" => Ok(DrvZeroCopy),
+            "auto" => Ok(Auto),
             _ => Err(Error::InvalidXskMode),
         }
     }
 }
 
+/// How the RX threads of the sockets are pinned to CPUs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuAffinity {
+    /// The threads are not pinned.
+    None,
+
+    /// The threads are pinned to the CPUs of the list in turn, one socket after the other.
+    Cpus(Vec<usize>),
+
+    /// The threads are pinned to the first CPU of the affinity of the IRQ of their queue, as
+    /// reported by `/proc`.
+    Irq,
+}
+
+impl FromStr for CpuAffinity {
+    type Err = Error;
+
+    /// Creates a new CpuAffinity object from a string.
+    ///
+    /// The input string is either `none`, `irq` or a CPU list, e.g. `0-3,8`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CpuAffinity::None),
+            "irq" => Ok(CpuAffinity::Irq),
+            _ => affinity::parse_cpu_list(s)
+                .map(CpuAffinity::Cpus)
+                .ok_or_else(|| Error::InvalidCpuList(s.to_string())),
+        }
+    }
+}
+
+/// A VLAN, identified by the VID of its 802.1Q tag and, for 802.1ad (QinQ) frames, by the VID of
+/// the outer service tag.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Vlan {
+    /// VID of the outer tag of a QinQ frame.
+    pub outer_vid: Option<u16>,
+    /// VID of the (inner) customer tag.
+    pub vid:       u16,
+}
+
+impl Vlan {
+    /// Creates a new Vlan object for frames with a single tag.
+    pub fn new(vid: u16) -> Self {
+        Vlan {
+            outer_vid: None,
+            vid:       vid & 0xfff,
+        }
+    }
+
+    /// Creates a new Vlan object for QinQ frames.
+    pub fn qinq(outer_vid: u16, vid: u16) -> Self {
+        Vlan {
+            outer_vid: Some(outer_vid & 0xfff),
+            vid:       vid & 0xfff,
+        }
+    }
+
+    /// Returns the key identifying the VLAN in the XDP program maps.
+    pub fn key(&self) -> u32 {
+        match self.outer_vid {
+            Some(outer_vid) => 1 << 24 | (outer_vid as u32) << 12 | self.vid as u32,
+            None => self.vid as u32,
+        }
+    }
+}
+
+impl FromStr for Vlan {
+    type Err = Error;
+
+    /// Creates a new Vlan object from a string.
+    ///
+    /// The input string is either a VID, e.g. `100`, or the outer and inner VIDs of a QinQ VLAN
+    /// separated by a dot, e.g. `10.100`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_vid = |vid: &str| match vid.parse::<u16>() {
+            Ok(vid) if vid > 0 && vid < 0xfff => Ok(vid),
+            _ => Err(Error::InvalidVlan(s.to_string())),
+        };
+
+        match s.split_once('.') {
+            Some((outer_vid, vid)) => Ok(Vlan::qinq(parse_vid(outer_vid)?, parse_vid(vid)?)),
+            None => Ok(Vlan::new(parse_vid(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for Vlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.outer_vid {
+            Some(outer_vid) => write!(f, "{}.{}", outer_vid, self.vid),
+            None => write!(f, "{}", self.vid),
+        }
+    }
+}
+
 /// Wrapper for the `XDP_USE_NEED_WAKEUP` flag.
 #[derive(Debug, Copy, Clone)]
 pub struct NeedsWakeup {
@@ -298,3 +589,78 @@ impl NeedsWakeup {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners() {
+        let mut cfg = Configuration::default();
+        cfg.set_bind_address("198.18.0.2".parse().unwrap())
+            .set_bind_port(1234)
+            .add_vlan_bind_address(Vlan::new(100), "198.18.100.2".parse().unwrap())
+            .add_listener("198.18.0.3:53".parse().unwrap())
+            .add_listener("198.18.0.2:1234".parse().unwrap())
+            .add_listener("198.18.100.2:53".parse().unwrap());
+
+        assert_eq!(
+            cfg.bind_addresses(),
+            vec![
+                "198.18.0.2".parse::<IpAddr>().unwrap(),
+                "198.18.0.3".parse().unwrap()
+            ]
+        );
+
+        assert_eq!(
+            cfg.listeners(),
+            vec![
+                "198.18.0.2:1234".parse::<SocketAddr>().unwrap(),
+                "198.18.100.2:1234".parse().unwrap(),
+                "198.18.0.3:53".parse().unwrap(),
+                "198.18.100.2:53".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cpu_affinity_from_str() {
+        assert_eq!("none".parse::<CpuAffinity>().unwrap(), CpuAffinity::None);
+        assert_eq!("irq".parse::<CpuAffinity>().unwrap(), CpuAffinity::Irq);
+        assert_eq!(
+            "0-2,6".parse::<CpuAffinity>().unwrap(),
+            CpuAffinity::Cpus(vec![0, 1, 2, 6])
+        );
+        assert!("0-".parse::<CpuAffinity>().is_err());
+    }
+
+    #[test]
+    fn test_vlan_from_str() {
+        assert_eq!("100".parse::<Vlan>().unwrap(), Vlan::new(100));
+        assert_eq!("10.100".parse::<Vlan>().unwrap(), Vlan::qinq(10, 100));
+        assert_eq!(
+            "10.100".parse::<Vlan>().unwrap().key(),
+            1 << 24 | 10 << 12 | 100
+        );
+
+        assert!("0".parse::<Vlan>().is_err());
+        assert!("4095".parse::<Vlan>().is_err());
+        assert!("10.".parse::<Vlan>().is_err());
+    }
+
+    #[test]
+    fn test_xsk_mode() {
+        for mode in XskMode::Auto.candidates() {
+            assert_eq!(mode.to_string().parse::<XskMode>().unwrap(), *mode);
+        }
+
+        assert_eq!(
+            XskMode::Auto.candidates(),
+            &[XskMode::DrvZeroCopy, XskMode::Drv, XskMode::Skb]
+        );
+        assert_eq!(
+            XskMode::Drv.into_attach_mode(),
+            xsk::sys::xdp_attach_mode_XDP_MODE_NATIVE
+        );
+    }
+}
@@ -17,7 +17,10 @@
 
 //! XDP descriptor.
 
-use std::{rc::Rc, sync::RwLock};
+use std::{
+    cell::UnsafeCell,
+    sync::{Arc, Mutex},
+};
 
 use crate::{xsk, xsk::FrameAllocator};
 
@@ -26,16 +29,71 @@ use crate::{xsk, xsk::FrameAllocator};
 /// A descriptor contains:
 /// * an address used to reference a particular frame in the UMEM memory buffer
 /// * the length of the frame
+///
+/// TX descriptors belong to the [`TxDescTable`] of their socket: dropping one without
+/// transmitting it gives it back to the table, and its frame back to the frame allocator (or to
+/// the RX ring, for a received frame transmitted in place).
 pub struct Desc {
-    frame_allocator: Rc<RwLock<FrameAllocator>>,
+    frame_allocator: Arc<FrameAllocator>,
     desc:            *mut xsk::sys::xdp_desc,
     index:           usize,
+    tx_table:        Option<Arc<TxDescTable>>,
+    // Whether the frame is a received one, still owned by the RX ring
+    rx_frame:        bool,
+}
+
+/// The TX descriptors of a [`TxSocket`](crate::xsk::TxSocket).
+///
+/// They live outside of the TX ring so that the network stack can hold on to a frame (e.g.
+/// while resolving a neighbor) without stalling the transmission of the following ones.
+pub struct TxDescTable {
+    descs:      Box<[UnsafeCell<xsk::sys::xdp_desc>]>,
+    free_descs: Mutex<Vec<usize>>,
+}
+
+// Each descriptor is only accessed through the `Desc` object holding it
+unsafe impl Send for TxDescTable {}
+unsafe impl Sync for TxDescTable {}
+
+impl TxDescTable {
+    /// Creates a new [`TxDescTable`] object with `size` descriptors.
+    pub fn new(size: usize) -> Self {
+        let descs = (0..size)
+            .map(|_| {
+                UnsafeCell::new(xsk::sys::xdp_desc {
+                    addr:    0,
+                    len:     0,
+                    options: 0,
+                })
+            })
+            .collect();
+
+        TxDescTable {
+            descs,
+            free_descs: Mutex::new((0..size).rev().collect()),
+        }
+    }
+
+    /// Takes a free descriptor from the table and returns its index.
+    pub fn alloc(&self) -> Option<usize> {
+        self.free_descs.lock().unwrap().pop()
+    }
+
+    /// Gives the descriptor with index `index` back to the table.
+    pub fn free(&self, index: usize) {
+        self.free_descs.lock().unwrap().push(index);
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn num_free(&self) -> usize {
+        self.free_descs.lock().unwrap().len()
+    }
 }
 
 impl Desc {
     /// Wraps an `xdp_desc` descriptor around a new [`Desc`] object.
     pub fn new_from_xdp_desc(
-        frame_allocator: Rc<RwLock<FrameAllocator>>,
+        frame_allocator: Arc<FrameAllocator>,
         desc: *mut xsk::sys::xdp_desc,
         index: usize,
     ) -> Self {
@@ -43,16 +101,81 @@ impl Desc {
             frame_allocator,
             desc,
             index,
+            tx_table: None,
+            rx_frame: false,
+        }
+    }
+
+    /// Wraps the descriptor with index `index` of `tx_table`, allocated with
+    /// [`TxDescTable::alloc`], around a new [`Desc`] object pointing to the `len` bytes at
+    /// `addr`.
+    pub fn new_tx(
+        frame_allocator: Arc<FrameAllocator>,
+        tx_table: Arc<TxDescTable>,
+        index: usize,
+        addr: u64,
+        len: usize,
+    ) -> Self {
+        let desc = tx_table.descs[index].get();
+        unsafe {
+            (*desc).addr = addr;
+            (*desc).len = len as u32;
+        }
+
+        Desc {
+            frame_allocator,
+            desc,
+            index,
+            tx_table: Some(tx_table),
+            rx_frame: false,
+        }
+    }
+
+    /// Like [`Desc::new_tx`], for a descriptor transmitting in place the packet received in the
+    /// frame at `addr`.
+    ///
+    /// The frame stays with the RX ring, which releases it once the packet has been processed,
+    /// until [`Desc::claim_rx_frame`] hands it over to the TX ring.
+    pub fn new_tx_in_place(
+        frame_allocator: Arc<FrameAllocator>,
+        tx_table: Arc<TxDescTable>,
+        index: usize,
+        addr: u64,
+        len: usize,
+    ) -> Self {
+        let mut desc = Self::new_tx(frame_allocator, tx_table, index, addr, len);
+        desc.rx_frame = true;
+
+        desc
+    }
+
+    /// Hands the received frame of a descriptor transmitting in place over to the TX ring, right
+    /// before placing the descriptor there.
+    pub fn claim_rx_frame(&mut self) {
+        if self.rx_frame {
+            self.frame_allocator.claim_rx_frame(self.addr());
+            self.rx_frame = false;
         }
     }
 
+    /// Gives a TX descriptor back to its table once it has been placed in the TX ring. Its frame
+    /// now belongs to the ring, and returns to the frame allocator through the completion ring.
+    pub fn release_to_tx_ring(mut self) {
+        if let Some(tx_table) = self.tx_table.take() {
+            tx_table.free(self.index);
+        }
+    }
+
+    /// Returns the address of the descriptor's frame in the UMEM memory buffer.
+    pub fn addr(&self) -> u64 {
+        unsafe { (*self.desc).addr }
+    }
+
     /// Returns a pointer to the descriptor's packet buffer.
     pub fn packet(&self) -> *mut u8 {
-        let frame_allocator = self.frame_allocator.read().unwrap();
-
         unsafe {
             let addr = (*self.desc).addr;
-            xsk::sys::xsk_umem__get_data(frame_allocator.buffer, addr) as *mut u8
+            xsk::sys::xsk_umem__get_data(self.frame_allocator.buffer, addr) as *mut u8
         }
     }
 
@@ -71,24 +194,38 @@ impl Desc {
         unsafe { (*self.desc).len = len as u32 }
     }
 
-    /// Return the position of the descriptor inside the ring.
+    /// Return the position of the descriptor inside the ring (or inside the descriptors table of
+    /// the [`TxSocket`](crate::xsk::TxSocket) for TX descriptors).
     pub fn index(&self) -> usize {
         self.index
     }
 }
 
+impl Drop for Desc {
+    fn drop(&mut self) {
+        // A TX descriptor dropped before being transmitted gives back its frame too, unless it
+        // is still owned by the RX ring
+        if let Some(tx_table) = self.tx_table.take() {
+            if !self.rx_frame {
+                self.frame_allocator.free_frame(self.addr());
+            }
+            tx_table.free(self.index);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::xsk::FrameOwner;
     use std::ptr;
-    use std::sync::RwLock;
 
     #[test]
     fn test_new() {
         let frame_allocator = FrameAllocator::new(4096, 4096);
         assert!(frame_allocator.is_ok());
 
-        let frame_allocator = Rc::new(RwLock::new(frame_allocator.unwrap()));
+        let frame_allocator = Arc::new(frame_allocator.unwrap());
 
         let mut xdp_desc = xsk::sys::xdp_desc {
             addr:    0,
@@ -105,7 +242,7 @@ mod tests {
         let frame_allocator = FrameAllocator::new(4096, 4096);
         assert!(frame_allocator.is_ok());
 
-        let frame_allocator = Rc::new(RwLock::new(frame_allocator.unwrap()));
+        let frame_allocator = Arc::new(frame_allocator.unwrap());
 
         let mut xdp_desc = xsk::sys::xdp_desc {
             addr:    0,
@@ -119,4 +256,75 @@ mod tests {
         desc.set_len(80);
         assert_eq!(desc.len(), 80);
     }
+
+    #[test]
+    fn test_tx_desc_drop() {
+        let frame_allocator = Arc::new(FrameAllocator::new(2, 4096).unwrap());
+        let tx_table = Arc::new(TxDescTable::new(2));
+
+        // An unsent descriptor gives back its slot and its frame
+        let addr = frame_allocator.alloc_frame(FrameOwner::TxRing).unwrap();
+        let index = tx_table.alloc().unwrap();
+        let desc = Desc::new_tx(frame_allocator.clone(), tx_table.clone(), index, addr, 0);
+        assert_eq!(tx_table.num_free(), 1);
+
+        drop(desc);
+        assert_eq!(tx_table.num_free(), 2);
+        assert_eq!(frame_allocator.owner(addr), FrameOwner::Allocator);
+
+        // A transmitted one leaves its frame to the TX ring
+        let addr = frame_allocator.alloc_frame(FrameOwner::TxRing).unwrap();
+        let index = tx_table.alloc().unwrap();
+        let desc = Desc::new_tx(frame_allocator.clone(), tx_table.clone(), index, addr, 0);
+
+        desc.release_to_tx_ring();
+        assert_eq!(tx_table.num_free(), 2);
+        assert_eq!(frame_allocator.owner(addr), FrameOwner::TxRing);
+    }
+
+    #[test]
+    fn test_tx_in_place_desc_drop() {
+        let frame_allocator = Arc::new(FrameAllocator::new(2, 4096).unwrap());
+        let tx_table = Arc::new(TxDescTable::new(2));
+
+        let addr = frame_allocator.alloc_frame(FrameOwner::FillRing).unwrap();
+        frame_allocator.receive_frame(addr);
+
+        // An unsent descriptor leaves the received frame to the RX ring, which releases it once
+        let index = tx_table.alloc().unwrap();
+        let desc = Desc::new_tx_in_place(
+            frame_allocator.clone(),
+            tx_table.clone(),
+            index,
+            addr + 64,
+            0,
+        );
+
+        drop(desc);
+        assert_eq!(tx_table.num_free(), 2);
+        assert_eq!(frame_allocator.owner(addr), FrameOwner::RxRing);
+
+        frame_allocator.release_rx_frame(addr);
+        assert_eq!(frame_allocator.owner(addr), FrameOwner::Allocator);
+
+        // A transmitted one takes the frame over from the RX ring
+        let addr = frame_allocator.alloc_frame(FrameOwner::FillRing).unwrap();
+        frame_allocator.receive_frame(addr);
+
+        let index = tx_table.alloc().unwrap();
+        let mut desc = Desc::new_tx_in_place(
+            frame_allocator.clone(),
+            tx_table.clone(),
+            index,
+            addr + 64,
+            0,
+        );
+
+        desc.claim_rx_frame();
+        desc.release_to_tx_ring();
+        assert_eq!(tx_table.num_free(), 2);
+
+        frame_allocator.release_rx_frame(addr);
+        assert_eq!(frame_allocator.owner(addr), FrameOwner::TxRing);
+    }
 }
@@ -33,8 +33,18 @@ pub enum Error {
     SetrlimitFailed(i32),
     #[error("Invalid XSK mode")]
     InvalidXskMode,
+    #[error("Invalid CPU list {}", .0)]
+    InvalidCpuList(String),
+    #[error("CPU {} is not online", .0)]
+    OfflineCpu(usize),
+    #[error("Invalid VLAN {}", .0)]
+    InvalidVlan(String),
     #[error("Invalid XSK config: missing {}", .0)]
     InvalidConfigWithMissingProperty(String),
+    #[error("Failed to query the interface channels: {}", errno_to_str(.0))]
+    EthtoolFailed(i32),
+    #[error("Invalid queue {}: only the first {} RX queues can be used", .0, .1)]
+    InvalidQueue(usize, usize),
     #[error("Failed to load BPF program: {}", errno_to_str(.0))]
     BpfProgLoadFailed(i32),
     #[error("Failed to attach XDP program to interface: {}", errno_to_str(.0))]
@@ -45,8 +55,14 @@ pub enum Error {
     SetMapFailed(String, i32),
     #[error("Failed to initialise frame allocator: {}", errno_to_str(.0))]
     FrameAllocatorAllocationFailed(i32),
+    #[error("Failed to pin thread: {}", errno_to_str(.0))]
+    SchedSetaffinityFailed(i32),
+    #[error("Failed to set the NUMA memory policy of the UMEM: {}", errno_to_str(.0))]
+    MbindFailed(i32),
     #[error("Failed to create XSK socket: {}", errno_to_str(.0))]
     XskSocketCreateFailed(i32),
+    #[error("Failed to get XSK socket options: {}", errno_to_str(.0))]
+    XskSocketGetOptFailed(i32),
     #[error("Failed to create umem socket: {}", errno_to_str(.0))]
     XskUmemCreateFailed(i32),
     #[error("Failed to reserve descriptors in TX ring")]
@@ -57,6 +73,10 @@ pub enum Error {
     XskSocketPollFailed(i32),
     #[error("poll() on umem socket returned -1: {}", errno_to_str(.0))]
     XskUmemPollFailed(i32),
+    #[error("No free descriptors available for TX")]
+    XskTxNoFreeDescs,
+    #[error("No free frames available for TX")]
+    XskTxNoFreeFrames,
     #[error("sendto() returned -1: {}", errno_to_str(.0))]
     XskTxSendtoFailed(i32),
 }
@@ -0,0 +1,106 @@
+// Copyright (C) 2020 Gilberto "jibi" Bertin <me@jibi.io>
+//
+// This file is part of hydrogen peroxyde.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Queries the configuration of a network interface via the ethtool ioctl.
+
+use libc::{c_char, c_void};
+
+use crate::xsk::{Error::*, Result};
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+
+/// The `struct ethtool_channels` of `linux/ethtool.h`.
+#[repr(C)]
+#[derive(Default)]
+struct EthtoolChannels {
+    cmd:            u32,
+    max_rx:         u32,
+    max_tx:         u32,
+    max_other:      u32,
+    max_combined:   u32,
+    rx_count:       u32,
+    tx_count:       u32,
+    other_count:    u32,
+    combined_count: u32,
+}
+
+/// The `struct ifreq` of `linux/if.h`, with the `ifr_data` member of its union.
+#[repr(C)]
+struct Ifreq {
+    name: [c_char; libc::IFNAMSIZ],
+    data: *mut c_void,
+    _pad: [u8; 16],
+}
+
+/// Returns the number of RX queues of `interface`, that is its RX-only and combined channels,
+/// or `None` if the driver does not report its channels.
+pub fn rx_queues(interface: &str) -> Result<Option<usize>> {
+    if interface.len() >= libc::IFNAMSIZ {
+        return Err(EthtoolFailed(libc::ENODEV));
+    }
+
+    let mut channels = EthtoolChannels {
+        cmd: ETHTOOL_GCHANNELS,
+        ..Default::default()
+    };
+
+    let mut ifr = Ifreq {
+        name: [0; libc::IFNAMSIZ],
+        data: &mut channels as *mut EthtoolChannels as *mut c_void,
+        _pad: [0; 16],
+    };
+    for (dst, src) in ifr.name.iter_mut().zip(interface.bytes()) {
+        *dst = src as c_char;
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(EthtoolFailed(nix::errno::Errno::last_raw()));
+    }
+
+    let ret = unsafe { libc::ioctl(fd, SIOCETHTOOL as _, &mut ifr as *mut Ifreq) };
+    let errno = nix::errno::Errno::last_raw();
+
+    unsafe {
+        libc::close(fd);
+    }
+
+    if ret < 0 {
+        // Virtual devices such as veth may not implement the channels API
+        if errno == libc::EOPNOTSUPP {
+            return Ok(None);
+        }
+
+        return Err(EthtoolFailed(errno));
+    }
+
+    Ok(Some((channels.rx_count + channels.combined_count) as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_ifreq_layout() {
+        assert_eq!(mem::size_of::<EthtoolChannels>(), 36);
+        assert_eq!(mem::size_of::<Ifreq>(), 40);
+    }
+}
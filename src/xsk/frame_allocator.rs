@@ -19,18 +19,48 @@
 
 use libc::{sysconf, _SC_PAGESIZE};
 
-use std::ptr;
+use std::{ptr, sync::Mutex};
 
 use crate::xsk::{Error::*, Result};
 
+/// The owner of an UMEM frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameOwner {
+    /// The frame is in the free list of the allocator.
+    Allocator,
+    /// The frame is in the fill ring, or has been filled by the kernel and is in the RX ring.
+    FillRing,
+    /// The frame has been consumed from the RX ring and is being processed.
+    RxRing,
+    /// The frame belongs to a TX descriptor, which is in the TX ring or will be placed there, or
+    /// is waiting in the completion ring.
+    TxRing,
+}
+
 /// A memory allocator to allocate frame buffers for [`ProdRing`](crate::xsk::ring::ProdRing) rings.
+///
+/// The allocator keeps track of the owner of each frame: frames move from the free list to the
+/// fill or TX rings and back, and a frame received on the RX ring can be handed over to the TX
+/// ring to be transmitted in place. Debug builds assert that each transition starts from the
+/// expected owner, so that a frame is never owned by two rings at once.
+///
+/// The allocator can be shared by the sockets of several queues, each running in its own
+/// thread.
 pub struct FrameAllocator {
     pub buffer: *mut libc::c_void,
 
-    frame_addr: Vec<u64>,
+    frame_size: usize,
+    frames:     Mutex<Frames>,
 }
 
+struct Frames {
+    addrs:  Vec<u64>,
+    owners: Vec<FrameOwner>,
+}
+
+// The buffer is never reallocated and the frames are only accessed by their owner
 unsafe impl Send for FrameAllocator {}
+unsafe impl Sync for FrameAllocator {}
 
 impl FrameAllocator {
     /// Creates a new [`FrameAllocator`] object.
@@ -45,17 +75,99 @@ impl FrameAllocator {
             }
         }
 
-        let mut frame_addr = Vec::new();
+        let mut addrs = Vec::new();
         for i in (0..num_frames).rev() {
-            frame_addr.push((i * frame_size) as u64);
+            addrs.push((i * frame_size) as u64);
+        }
+
+        Ok(FrameAllocator {
+            buffer,
+            frame_size,
+            frames: Mutex::new(Frames {
+                addrs,
+                owners: vec![FrameOwner::Allocator; num_frames],
+            }),
+        })
+    }
+
+    /// Allocates a new frame for `owner` and return its address.
+    pub fn alloc_frame(&self, owner: FrameOwner) -> Option<u64> {
+        let mut frames = self.frames.lock().unwrap();
+
+        let addr = frames.addrs.pop()?;
+        self.transfer(&mut frames, addr, FrameOwner::Allocator, owner);
+
+        Some(addr)
+    }
+
+    /// Returns the frame containing address `addr`, owned by the RX or TX ring, to the
+    /// allocator.
+    pub fn free_frame(&self, addr: u64) {
+        self.free(&mut self.frames.lock().unwrap(), addr);
+    }
+
+    /// Records that the frame containing address `addr` has been received on the RX ring.
+    pub fn receive_frame(&self, addr: u64) {
+        let mut frames = self.frames.lock().unwrap();
+        self.transfer(&mut frames, addr, FrameOwner::FillRing, FrameOwner::RxRing);
+    }
+
+    /// Hands the frame containing address `addr`, received on the RX ring, over to the TX ring.
+    /// It is then returned to the allocator once transmitted.
+    pub fn claim_rx_frame(&self, addr: u64) {
+        let mut frames = self.frames.lock().unwrap();
+        self.transfer(&mut frames, addr, FrameOwner::RxRing, FrameOwner::TxRing);
+    }
+
+    /// Returns the frame containing address `addr`, whose RX descriptor has been consumed, to
+    /// the allocator, unless it was handed over to the TX ring.
+    pub fn release_rx_frame(&self, addr: u64) {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.owners[self.frame_index(addr)] == FrameOwner::RxRing {
+            self.free(&mut frames, addr);
         }
+    }
+
+    /// Returns the owner of the frame containing address `addr`.
+    pub fn owner(&self, addr: u64) -> FrameOwner {
+        self.frames.lock().unwrap().owners[self.frame_index(addr)]
+    }
+
+    fn frame_index(&self, addr: u64) -> usize {
+        addr as usize / self.frame_size
+    }
+
+    fn free(&self, frames: &mut Frames, addr: u64) {
+        // Received and transmitted packets may start anywhere in their frame
+        let frame = addr - addr % self.frame_size as u64;
+
+        let owner = &mut frames.owners[self.frame_index(frame)];
+        debug_assert!(
+            *owner == FrameOwner::RxRing || *owner == FrameOwner::TxRing,
+            "freeing frame 0x{:x} owned by {:?}",
+            frame,
+            owner
+        );
+        *owner = FrameOwner::Allocator;
 
-        Ok(FrameAllocator { buffer, frame_addr })
+        frames.addrs.push(frame);
     }
 
-    /// Allocates a new frame and return its address.
-    pub fn alloc_frame(&mut self) -> Option<u64> {
-        self.frame_addr.pop()
+    /// Moves the frame containing address `addr` from `from` to `to`.
+    fn transfer(&self, frames: &mut Frames, addr: u64, from: FrameOwner, to: FrameOwner) {
+        let owner = &mut frames.owners[self.frame_index(addr)];
+        debug_assert_eq!(
+            *owner, from,
+            "frame 0x{:x} owned by {:?} rather than {:?}",
+            addr, owner, from
+        );
+        *owner = to;
+    }
+}
+
+impl Drop for FrameAllocator {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.buffer) };
     }
 }
 
@@ -76,21 +188,65 @@ mod tests {
         let frame_allocator = FrameAllocator::new(2, 4096);
         assert!(frame_allocator.is_ok());
 
-        let mut frame_allocator = frame_allocator.unwrap();
+        let frame_allocator = frame_allocator.unwrap();
 
-        let frame = frame_allocator.alloc_frame();
+        let frame = frame_allocator.alloc_frame(FrameOwner::TxRing);
         assert!(frame.is_some());
 
         let frame = frame.unwrap();
         assert_eq!(frame, 0);
 
-        let frame = frame_allocator.alloc_frame();
+        let frame = frame_allocator.alloc_frame(FrameOwner::TxRing);
         assert!(frame.is_some());
 
         let frame = frame.unwrap();
         assert_eq!(frame, 4096);
 
-        let frame = frame_allocator.alloc_frame();
+        let frame = frame_allocator.alloc_frame(FrameOwner::TxRing);
         assert!(frame.is_none());
     }
+
+    #[test]
+    fn test_frame_allocator_free_frame() {
+        let frame_allocator = FrameAllocator::new(1, 4096).unwrap();
+
+        let frame = frame_allocator.alloc_frame(FrameOwner::TxRing).unwrap();
+        assert!(frame_allocator.alloc_frame(FrameOwner::TxRing).is_none());
+
+        frame_allocator.free_frame(frame);
+        assert_eq!(frame_allocator.alloc_frame(FrameOwner::TxRing), Some(frame));
+    }
+
+    #[test]
+    fn test_frame_allocator_rx_frame() {
+        let frame_allocator = FrameAllocator::new(2, 4096).unwrap();
+        let rx_frame = frame_allocator.alloc_frame(FrameOwner::FillRing).unwrap();
+
+        // Received packets start after the headroom of the frame
+        frame_allocator.receive_frame(rx_frame + 256);
+        assert_eq!(frame_allocator.owner(rx_frame), FrameOwner::RxRing);
+        frame_allocator.release_rx_frame(rx_frame + 256);
+        assert_eq!(frame_allocator.owner(rx_frame), FrameOwner::Allocator);
+
+        let rx_frame = frame_allocator.alloc_frame(FrameOwner::FillRing).unwrap();
+        frame_allocator.receive_frame(rx_frame + 256);
+        frame_allocator.claim_rx_frame(rx_frame + 256);
+        frame_allocator.release_rx_frame(rx_frame + 256);
+        assert_eq!(frame_allocator.owner(rx_frame), FrameOwner::TxRing);
+
+        // Once transmitted, the claimed frame goes back to the allocator
+        frame_allocator.free_frame(rx_frame + 320);
+        assert_eq!(frame_allocator.owner(rx_frame), FrameOwner::Allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_frame_allocator_double_free() {
+        let frame_allocator = FrameAllocator::new(1, 4096).unwrap();
+
+        let frame = frame_allocator.alloc_frame(FrameOwner::TxRing).unwrap();
+        frame_allocator.free_frame(frame);
+        frame_allocator.free_frame(frame);
+    }
 }
@@ -19,6 +19,8 @@
 
 #![warn(missing_docs)]
 
+mod affinity;
+
 mod configuration;
 pub use self::configuration::*;
 
@@ -28,6 +30,8 @@ pub use self::desc::*;
 mod error;
 use self::error::*;
 
+mod ethtool;
+
 mod frame_allocator;
 use self::frame_allocator::*;
 
@@ -49,6 +53,7 @@ mod umem;
 use self::umem::*;
 
 mod xdp_prog;
+pub use self::xdp_prog::ListenerMaps;
 use self::xdp_prog::*;
 
 use std::{
@@ -58,7 +63,7 @@ use std::{
     rc::Rc,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     thread,
 };
@@ -66,44 +71,176 @@ use std::{
 /// Controls how many packets in a row can be received and transmitted.
 pub const BATCH_SIZE: usize = 64;
 
+/// Controls how long (in milliseconds) the RX loop waits for new packets before invoking the
+/// network stack timers.
+pub const POLL_TIMEOUT_MS: i32 = 100;
+
 /// The main XSK object.
 #[allow(dead_code)]
 pub struct Xsk {
     xdp_prog:       XdpProg,
     threads_runner: ThreadsRunner,
+    mode:           XskMode,
 }
 
 unsafe impl Send for Xsk {}
 
 impl Xsk {
     /// Creates a new [`Xsk`] object.
-    pub fn new(configuration: Configuration) -> Result<Self> {
+    pub fn new(mut configuration: Configuration) -> Result<Self> {
+        Self::resolve_queues(&mut configuration)?;
+        affinity::validate_cpus(&configuration)?;
         let configuration = Rc::new(configuration);
+        let cpus = affinity::socket_cpus(&configuration);
 
         let mut threads_runner = ThreadsRunner::new();
-        let mut queues = Queues::default();
 
-        for queue_num in configuration.queues() {
-            let cfg = configuration.clone();
-            queues.push(Queue::new(cfg, *queue_num, &threads_runner)?);
+        // Try each mode in turn, until the XDP program can be attached and the sockets bound. A
+        // failed attempt deletes its sockets and UMEMs and detaches the program when dropped
+        let mut setup = Err(Error::InvalidXskMode);
+        for &mode in configuration.mode().candidates() {
+            setup = Self::setup(&configuration, mode, &cpus, &threads_runner);
+            match &setup {
+                Ok(_) => break,
+                Err(e) => warn!("Cannot run in {} XSK mode: {}", mode, e),
+            }
         }
+        let (xdp_prog, queues, mode) = setup?;
 
-        let xdp_prog = XdpProg::load(&configuration, &queues)?;
+        // The sockets may have fallen back to copy mode
+        let mode = match QueuesSocketsRef::from(&queues).into_iter().next() {
+            Some(socket) if mode != XskMode::Skb && socket.zero_copy()? => XskMode::DrvZeroCopy,
+            _ if mode == XskMode::Skb => XskMode::Skb,
+            _ => XskMode::Drv,
+        };
+        info!("Running in {} XSK mode", mode);
 
-        for (socket_idx, mut socket) in QueuesSockets::from(queues).into_iter().enumerate() {
-            let net = (configuration.net_allocator())(socket.take_tx_socket().into());
+        let listener_maps = Arc::new(xdp_prog.listener_maps()?);
 
-            threads_runner.spawn(format!("socket {} RX loop", socket_idx), move |runner| {
-                RxSocket::rx_loop(runner, net, socket.take_rx_socket())
-            });
+        for (mut socket, cpu) in QueuesSockets::from(queues).into_iter().zip(cpus) {
+            let handle = net::Handle::new(socket.take_tx_socket(), listener_maps.clone());
+            let net = (configuration.net_allocator())(handle);
+
+            threads_runner.spawn(
+                format!("socket {} RX loop", socket.index()),
+                cpu,
+                move |runner| RxSocket::rx_loop(runner, net, socket.take_rx_socket()),
+            );
         }
 
         Ok(Xsk {
             xdp_prog,
             threads_runner,
+            mode,
         })
     }
 
+    /// Attaches the XDP program and creates the sockets of all the queues in `mode`, allocating
+    /// each UMEM on the NUMA node of `cpus`, the CPUs of the sockets.
+    fn setup(
+        configuration: &Rc<Configuration>,
+        mode: XskMode,
+        cpus: &[Option<usize>],
+        threads_runner: &ThreadsRunner,
+    ) -> Result<(XdpProg, Queues, XskMode)> {
+        let xdp_prog = XdpProg::load(configuration, mode)?;
+        let mut queues = Queues::default();
+
+        // With a shared UMEM, the first queue creates it and the other ones bind to it
+        let mut shared_umem: Option<Arc<Mutex<Umem>>> = None;
+
+        for (i, queue_num) in configuration.queues().iter().enumerate() {
+            let umem = match &shared_umem {
+                Some(umem) => Umem::new_shared(configuration, umem),
+                None => {
+                    let cpu = cpus[i * configuration.socks_per_queue()];
+                    Umem::new(configuration, cpu.and_then(affinity::cpu_node))?
+                }
+            };
+            let umem = Arc::new(Mutex::new(umem));
+
+            if configuration.shared_umem() && shared_umem.is_none() {
+                shared_umem = Some(umem.clone());
+            }
+
+            let cfg = configuration.clone();
+            // The XDP program redirects the packets of a queue to the sockets starting at this
+            // index
+            let first_index = queue_num * configuration.socks_per_queue();
+            queues.push(Queue::new(
+                cfg,
+                *queue_num,
+                first_index,
+                umem,
+                mode,
+                threads_runner,
+            )?);
+        }
+
+        xdp_prog.load_maps(configuration, &queues)?;
+
+        Ok((xdp_prog, queues, mode))
+    }
+
+    /// Replaces the configured queues with all the RX queues of the interface when
+    /// [`Configuration::all_queues`] is set, or checks that they all exist when
+    /// [`Configuration::validate_queues`] is set, then checks that their sockets fit in the XDP
+    /// program `xsks_map` map.
+    ///
+    /// Failing to query the RX queues of the interface is an error when
+    /// [`Configuration::validate_queues`] is set. Otherwise, or if the driver does not report its
+    /// channels, the configured queues are used as they are, and [`Configuration::all_queues`]
+    /// only runs on queue 0.
+    fn resolve_queues(configuration: &mut Configuration) -> Result<()> {
+        if configuration.all_queues() || configuration.validate_queues() {
+            let rx_queues = match ethtool::rx_queues(configuration.interface()) {
+                Ok(rx_queues) => rx_queues,
+                Err(e) if configuration.validate_queues() => return Err(e),
+                Err(e) => {
+                    error!(
+                        "Cannot get the RX queues of {}: {}",
+                        configuration.interface(),
+                        e
+                    );
+                    None
+                }
+            };
+
+            if configuration.all_queues() {
+                // Without channels information, assume a single queue device
+                let rx_queues = rx_queues.unwrap_or_else(|| {
+                    warn!(
+                        "Cannot find the RX queues of {}, running on queue 0 only",
+                        configuration.interface()
+                    );
+                    1
+                });
+                info!("Running on {} RX queues", rx_queues);
+
+                configuration.set_queues((0..rx_queues).collect());
+            } else if let Some(rx_queues) = rx_queues {
+                if let Some(&queue) = configuration.queues().iter().find(|&&q| q >= rx_queues) {
+                    return Err(Error::InvalidQueue(queue, rx_queues));
+                }
+            }
+        }
+
+        // The sockets of a queue use `socks_per_queue` consecutive entries of the map, starting at
+        // the queue number times `socks_per_queue`
+        let max_queues = XSKS_MAP_SIZE / configuration.socks_per_queue().max(1);
+        if let Some(&queue) = configuration.queues().iter().find(|&&q| q >= max_queues) {
+            return Err(Error::InvalidQueue(queue, max_queues));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the XSK mode of operation obtained, which may differ from the configured one when
+    /// it is [`XskMode::Auto`] or when the driver falls back to copy mode.
+    pub fn mode(&self) -> XskMode {
+        self.mode
+    }
+
     /// Returns the runner associated with the XSK object.
     pub fn runner(&mut self) -> Runner {
         self.threads_runner.runner.clone()
@@ -142,6 +279,7 @@ impl Drop for Xsk {
 #[derive(Clone)]
 pub struct Runner {
     running:  Arc<AtomicBool>,
+    failed:   Arc<AtomicBool>,
     pipe_fds: [i32; 2],
 }
 
@@ -154,6 +292,7 @@ impl Default for Runner {
 
         Runner {
             running: Arc::new(AtomicBool::new(true)),
+            failed: Arc::new(AtomicBool::new(false)),
             pipe_fds,
         }
     }
@@ -170,17 +309,27 @@ impl Runner {
         self.pipe_fds[0]
     }
 
+    /// Returns true if the runner was stopped because a network stack failed.
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
     /// Stops the runner.
     pub fn stop(&mut self) {
-        if !self.is_running() {
+        // Only the first caller wakes the threads up, as the control pipe is closed after that
+        if !self.running.swap(false, Ordering::SeqCst) {
             return;
         }
 
-        self.running.store(false, Ordering::SeqCst);
-
         let mut writer = unsafe { File::from_raw_fd(self.pipe_fds[1]) };
         writer.write_all(b"kthxbye").unwrap();
     }
+
+    /// Stops the runner, marking it as failed.
+    pub fn fail(&mut self) {
+        self.failed.store(true, Ordering::SeqCst);
+        self.stop();
+    }
 }
 
 /// A type for keeping track of the XSK operation related threads.
@@ -197,14 +346,68 @@ impl ThreadsRunner {
         }
     }
 
-    fn spawn<F>(&mut self, name: String, func: F)
+    fn spawn<F>(&mut self, name: String, cpu: Option<usize>, func: F)
     where
         F: FnOnce(Runner),
         F: Send + 'static,
     {
         let r = self.runner.clone();
 
-        self.threads
-            .push(thread::Builder::new().name(name).spawn(|| func(r)).unwrap());
+        let thread = thread::Builder::new().name(name).spawn(move || {
+            if let Some(cpu) = cpu {
+                if let Err(e) = affinity::pin_thread(cpu) {
+                    let thread = thread::current();
+                    warn!(
+                        "Cannot pin {} to CPU {}: {}",
+                        thread.name().unwrap(),
+                        cpu,
+                        e
+                    );
+                }
+            }
+
+            func(r)
+        });
+
+        self.threads.push(thread.unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_queues_ethtool_failure() {
+        let mut cfg = Configuration::default();
+        cfg.set_interface("h2o2-missing0").set_queues(vec![1]);
+
+        assert!(matches!(
+            Xsk::resolve_queues(&mut cfg),
+            Err(Error::EthtoolFailed(_))
+        ));
+
+        cfg.set_validate_queues(false).set_all_queues(true);
+        assert!(Xsk::resolve_queues(&mut cfg).is_ok());
+        assert_eq!(cfg.queues(), &[0]);
+    }
+
+    #[test]
+    fn test_resolve_queues_xsks_map_size() {
+        let mut cfg = Configuration::default();
+        cfg.set_validate_queues(false);
+
+        cfg.set_queues(vec![0, 1023]);
+        assert!(Xsk::resolve_queues(&mut cfg).is_ok());
+
+        // With 2 sockets per queue, the ones of queue 512 would be past the end of the map
+        cfg.set_socks_per_queue(2).set_queues(vec![0, 511]);
+        assert!(Xsk::resolve_queues(&mut cfg).is_ok());
+
+        cfg.set_queues(vec![0, 512]);
+        assert!(matches!(
+            Xsk::resolve_queues(&mut cfg),
+            Err(Error::InvalidQueue(512, 512))
+        ));
     }
 }
@@ -17,9 +17,11 @@
 
 //! Interfaces for glueing together `xsk` and a network stack.
 
+use std::{net::SocketAddr, sync::Arc};
+
 use crate::{
     xsk,
-    xsk::{Configuration, Desc},
+    xsk::{Configuration, Desc, ListenerMaps},
 };
 
 /// Signature of the closure that `xsk` expects to call whenever it needs to allocate a new network
@@ -34,31 +36,73 @@ pub trait Net: Send + Sync {
     /// Callback invoked when XSK has received a new packet.
     /// `desc` is an XDP descriptor pointing to a packet buffer of a newly arrived packet.
     fn rx_packet(&mut self, desc: Desc) -> anyhow::Result<()>;
+
+    /// Callback invoked periodically by the RX loop, whether or not new packets have been
+    /// received. It can be used to drive the network stack timers.
+    fn tick(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns true if the network stack cannot keep running, in which case the XSK threads are
+    /// stopped and the runner is marked as failed.
+    fn has_failed(&self) -> bool {
+        false
+    }
 }
 
 /// An object used to expose a minimal interface of the XSK socket to the network stack.
-pub struct Handle(xsk::TxSocket);
+pub struct Handle {
+    socket:        xsk::TxSocket,
+    listener_maps: Arc<ListenerMaps>,
+}
 
 impl Handle {
+    /// Creates a new handle for `socket`, adding listeners to the `listener_maps` of the XDP
+    /// program.
+    pub fn new(socket: xsk::TxSocket, listener_maps: Arc<ListenerMaps>) -> Self {
+        Handle {
+            socket,
+            listener_maps,
+        }
+    }
+
     /// Returns the XSK [`Configuration`] associated with the handle.
     pub fn configuration(&self) -> &Configuration {
-        self.0.configuration()
+        self.socket.configuration()
     }
 
-    /// Returns the next TX descriptor available in the socket's TX ring.
+    /// Returns the queue of the XSK socket.
+    pub fn queue(&self) -> usize {
+        self.socket.queue()
+    }
+
+    /// Returns the index of the XSK socket in the XDP program `xsks_map` map, unique among all the
+    /// sockets of the [`xsk::Xsk`] object.
+    pub fn socket_index(&self) -> usize {
+        self.socket.index()
+    }
+
+    /// Returns a new TX descriptor backed by a free UMEM frame.
     pub fn next_tx_slot(&mut self) -> xsk::Result<Desc> {
-        self.0.next_tx_slot()
+        self.socket.next_tx_slot()
     }
 
-    /// Mark the `desc` [`Desc`] as ready to be transmitted and transmits all
-    /// consecutive ready-to-be-transmitted descriptors from the beginning of the ring.
-    pub fn tx(&mut self, desc: &Desc) -> xsk::Result<()> {
-        self.0.tx(desc)
+    /// Returns a new TX descriptor for the `len` bytes at `offset` of the frame of the packet
+    /// received at `rx_addr`, to transmit a packet in place of the received one.
+    pub fn rx_to_tx_slot(&mut self, rx_addr: u64, offset: usize, len: usize) -> xsk::Result<Desc> {
+        self.socket.rx_to_tx_slot(rx_addr, offset, len)
+    }
+
+    /// Places the `desc` [`Desc`] in the TX ring and transmits it.
+    ///
+    /// Dropping a TX descriptor rather than transmitting it releases it.
+    pub fn tx(&mut self, desc: Desc) -> xsk::Result<()> {
+        self.socket.tx(desc)
     }
-}
 
-impl From<xsk::TxSocket> for Handle {
-    fn from(tx_socket: xsk::TxSocket) -> Self {
-        Handle(tx_socket)
+    /// Redirects the UDP datagrams sent to `listener` to the sockets, in addition to the
+    /// listeners of the XSK [`Configuration`].
+    pub fn add_listener(&self, listener: SocketAddr) -> xsk::Result<()> {
+        self.listener_maps.add(listener)
     }
 }
@@ -17,9 +17,12 @@
 
 //! XSK RX/TX queue.
 
-use std::{rc::Rc, sync::RwLock};
+use std::{
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
-use crate::xsk::{Configuration, Result, Socket, ThreadsRunner, Umem};
+use crate::xsk::{Configuration, Result, Socket, ThreadsRunner, Umem, XskMode};
 
 /// A collection of XSK queues.
 pub struct Queues(Vec<Queue>);
@@ -47,20 +50,24 @@ pub struct Queue {
 ///
 /// It may be made up of multiple XSK sockets.
 impl Queue {
-    /// Creates a new XSK [`Queue`].
+    /// Creates a new XSK [`Queue`] using `umem`, whose sockets are numbered starting from
+    /// `first_index` and bound in `mode`.
     pub fn new(
         cfg: Rc<Configuration>,
         queue_num: usize,
+        first_index: usize,
+        mut umem: Arc<Mutex<Umem>>,
+        mode: XskMode,
         threads_runner: &ThreadsRunner,
     ) -> Result<Self> {
-        let mut umem = Rc::new(RwLock::new(Umem::new(&cfg)?));
-
         let mut sockets = Vec::new();
-        for _ in 0..cfg.socks_per_queue() {
+        for i in 0..cfg.socks_per_queue() {
             let socket = Socket::new(
                 cfg.clone(),
                 &mut umem,
                 queue_num,
+                first_index + i,
+                mode,
                 threads_runner.runner.pipe_reader_fd(),
             )?;
 
@@ -17,7 +17,7 @@
 
 //! XSK producer and consumer rings.
 
-use std::{rc::Rc, sync::RwLock};
+use std::sync::Arc;
 
 use crate::{
     xsk,
@@ -26,30 +26,25 @@ use crate::{
 
 /// An `xsk_ring_prod` wrapper.
 pub struct ProdRing {
-    ring:            xsk::sys::xsk_ring_prod,
-    frame_allocator: Rc<RwLock<FrameAllocator>>,
-    size:            usize,
+    ring: xsk::sys::xsk_ring_prod,
 }
 
 /// An `xsk_ring_cons` wrapper.
 pub struct ConsRing {
     ring:            xsk::sys::xsk_ring_cons,
-    frame_allocator: Rc<RwLock<FrameAllocator>>,
+    frame_allocator: Arc<FrameAllocator>,
     size:            usize,
 }
 
 impl ProdRing {
     /// Wraps an `xsk_ring_prod` ring around a new [`ProdRing`] object.
-    pub fn new_from_xsk_ring_prod(
-        frame_allocator: Rc<RwLock<FrameAllocator>>,
-        ring: xsk::sys::xsk_ring_prod,
-        size: usize,
-    ) -> Self {
-        ProdRing {
-            ring,
-            frame_allocator,
-            size,
-        }
+    pub fn new_from_xsk_ring_prod(ring: xsk::sys::xsk_ring_prod) -> Self {
+        ProdRing { ring }
+    }
+
+    /// Returns a pointer to the wrapped ring, to be initialized by libxdp.
+    pub fn as_mut_ptr(&mut self) -> *mut xsk::sys::xsk_ring_prod {
+        &mut self.ring
     }
 
     /// Sets the address of the packet buffer for the descriptor with index `idx`.
@@ -78,15 +73,14 @@ impl ProdRing {
         unsafe { xsk::sys::xsk_prod_nb_free(&mut self.ring, num_bufs as u32) as usize }
     }
 
-    /// Returns the descriptor with index `idx`.
-    pub fn get_desc(&mut self, idx: u32) -> Desc {
-        let desc = unsafe { xsk::sys::xsk_ring_prod__tx_desc(&mut self.ring, idx) };
-
-        Desc::new_from_xdp_desc(
-            self.frame_allocator.clone(),
-            desc,
-            (idx as usize) % self.size,
-        )
+    /// Copies the address and length of `desc` into the descriptor with index `idx`.
+    pub fn set_desc(&mut self, idx: u32, desc: &Desc) {
+        unsafe {
+            let tx_desc = xsk::sys::xsk_ring_prod__tx_desc(&mut self.ring, idx);
+            (*tx_desc).addr = desc.addr();
+            (*tx_desc).len = desc.len() as u32;
+            (*tx_desc).options = 0;
+        }
     }
 
     /// Returns wether the ring needs to be woken up or not.
@@ -98,7 +92,7 @@ impl ProdRing {
 impl ConsRing {
     /// Wraps an `xsk_ring_cons` ring around a new [`ConsRing`] object.
     pub fn new_from_xsk_ring_cons(
-        frame_allocator: Rc<RwLock<FrameAllocator>>,
+        frame_allocator: Arc<FrameAllocator>,
         ring: xsk::sys::xsk_ring_cons,
         size: usize,
     ) -> Self {
@@ -109,6 +103,11 @@ impl ConsRing {
         }
     }
 
+    /// Returns a pointer to the wrapped ring, to be initialized by libxdp.
+    pub fn as_mut_ptr(&mut self) -> *mut xsk::sys::xsk_ring_cons {
+        &mut self.ring
+    }
+
     /// Peeks up to `num_bufs` descriptors and set `idx` to the index of the first available
     /// buffer.
     pub fn peek(&mut self, num_bufs: usize, idx: &mut u32) -> usize {
@@ -122,6 +121,11 @@ impl ConsRing {
         }
     }
 
+    /// Returns the frame address stored in the completion ring at index `idx`.
+    pub fn comp_addr(&mut self, idx: u32) -> u64 {
+        unsafe { *xsk::sys::xsk_ring_cons__comp_addr(&mut self.ring, idx) }
+    }
+
     /// Returns the descriptor with index `idx`.
     pub fn get_desc(&mut self, idx: u32) -> Desc {
         let desc = unsafe { xsk::sys::xsk_ring_cons__rx_desc(&mut self.ring, idx) };
@@ -17,34 +17,47 @@
 
 //! XSK sockets.
 
-use std::{ffi::CString, io, mem, ptr, rc::Rc, sync::RwLock};
+use std::{
+    ffi::CString,
+    io, mem, ptr,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     xsk,
     xsk::{
-        net, Configuration, ConsRing, Desc, Error::*, NeedsWakeup, ProdRing, Result, Runner, Umem,
+        net, Configuration, ConsRing, Desc, Error::*, FrameOwner, NeedsWakeup, ProdRing, Result,
+        Runner, TxDescTable, Umem, XskMode,
     },
 };
 
 /// An XSK soscket.
 pub struct Socket {
     socket:    *mut xsk::sys::xsk_socket,
+    index:     usize,
     rx_socket: Option<RxSocket>,
     tx_socket: Option<TxSocket>,
+
+    // Keep the UMEM alive until the socket is deleted, as it cannot be deleted before
+    _umem: Arc<Mutex<Umem>>,
 }
 
 unsafe impl Send for Socket {}
 
 impl Socket {
-    /// Create a new XSK socket.
+    /// Create a new XSK socket on `queue`, whose index in the XDP program `xsks_map` map is
+    /// `index`, bound in `mode`.
     pub fn new(
         cfg: Rc<Configuration>,
-        umem: &mut Rc<RwLock<Umem>>,
+        umem: &mut Arc<Mutex<Umem>>,
         queue: usize,
+        index: usize,
+        mode: XskMode,
         pipe_reader_fd: i32,
     ) -> Result<Self> {
         let (socket, tx, rx) = {
-            let umem = umem.write().unwrap();
+            let mut umem = umem.lock().unwrap();
 
             // Initialize the XSK socket.
             let interface_cstr = CString::new(String::from(cfg.interface())).unwrap();
@@ -58,11 +71,15 @@ impl Socket {
             xsk_opts.tx = &mut tx_ring;
             xsk_opts.rx_size = cfg.rx_size() as u32;
             xsk_opts.tx_size = cfg.tx_size() as u32;
+
+            // The first socket of a queue sharing the UMEM of another one creates the fill and
+            // completion rings of the queue
+            let (fill_ring, comp_ring) = umem.pending_rings();
+            xsk_opts.fill = fill_ring;
+            xsk_opts.comp = comp_ring;
             xsk_opts.libxdp_flags = xsk::sys::XSK_LIBXDP_FLAGS__INHIBIT_PROG_LOAD;
-            xsk_opts.bind_flags =
-                cfg.needs_wakeup().into_bind_flags() | cfg.mode().into_bind_flags();
-            xsk_opts.xdp_flags =
-                xsk::sys::XDP_FLAGS_UPDATE_IF_NOEXIST | cfg.mode().into_xdp_flags();
+            xsk_opts.bind_flags = cfg.needs_wakeup().into_bind_flags() | mode.into_bind_flags();
+            xsk_opts.xdp_flags = xsk::sys::XDP_FLAGS_UPDATE_IF_NOEXIST | mode.into_xdp_flags();
 
             let socket = unsafe {
                 xsk::sys::xsk_socket__create_opts(
@@ -77,6 +94,11 @@ impl Socket {
                 return Err(XskSocketCreateFailed(nix::errno::Errno::last_raw()));
             }
 
+            if let Err(e) = umem.socket_created() {
+                unsafe { xsk::sys::xsk_socket__delete(socket) };
+                return Err(e);
+            }
+
             // Initialize the RX ring.
             let rx = ConsRing::new_from_xsk_ring_cons(
                 umem.frame_allocator.clone(),
@@ -84,20 +106,8 @@ impl Socket {
                 cfg.rx_size(),
             );
 
-            // Initialize and populate the TX ring.
-            let mut tx = ProdRing::new_from_xsk_ring_prod(
-                umem.frame_allocator.clone(),
-                tx_ring,
-                cfg.tx_size(),
-            );
-            {
-                let mut frame_allocator = umem.frame_allocator.write().unwrap();
-
-                for i in 0..cfg.tx_size() {
-                    let addr = frame_allocator.alloc_frame().unwrap();
-                    tx.fill_addr(i as u32, addr);
-                }
-            }
+            // Initialize the TX ring. Frames are allocated on demand by `TxSocket::next_tx_slot`.
+            let tx = ProdRing::new_from_xsk_ring_prod(tx_ring);
 
             (socket, tx, rx)
         };
@@ -115,11 +125,11 @@ impl Socket {
             },
         ];
 
-        let ready_for_tx_slots = vec![false; cfg.tx_size()];
-        let current_tx_slot = 0;
+        let tx_table = Arc::new(TxDescTable::new(cfg.tx_size()));
 
         Ok(Socket {
             socket,
+            index,
 
             rx_socket: Some(RxSocket {
                 rx,
@@ -130,12 +140,15 @@ impl Socket {
             tx_socket: Some(TxSocket {
                 tx,
                 socket,
+                queue,
+                index,
                 umem: umem.clone(),
                 needs_wakeup: cfg.needs_wakeup(),
-                ready_for_tx_slots,
-                current_tx_slot,
+                tx_table,
                 configuration: cfg,
             }),
+
+            _umem: umem.clone(),
         })
     }
 
@@ -144,6 +157,33 @@ impl Socket {
         unsafe { xsk::sys::xsk_socket__fd(self.socket) }
     }
 
+    /// Returns the index of the socket in the XDP program `xsks_map` map.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns whether the socket is bound in zerocopy mode, according to its `XDP_OPTIONS`.
+    pub fn zero_copy(&self) -> Result<bool> {
+        let mut opts: xsk::sys::xdp_options = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<xsk::sys::xdp_options>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd(),
+                libc::SOL_XDP,
+                xsk::sys::XDP_OPTIONS as i32,
+                &mut opts as *mut xsk::sys::xdp_options as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        if ret == -1 {
+            return Err(XskSocketGetOptFailed(nix::errno::Errno::last_raw()));
+        }
+
+        Ok(opts.flags & xsk::sys::XDP_OPTIONS_ZEROCOPY != 0)
+    }
+
     /// Returns an owned `RxSocket` socket.
     pub fn take_rx_socket(&mut self) -> RxSocket {
         self.rx_socket.take().unwrap()
@@ -155,29 +195,43 @@ impl Socket {
     }
 }
 
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { xsk::sys::xsk_socket__delete(self.socket) };
+    }
+}
+
 /// An object responsible for handling the RX logic of an XSK [`Socket`].
 pub struct RxSocket {
     rx:   ConsRing,
-    umem: Rc<RwLock<Umem>>,
+    umem: Arc<Mutex<Umem>>,
 
     poll_fds: [libc::pollfd; 2],
 }
 
 impl RxSocket {
     /// Start the RX loop
-    pub fn rx_loop(runner: Runner, mut net: Box<dyn net::Net>, mut socket: RxSocket) {
+    pub fn rx_loop(mut runner: Runner, mut net: Box<dyn net::Net>, mut socket: RxSocket) {
         let umem = socket.umem.clone();
         while runner.is_running() {
             socket
                 .run_rx_loop(&umem, &mut net)
                 .unwrap_or_else(|e| eprintln!("Error in receive loop: {}", e));
+
+            net.tick()
+                .unwrap_or_else(|e| error!("Error in network stack timers: {}", e));
+
+            if net.has_failed() {
+                error!("Network stack failed, stopping");
+                runner.fail();
+            }
         }
     }
 
     /// poll() the fd associated with the [`RxSocket`].
     pub fn poll(&mut self) -> Result<i32> {
         let nfds = self.poll_fds.len() as u64;
-        let timeout = -1 as libc::c_int;
+        let timeout = xsk::POLL_TIMEOUT_MS as libc::c_int;
 
         let ret = unsafe { libc::poll(self.poll_fds.as_mut_ptr(), nfds, timeout) };
         if ret == -1 {
@@ -191,27 +245,21 @@ impl RxSocket {
     }
 
     /// RX loop
-    fn run_rx_loop(&mut self, umem: &Rc<RwLock<Umem>>, net: &mut Box<dyn net::Net>) -> Result<()> {
-        match self.poll() {
-            Ok(ret) => {
-                if ret <= 0 {
-                    return Ok(());
-                }
-            }
-            Err(e) => return Err(e),
-        };
+    fn run_rx_loop(&mut self, umem: &Arc<Mutex<Umem>>, net: &mut Box<dyn net::Net>) -> Result<()> {
+        // Nothing is received on a timeout, but the fill ring is topped up all the same
+        self.poll()?;
 
         let mut idx_rx = 0;
         let rcvd = self.rx.peek(xsk::BATCH_SIZE, &mut idx_rx);
 
+        let mut rx_addrs = [0; xsk::BATCH_SIZE];
         {
-            let mut umem = umem.write().unwrap();
-            umem.reclaim_fq_bufs(self, rcvd)
-                .unwrap_or_else(|e| eprintln!("Error reclaiming FQ buffers: {}", e));
-        }
+            let frame_allocator = umem.lock().unwrap().frame_allocator.clone();
 
-        if rcvd == 0 {
-            return Ok(());
+            for (i, rx_addr) in rx_addrs.iter_mut().take(rcvd).enumerate() {
+                *rx_addr = self.rx.get_desc(idx_rx + i as u32).addr();
+                frame_allocator.receive_frame(*rx_addr);
+            }
         }
 
         for _ in 0..rcvd {
@@ -222,6 +270,14 @@ impl RxSocket {
             idx_rx += 1;
         }
 
+        // The frames are given back to the allocator only once the network stack is done with
+        // them, as it may have claimed some of them to transmit a reply in place
+        umem.lock().unwrap().reclaim_fq_bufs(&rx_addrs[..rcvd]);
+
+        if rcvd == 0 {
+            return Ok(());
+        }
+
         self.rx.release(rcvd);
 
         Ok(())
@@ -232,12 +288,14 @@ impl RxSocket {
 pub struct TxSocket {
     tx:     ProdRing,
     socket: *mut xsk::sys::xsk_socket,
-    umem:   Rc<RwLock<Umem>>,
+    queue:  usize,
+    index:  usize,
+    umem:   Arc<Mutex<Umem>>,
 
     needs_wakeup: NeedsWakeup,
 
-    current_tx_slot:    usize,
-    ready_for_tx_slots: Vec<bool>,
+    // Descriptors handed out by `next_tx_slot` and `rx_to_tx_slot`
+    tx_table: Arc<TxDescTable>,
 
     // Keep a reference to the XSK configuration as it will be exposed by the Handle trait
     configuration: Rc<Configuration>,
@@ -249,29 +307,76 @@ impl TxSocket {
         &self.configuration
     }
 
-    /// Returns the next TX descriptor available in the socket's TX ring.
+    /// Returns the queue the socket is bound to.
+    pub fn queue(&self) -> usize {
+        self.queue
+    }
+
+    /// Returns the index of the socket in the XDP program `xsks_map` map.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns a new TX descriptor backed by a free UMEM frame.
+    ///
+    /// The descriptor is not part of the TX ring until it is passed to [`TxSocket::tx`].
     pub fn next_tx_slot(&mut self) -> Result<Desc> {
-        let mut tx_idx = 0;
-        if self.tx.reserve(1, &mut tx_idx) != 1 {
-            return Err(XskTxRingProdReserveFailed);
-        }
+        let index = self.tx_table.alloc().ok_or(XskTxNoFreeDescs)?;
+
+        let addr = match self.alloc_frame() {
+            Some(addr) => addr,
+            None => {
+                self.tx_table.free(index);
+                return Err(XskTxNoFreeFrames);
+            }
+        };
 
-        let desc = self.tx.get_desc(tx_idx);
+        let frame_allocator = self.umem.lock().unwrap().frame_allocator.clone();
 
-        Ok(desc)
+        Ok(Desc::new_tx(
+            frame_allocator,
+            self.tx_table.clone(),
+            index,
+            addr,
+            0,
+        ))
     }
 
-    /// Mark the `desc` [`Desc`] as ready to be transmitted and transmits all
-    /// consecutive ready-to-be-transmitted descriptors from the beginning of the ring.
-    pub fn tx(&mut self, desc: &Desc) -> Result<()> {
-        self.mark_slot_ready_for_tx(desc.index());
+    /// Returns a new TX descriptor for the `len` bytes at `offset` of the frame of the packet
+    /// received at `rx_addr`, so that a packet can be transmitted in place of the received one.
+    ///
+    /// The frame is handed over to the TX ring when the descriptor is transmitted, and is then
+    /// not given back to the fill ring once the RX descriptor is released. Dropping the
+    /// descriptor instead leaves the frame to the RX ring.
+    pub fn rx_to_tx_slot(&mut self, rx_addr: u64, offset: usize, len: usize) -> Result<Desc> {
+        let index = self.tx_table.alloc().ok_or(XskTxNoFreeDescs)?;
+
+        let frame_allocator = self.umem.lock().unwrap().frame_allocator.clone();
+
+        Ok(Desc::new_tx_in_place(
+            frame_allocator,
+            self.tx_table.clone(),
+            index,
+            rx_addr + offset as u64,
+            len,
+        ))
+    }
 
-        let ready_for_tx_slots_count = self.ready_for_tx_slot_counts();
-        if ready_for_tx_slots_count == 0 {
-            return Ok(());
+    /// Places the `desc` [`Desc`] in the TX ring and transmits it.
+    ///
+    /// The descriptor must have been returned by [`TxSocket::next_tx_slot`] or
+    /// [`TxSocket::rx_to_tx_slot`]. If the TX ring is full the descriptor is dropped, which
+    /// gives back its frame.
+    pub fn tx(&mut self, mut desc: Desc) -> Result<()> {
+        let mut tx_idx = 0;
+        if self.tx.reserve(1, &mut tx_idx) != 1 {
+            return Err(XskTxRingProdReserveFailed);
         }
 
-        self.tx.submit(ready_for_tx_slots_count);
+        desc.claim_rx_frame();
+        self.tx.set_desc(tx_idx, &desc);
+        self.tx.submit(1);
+        desc.release_to_tx_ring();
 
         if self.needs_wakeup.value {
             if self.tx.needs_wakeup() {
@@ -282,35 +387,26 @@ impl TxSocket {
         }
 
         self.umem
-            .write()
+            .lock()
             .unwrap()
-            .reclaim_cq_bufs(ready_for_tx_slots_count);
+            .reclaim_cq_bufs(self.configuration.tx_size());
 
         Ok(())
     }
 
-    /// Mark slot at index `index` as ready to be transmitted.
-    fn mark_slot_ready_for_tx(&mut self, index: usize) {
-        self.ready_for_tx_slots[index] = true;
-    }
-
-    /// Returns the number of consecutive slots (from the current one) in the TX ring which are
-    /// ready to be transmitted.
-    fn ready_for_tx_slot_counts(&mut self) -> usize {
-        let slots_count = self.ready_for_tx_slots.len();
-        let mut ready_for_tx_slots_count = 0;
+    /// Allocates a frame for a new TX descriptor, reclaiming the completed ones if none is
+    /// available.
+    fn alloc_frame(&mut self) -> Option<u64> {
+        let mut umem = self.umem.lock().unwrap();
 
-        for _ in 0..slots_count {
-            if !self.ready_for_tx_slots[self.current_tx_slot] {
-                break;
-            }
-
-            self.ready_for_tx_slots[self.current_tx_slot] = false;
-            self.current_tx_slot = (self.current_tx_slot + 1) % slots_count;
-            ready_for_tx_slots_count += 1;
+        let addr = umem.frame_allocator.alloc_frame(FrameOwner::TxRing);
+        if addr.is_some() {
+            return addr;
         }
 
-        ready_for_tx_slots_count
+        umem.reclaim_cq_bufs(self.configuration.tx_size());
+
+        umem.frame_allocator.alloc_frame(FrameOwner::TxRing)
     }
 
     /// Returns the fd associated with the TxSocket.
@@ -370,14 +466,14 @@ mod tests {
 
         let cfg = Rc::new(cfg);
 
-        let mut umem = Rc::new(RwLock::new(Umem::new(&cfg).unwrap()));
+        let mut umem = Arc::new(Mutex::new(Umem::new(&cfg, None).unwrap()));
 
         let mut pipe_fds = [0; 2];
         unsafe {
             libc::pipe(pipe_fds.as_mut_ptr());
         }
 
-        let socket = Socket::new(cfg, &mut umem, 0, pipe_fds[0]);
+        let socket = Socket::new(cfg, &mut umem, 0, 0, XskMode::Skb, pipe_fds[0]);
 
         assert!(socket.is_ok());
     }
@@ -45,7 +45,6 @@ pub unsafe fn xsk_ring_prod__fill_addr(fill: *mut xsk_ring_prod, idx: u32) -> *m
 }
 
 #[inline(always)]
-#[allow(dead_code)]
 pub unsafe fn xsk_ring_cons__comp_addr(comp: *mut xsk_ring_cons, idx: u32) -> *mut u64 {
     let addrs = (*comp).ring as *mut u64;
 
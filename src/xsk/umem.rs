@@ -14,47 +14,80 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
-
 //! UMEM sockets.
 
-use std::{mem, rc::Rc, sync::RwLock};
+use std::{
+    mem, ptr,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     xsk,
     xsk::{
-        Configuration, ConsRing, Error::*, FrameAllocator, NeedsWakeup, ProdRing, Result, RxSocket,
+        affinity, Configuration, ConsRing, Error::*, FrameAllocator, FrameOwner, ProdRing, Result,
     },
 };
 
 /// An UMEM socket.
+///
+/// Each [`Umem`] object holds the fill and completion rings of a queue. When the UMEM is shared
+/// by all the queues, the objects of the queues also share the memory buffer and its
+/// [`FrameAllocator`].
 pub struct Umem {
-    pub frame_allocator: Rc<RwLock<FrameAllocator>>,
+    pub frame_allocator: Arc<FrameAllocator>,
     pub umem:            *mut xsk::sys::xsk_umem,
 
     cq: ConsRing,
     fq: ProdRing,
 
-    needs_wakeup: NeedsWakeup,
+    fill_size: usize,
+    comp_size: usize,
+
+    // Whether the rings have yet to be created along with the first socket of the queue, which
+    // happens when the UMEM is shared with another queue.
+    rings_pending: bool,
+
+    // The UMEM whose memory buffer is shared, which is deleted along with it once the sockets
+    // of all the queues using it are
+    shared_with: Option<Arc<Mutex<Umem>>>,
 }
 
 unsafe impl Send for Umem {}
 
 impl Umem {
     pub fn size(cfg: &Rc<Configuration>) -> usize {
-        (cfg.rx_size() * cfg.socks_per_queue() + cfg.tx_size() * cfg.socks_per_queue())
-            * cfg.frame_size()
+        Self::num_frames(cfg) * cfg.frame_size()
+    }
+
+    /// Returns the number of frames of the UMEM: enough to fill the RX and TX rings of the
+    /// sockets of a queue, or of all the queues if the UMEM is shared.
+    fn num_frames(cfg: &Rc<Configuration>) -> usize {
+        let num_frames = (cfg.rx_size() + cfg.tx_size()) * cfg.socks_per_queue();
+
+        if cfg.shared_umem() {
+            num_frames * cfg.queues().len()
+        } else {
+            num_frames
+        }
     }
 
-    /// Creates a new [`Umem`] object.
-    pub fn new(cfg: &Rc<Configuration>) -> Result<Self> {
+    /// Creates a new [`Umem`] object, whose memory is allocated on NUMA `node` if any.
+    pub fn new(cfg: &Rc<Configuration>, node: Option<usize>) -> Result<Self> {
         let rx_size = cfg.rx_size() * cfg.socks_per_queue();
         let tx_size = cfg.tx_size() * cfg.socks_per_queue();
 
         // Initialize the frame allocator.
-        let frame_allocator = Rc::new(RwLock::new(FrameAllocator::new(
-            rx_size + tx_size,
+        let frame_allocator = Arc::new(FrameAllocator::new(
+            Self::num_frames(cfg),
             cfg.frame_size(),
-        )?));
+        )?);
+
+        if let Some(node) = node {
+            if let Err(e) = affinity::bind_to_node(frame_allocator.buffer, Self::size(cfg), node) {
+                warn!("Cannot allocate the UMEM on NUMA node {}: {}", node, e);
+            }
+        }
 
         // Initialize the umem socket.
         let mut fq_ring: xsk::sys::xsk_ring_prod = unsafe { mem::zeroed() };
@@ -67,17 +100,13 @@ impl Umem {
         umem_opts.comp_size = tx_size as u32;
         umem_opts.frame_size = cfg.frame_size() as u32;
 
-        let umem = {
-            let frame_allocator = frame_allocator.write().unwrap();
-
-            unsafe {
-                xsk::sys::xsk_umem__create_opts(
-                    frame_allocator.buffer,
-                    &mut fq_ring,
-                    &mut cq_ring,
-                    &mut umem_opts,
-                )
-            }
+        let umem = unsafe {
+            xsk::sys::xsk_umem__create_opts(
+                frame_allocator.buffer,
+                &mut fq_ring,
+                &mut cq_ring,
+                &mut umem_opts,
+            )
         };
 
         if umem.is_null() {
@@ -88,82 +117,178 @@ impl Umem {
         let cq = ConsRing::new_from_xsk_ring_cons(frame_allocator.clone(), cq_ring, tx_size);
 
         // Initialize and populate the fill ring.
-        let mut fq = ProdRing::new_from_xsk_ring_prod(frame_allocator.clone(), fq_ring, rx_size);
-        {
-            let mut frame_allocator = frame_allocator.write().unwrap();
-
-            let mut rx_idx = 0;
-            let n = fq.reserve(rx_size, &mut rx_idx);
-            if n != rx_size {
-                return Err(XskFqRingProdReserveFailed);
-            }
-
-            for i in 0..rx_size {
-                let addr = frame_allocator.alloc_frame().unwrap();
-                fq.fill_addr(i as u32, addr);
-            }
-
-            fq.submit(rx_size);
-        }
+        let fq = ProdRing::new_from_xsk_ring_prod(fq_ring);
 
-        Ok(Umem {
+        let mut umem = Umem {
             frame_allocator,
 
             fq,
             cq,
             umem,
-            needs_wakeup: cfg.needs_wakeup(),
-        })
+            fill_size: rx_size,
+            comp_size: tx_size,
+            rings_pending: false,
+            shared_with: None,
+        };
+
+        umem.populate_fq()?;
+
+        Ok(umem)
     }
 
-    /// Reclaim `num_bufs` descriptor in the FQ UMEM ring.
-    pub fn reclaim_fq_bufs(&mut self, socket: &mut RxSocket, num_bufs: usize) -> Result<()> {
-        if num_bufs == 0 {
-            if self.needs_wakeup.value && self.fq.needs_wakeup() {
-                socket.poll()?;
-            }
+    /// Creates a new [`Umem`] object for another queue, sharing the memory buffer of `umem`.
+    ///
+    /// Its fill and completion rings are created along with the first socket of the queue, see
+    /// [`Umem::pending_rings`].
+    pub fn new_shared(cfg: &Rc<Configuration>, shared_umem: &Arc<Mutex<Umem>>) -> Self {
+        let tx_size = cfg.tx_size() * cfg.socks_per_queue();
+        let umem = shared_umem.lock().unwrap();
+
+        let fq_ring: xsk::sys::xsk_ring_prod = unsafe { mem::zeroed() };
+        let cq_ring: xsk::sys::xsk_ring_cons = unsafe { mem::zeroed() };
+
+        Umem {
+            frame_allocator: umem.frame_allocator.clone(),
+
+            fq:            ProdRing::new_from_xsk_ring_prod(fq_ring),
+            cq:            ConsRing::new_from_xsk_ring_cons(
+                umem.frame_allocator.clone(),
+                cq_ring,
+                tx_size,
+            ),
+            umem:          umem.umem,
+            fill_size:     cfg.rx_size() * cfg.socks_per_queue(),
+            comp_size:     tx_size,
+            rings_pending: true,
+            shared_with:   Some(shared_umem.clone()),
+        }
+    }
 
-            return Ok(());
+    /// Returns pointers to the fill and completion rings if they have yet to be created, so that
+    /// they are initialized when the first socket of the queue is bound with `XDP_SHARED_UMEM`.
+    pub fn pending_rings(
+        &mut self,
+    ) -> (*mut xsk::sys::xsk_ring_prod, *mut xsk::sys::xsk_ring_cons) {
+        if !self.rings_pending {
+            return (ptr::null_mut(), ptr::null_mut());
         }
 
-        if self.fq.free(xsk::BATCH_SIZE) > 0 {
-            let mut idx_fq = 0;
+        (self.fq.as_mut_ptr(), self.cq.as_mut_ptr())
+    }
 
-            let mut ret = self.fq.reserve(num_bufs, &mut idx_fq);
+    /// Records that a socket of the queue has been created, along with the rings returned by
+    /// [`Umem::pending_rings`], if any, and populates the fill ring.
+    pub fn socket_created(&mut self) -> Result<()> {
+        if !self.rings_pending {
+            return Ok(());
+        }
 
-            while ret != num_bufs {
-                if self.needs_wakeup.value && self.fq.needs_wakeup() {
-                    socket.poll()?;
-                }
+        self.rings_pending = false;
+        self.populate_fq()
+    }
 
-                ret = self.fq.reserve(num_bufs, &mut idx_fq);
-            }
+    /// Fills the fill ring with new frames.
+    fn populate_fq(&mut self) -> Result<()> {
+        let mut rx_idx = 0;
+        let n = self.fq.reserve(self.fill_size, &mut rx_idx);
+        if n != self.fill_size {
+            return Err(XskFqRingProdReserveFailed);
+        }
 
-            self.fq.submit(num_bufs);
+        for i in 0..self.fill_size {
+            let addr = self
+                .frame_allocator
+                .alloc_frame(FrameOwner::FillRing)
+                .unwrap();
+            self.fq.fill_addr(rx_idx + i as u32, addr);
         }
 
+        self.fq.submit(self.fill_size);
+
         Ok(())
     }
 
-    /// Reclaim `num_bufs` descriptors in the CQ UMEM ring.
+    /// Return the frames of the RX descriptors at `rx_addrs`, which have been consumed, to the
+    /// frame allocator, reap the CQ UMEM ring and top the FQ UMEM ring up.
+    ///
+    /// The frames handed over to the TX ring stay there until their transmission completes. The
+    /// FQ ring is refilled even when no packet has been received, so that it recovers once frames
+    /// are available again after the allocator ran out of them.
+    pub fn reclaim_fq_bufs(&mut self, rx_addrs: &[u64]) {
+        for &rx_addr in rx_addrs {
+            self.frame_allocator.release_rx_frame(rx_addr);
+        }
+
+        self.reclaim_cq_bufs(self.comp_size);
+        refill_fq(&mut self.fq, &self.frame_allocator, self.fill_size);
+    }
+
+    /// Reclaim up to `num_bufs` descriptors in the CQ UMEM ring, returning their frames to the
+    /// frame allocator.
     pub fn reclaim_cq_bufs(&mut self, num_bufs: usize) {
         let mut tx_idx = 0;
         let completed = self.cq.peek(num_bufs, &mut tx_idx);
         if completed > 0 {
+            for i in 0..completed {
+                self.frame_allocator
+                    .free_frame(self.cq.comp_addr(tx_idx + i as u32));
+            }
+
             self.cq.release(completed);
         }
     }
 }
 
+impl Drop for Umem {
+    fn drop(&mut self) {
+        // A shared UMEM is only deleted by the queue which created it
+        if self.shared_with.is_some() {
+            return;
+        }
+
+        let ret = unsafe { xsk::sys::xsk_umem__delete(self.umem) };
+        if ret < 0 {
+            error!("Cannot delete UMEM: errno {}", -ret);
+        }
+    }
+}
+
+/// Tops the fill ring `fq`, of `fill_size` entries, up with frames from `frame_allocator`, as many
+/// as both have available. Returns the number of frames added.
+fn refill_fq(fq: &mut ProdRing, frame_allocator: &FrameAllocator, fill_size: usize) -> usize {
+    let addrs: Vec<u64> = (0..fq.free(fill_size).min(fill_size))
+        .map_while(|_| frame_allocator.alloc_frame(FrameOwner::FillRing))
+        .collect();
+
+    let num_bufs = addrs.len();
+    if num_bufs == 0 {
+        return 0;
+    }
+
+    // The ring has at least `num_bufs` free entries, so the reservation cannot fail
+    let mut idx_fq = 0;
+    fq.reserve(num_bufs, &mut idx_fq);
+
+    for (i, &addr) in addrs.iter().enumerate() {
+        fq.fill_addr(idx_fq + i as u32, addr);
+    }
+
+    fq.submit(num_bufs);
+
+    num_bufs
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::xsk::NeedsWakeup;
+
     #[test]
     fn test_new() {
         let mut cfg = Configuration::default();
         cfg.set_needs_wakeup(NeedsWakeup::new(false));
 
-        let umem = Umem::new(&Rc::new(cfg));
+        let umem = Umem::new(&Rc::new(cfg), None);
         assert!(umem.is_ok());
     }
 
@@ -173,7 +298,55 @@ mod tests {
         cfg.set_rx_size(42);
         cfg.set_needs_wakeup(NeedsWakeup::new(false));
 
-        let umem = Umem::new(&Rc::new(cfg));
+        let umem = Umem::new(&Rc::new(cfg), None);
         assert!(umem.is_err());
     }
+
+    #[test]
+    fn test_refill_fq() {
+        let frame_allocator = FrameAllocator::new(4, 4096).unwrap();
+
+        // The producer and consumer pointers, the flags and the ring shared with the kernel
+        let mut shared = [0_u32; 3];
+        let mut ring = [0_u64; 4];
+        let (producer, consumer) = (shared.as_mut_ptr(), unsafe { shared.as_mut_ptr().add(1) });
+        let ring = ring.as_mut_ptr();
+
+        let mut fq = ProdRing::new_from_xsk_ring_prod(xsk::sys::xsk_ring_prod {
+            cached_prod: 0,
+            cached_cons: 4,
+            mask: 3,
+            size: 4,
+            producer,
+            consumer,
+            ring: ring as *mut libc::c_void,
+            flags: unsafe { shared.as_mut_ptr().add(2) },
+        });
+
+        // With the allocator starved, the ring is only partially filled
+        let tx_frames: Vec<u64> = (0..3)
+            .map(|_| frame_allocator.alloc_frame(FrameOwner::TxRing).unwrap())
+            .collect();
+        assert_eq!(refill_fq(&mut fq, &frame_allocator, 4), 1);
+        assert_eq!(refill_fq(&mut fq, &frame_allocator, 4), 0);
+
+        // and recovers once frames are given back, without waiting for new packets
+        tx_frames
+            .iter()
+            .for_each(|&addr| frame_allocator.free_frame(addr));
+        assert_eq!(refill_fq(&mut fq, &frame_allocator, 4), 3);
+        assert_eq!(unsafe { *producer }, 4);
+
+        // Frames consumed by the kernel are replaced once they are released
+        unsafe { *consumer = 2 };
+        assert_eq!(refill_fq(&mut fq, &frame_allocator, 4), 0);
+
+        for i in 0..2 {
+            let addr = unsafe { *ring.add(i) };
+            frame_allocator.receive_frame(addr);
+            frame_allocator.release_rx_frame(addr);
+        }
+        assert_eq!(refill_fq(&mut fq, &frame_allocator, 4), 2);
+        assert_eq!(unsafe { *producer }, 6);
+    }
 }
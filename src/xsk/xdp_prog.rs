@@ -19,22 +19,34 @@
 
 use libc::c_void;
 
-use std::{ffi::CString, net::Ipv4Addr, ptr};
+use std::{
+    ffi::CString,
+    net::{IpAddr, SocketAddr},
+    ptr,
+};
 
 use crate::{
     xsk,
-    xsk::{Configuration, Error::*, Queues, QueuesSocketsRef, Result},
+    xsk::{Configuration, Error::*, Queues, QueuesSocketsRef, Result, Vlan, XskMode},
 };
 
+/// Number of entries of the XDP program `xsks_map` map, which bounds the number of sockets.
+pub const XSKS_MAP_SIZE: usize = 1024;
+
 /// An object responsible for managing the lifecycle of an XSK XDP program on a given interface.
 pub struct XdpProg {
     iface_index: u32,
     xdp_prog:    *mut xsk::sys::xdp_program,
+    attach_mode: xsk::sys::xdp_attach_mode,
 }
 
 impl XdpProg {
-    /// Load a new XSK XDP program on an interface.
-    pub fn load(cfg: &Configuration, queues: &Queues) -> Result<Self> {
+    /// Load a new XSK XDP program on an interface and attach it in the XDP mode required by
+    /// `mode`.
+    ///
+    /// Until its maps are set up with [`XdpProg::load_maps`], the program passes all the packets
+    /// to the kernel.
+    pub fn load(cfg: &Configuration, mode: XskMode) -> Result<Self> {
         let iface_index = Self::if_nametoindex(cfg.interface().to_string());
 
         let file_cstr = CString::new(cfg.xdp_prog_path().to_string()).unwrap();
@@ -52,52 +64,103 @@ impl XdpProg {
             return Err(BpfProgLoadFailed(nix::errno::Errno::last_raw()));
         }
 
-        let ret = unsafe {
-            xsk::sys::xdp_program__attach(
-                xdp_prog,
-                iface_index as i32,
-                xsk::sys::xdp_attach_mode_XDP_MODE_SKB,
-                0,
-            )
-        };
+        let attach_mode = mode.into_attach_mode();
+        let ret =
+            unsafe { xsk::sys::xdp_program__attach(xdp_prog, iface_index as i32, attach_mode, 0) };
 
         if ret != 0 {
+            unsafe { xsk::sys::xdp_program__close(xdp_prog) };
             return Err(BpfSetLinkXDPFailed(-ret));
         }
 
-        Self::load_xdp_prog_maps(
-            unsafe { xsk::sys::xdp_program__bpf_obj(xdp_prog) },
-            cfg.bind_address(),
-            cfg.bind_port(),
-            queues,
-            cfg.socks_per_queue(),
-        )?;
-
         Ok(XdpProg {
             iface_index,
             xdp_prog,
+            attach_mode,
         })
     }
 
+    /// Setup the XSK XDP program maps with the sockets of `queues`.
+    pub fn load_maps(&self, cfg: &Configuration, queues: &Queues) -> Result<()> {
+        Self::load_xdp_prog_maps(
+            unsafe { xsk::sys::xdp_program__bpf_obj(self.xdp_prog) },
+            &cfg.bind_addresses(),
+            cfg.vlan_bind_addresses(),
+            &cfg.listeners(),
+            queues,
+            cfg.socks_per_queue(),
+            cfg.all_protocols(),
+        )
+    }
+
+    /// Returns the listener maps of the program, to add listeners while it runs.
+    pub fn listener_maps(&self) -> Result<ListenerMaps> {
+        ListenerMaps::new(unsafe { xsk::sys::xdp_program__bpf_obj(self.xdp_prog) })
+    }
+
     /// Setup the XSK XDP program maps.
     ///
-    /// This will initialize the `xsks_map`, `socks_per_queue_map`, `bind_addr_map` and `bind_port_map` maps.
+    /// This will initialize the `xsks_map`, `socks_per_queue_map`, `all_protocols_map`,
+    /// `bind_addr_map`, `bind_addr6_map`, `vlan_addr_map`, `vlan_addr6_map`, `listener_map` and
+    /// `listener6_map` maps.
+    ///
+    /// UDP datagrams are matched on both their address and port by the XDP program, the ones for
+    /// other ports of the bound addresses being passed to the kernel.
     fn load_xdp_prog_maps(
         obj: *mut xsk::sys::bpf_object,
-        bind_addr: Ipv4Addr,
-        bind_port: u16,
+        bind_addrs: &[IpAddr],
+        vlan_addrs: &[(Vlan, IpAddr)],
+        listeners: &[SocketAddr],
         queues: &Queues,
         socks_per_queue: usize,
+        all_protocols: bool,
     ) -> Result<()> {
         let xsks_map = Map::new(obj, "xsks_map")?;
 
-        for (socket_idx, socket) in QueuesSocketsRef::from(queues).into_iter().enumerate() {
-            xsks_map.set(socket_idx as i32, socket.fd())?;
+        for socket in QueuesSocketsRef::from(queues).into_iter() {
+            xsks_map.set(socket.index() as i32, socket.fd())?;
         }
 
         Map::new(obj, "socks_per_queue_map")?.set(0, socks_per_queue)?;
-        Map::new(obj, "bind_addr_map")?.set(0, u32::from(bind_addr))?;
-        Map::new(obj, "bind_port_map")?.set(0, bind_port)?;
+        Map::new(obj, "all_protocols_map")?.set(0, all_protocols as usize)?;
+
+        let bind_addr_map = Map::new(obj, "bind_addr_map")?;
+        let bind_addr6_map = Map::new(obj, "bind_addr6_map")?;
+
+        for addr in bind_addrs {
+            match addr {
+                IpAddr::V4(addr) => bind_addr_map.set(u32::from(*addr).to_be(), 1u8)?,
+                IpAddr::V6(addr) => bind_addr6_map.set(addr.octets(), 1u8)?,
+            }
+        }
+
+        let vlan_addr_map = Map::new(obj, "vlan_addr_map")?;
+        let vlan_addr6_map = Map::new(obj, "vlan_addr6_map")?;
+
+        for (vlan, addr) in vlan_addrs {
+            match addr {
+                IpAddr::V4(addr) => vlan_addr_map.set(
+                    VlanAddr {
+                        vlan: vlan.key(),
+                        addr: u32::from(*addr).to_be(),
+                    },
+                    1u8,
+                )?,
+                IpAddr::V6(addr) => vlan_addr6_map.set(
+                    VlanAddr6 {
+                        vlan: vlan.key(),
+                        addr: addr.octets(),
+                    },
+                    1u8,
+                )?,
+            }
+        }
+
+        let listener_maps = ListenerMaps::new(obj)?;
+
+        for listener in listeners {
+            listener_maps.add(*listener)?;
+        }
 
         Ok(())
     }
@@ -114,13 +177,95 @@ impl Drop for XdpProg {
             xsk::sys::xdp_program__detach(
                 self.xdp_prog,
                 self.iface_index as i32,
-                xsk::sys::xdp_attach_mode_XDP_MODE_SKB,
+                self.attach_mode,
                 0,
             )
         };
         if ret < 0 {
             error!("Cannot unload XDP program: errno {}", -ret);
         }
+
+        unsafe { xsk::sys::xdp_program__close(self.xdp_prog) };
+    }
+}
+
+/// Key of the `vlan_addr_map` map, i.e. `struct vlan_addr`.
+#[repr(C)]
+struct VlanAddr {
+    vlan: u32,
+    addr: u32,
+}
+
+/// Key of the `vlan_addr6_map` map, i.e. `struct vlan_addr6`.
+#[repr(C)]
+struct VlanAddr6 {
+    vlan: u32,
+    addr: [u8; 16],
+}
+
+/// Key of the `listener_map` map, i.e. `struct listener`.
+#[repr(C)]
+struct Listener {
+    addr: u32,
+    port: u16,
+    pad:  u16,
+}
+
+/// Key of the `listener6_map` map, i.e. `struct listener6`.
+#[repr(C)]
+struct Listener6 {
+    addr: [u8; 16],
+    port: u16,
+    pad:  u16,
+}
+
+/// The `listener_map` and `listener6_map` maps of an XSK XDP program, holding the addresses and
+/// ports of the UDP datagrams redirected to the sockets.
+///
+/// The maps are kept open until the object is dropped, even after the program is unloaded.
+pub struct ListenerMaps {
+    listener_map:  Map,
+    listener6_map: Map,
+}
+
+impl ListenerMaps {
+    fn new(obj: *mut xsk::sys::bpf_object) -> Result<Self> {
+        Ok(ListenerMaps {
+            listener_map:  Map::new(obj, "listener_map")?.dup()?,
+            listener6_map: Map::new(obj, "listener6_map")?.dup()?,
+        })
+    }
+
+    /// Adds a listener, redirecting the UDP datagrams sent to its address and port to the
+    /// sockets.
+    pub fn add(&self, listener: SocketAddr) -> Result<()> {
+        match listener {
+            SocketAddr::V4(listener) => self.listener_map.set(
+                Listener {
+                    addr: u32::from(*listener.ip()).to_be(),
+                    port: listener.port().to_be(),
+                    pad:  0,
+                },
+                1u8,
+            ),
+            SocketAddr::V6(listener) => self.listener6_map.set(
+                Listener6 {
+                    addr: listener.ip().octets(),
+                    port: listener.port().to_be(),
+                    pad:  0,
+                },
+                1u8,
+            ),
+        }
+    }
+}
+
+impl Drop for ListenerMaps {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.listener_map.fd());
+            libc::close(self.listener6_map.fd());
+        }
     }
 }
 
@@ -145,6 +290,19 @@ impl Map {
         Ok(Map { fd, name })
     }
 
+    /// Returns a copy of the map with its own fd, which must be closed by the caller.
+    fn dup(&self) -> Result<Self> {
+        let fd = unsafe { libc::dup(self.fd) };
+        if fd < 0 {
+            return Err(MapNotFound(self.name(), nix::errno::Errno::last_raw()));
+        }
+
+        Ok(Map {
+            fd,
+            name: self.name(),
+        })
+    }
+
     fn fd(&self) -> i32 {
         self.fd
     }